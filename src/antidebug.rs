@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use crate::errors::GeneralError;
+
+/**
+ * Either one lets a library of the user's choosing run inside our process before libjvm does.
+ */
+const LOADER_VARIABLES: &[&str] = &["LD_PRELOAD", "LD_AUDIT"];
+
+const TRACER_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/**
+ * Must run before libjvm.so is loaded: refuses to start under a tracer or with a preloaded
+ * library, makes the process non-dumpable and keeps watching for a tracer afterwards.
+ * Non-dumpable means no core dumps and no /proc/<pid>/mem reads or ptrace attach by other
+ * processes of the same user.
+ */
+#[cfg(target_os = "linux")]
+pub fn protect() -> Result<(), GeneralError> {
+    use std::{env, thread};
+
+    use log::debug;
+
+    use crate::errors;
+
+    for name in LOADER_VARIABLES {
+        if let Some(value) = env::var_os(name).filter(|value| !value.is_empty()) {
+            return Err(GeneralError::SecurityError(format!(
+                "{} is set ({}), refusing to start",
+                name,
+                value.to_string_lossy()
+            )));
+        }
+    }
+
+    if let Some(pid) = tracer_pid() {
+        return Err(GeneralError::SecurityError(format!("process is traced by pid {}", pid)));
+    }
+
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        return Err(GeneralError::SecurityError(format!(
+            "cannot make the process non-dumpable ({})",
+            std::io::Error::last_os_error()
+        )));
+    }
+    debug!("Process is no longer dumpable");
+
+    thread::Builder::new()
+        .name(String::from("tracer-check"))
+        .spawn(|| loop {
+            if let Some(pid) = tracer_pid() {
+                errors::report(&GeneralError::SecurityError(format!("process is traced by pid {}", pid)));
+            }
+            thread::sleep(TRACER_CHECK_INTERVAL);
+        })
+        .map_err(|e| GeneralError::SecurityError(format!("cannot start the tracer check ({})", e)))?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn protect() -> Result<(), GeneralError> {
+    log::debug!("Anti-debugging is only implemented on Linux");
+    Ok(())
+}
+
+/**
+ * The TracerPid line of /proc/self/status, None when nobody is tracing us.
+ */
+#[cfg(target_os = "linux")]
+fn tracer_pid() -> Option<u32> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("TracerPid:"))
+        .and_then(|pid| pid.trim().parse().ok())
+        .filter(|&pid| pid != 0)
+}
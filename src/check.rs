@@ -1,77 +1,147 @@
-use std::process;
+use std::env;
+use std::path::Path;
 use jni::objects::{JObjectArray, JString};
-use jni::sys::jobjectArray;
 
+use crate::config::EnvOptionPolicy;
+use crate::errors::GeneralError;
+use crate::integrity;
 use crate::jvmti::jvmti_wrapper::JvmtiEnv;
-use log::{debug, error};
+use crate::policy::OptionPolicy;
+use log::{debug, error, warn};
 
+/**
+ * JAVA_TOOL_OPTIONS and _JAVA_OPTIONS are read by the JVM itself, even when it is created through JNI.
+ * JDK_JAVA_OPTIONS only reaches the java launcher, i.e. child processes started by the application.
+ */
+pub const JAVA_OPTION_VARIABLES: &[&str] = &["JAVA_TOOL_OPTIONS", "_JAVA_OPTIONS", "JDK_JAVA_OPTIONS"];
+
+/**
+ * Has to run before the VM is created, afterwards the options are already applied.
+ */
+pub fn java_env_check(policy: EnvOptionPolicy) -> Result<(), GeneralError> {
+    let mut rejected = Vec::new();
+    for name in JAVA_OPTION_VARIABLES {
+        let value = match env::var(name) {
+            Ok(value) if !value.trim().is_empty() => value,
+            _ => continue,
+        };
+        match policy {
+            EnvOptionPolicy::Warn => warn!("{} is set, the JVM will pick it up: {}", name, value),
+            EnvOptionPolicy::Strip => {
+                warn!("Removing {} from the environment: {}", name, value);
+                env::remove_var(name);
+            }
+            EnvOptionPolicy::Abort => {
+                error!("{} is set: {}", name, value);
+                rejected.push(*name);
+            }
+        }
+    }
+    if rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(GeneralError::SecurityError(format!(
+            "{} set in the environment, refusing to start",
+            rejected.join(" and ")
+        )))
+    }
+}
+
+/**
+ * `expected` are the options we created the VM with, they already passed the option policy.
+ * Anything else arrived through the environment and can no longer be stripped here,
+ * so only the warn policy lets it through, and never if the option policy denies it.
+ */
 #[allow(unused)]
-pub unsafe fn vm_param_check(jvmti: &JvmtiEnv, env: &mut jni::JNIEnv) {
+pub fn vm_param_check(
+    jvmti: &JvmtiEnv,
+    env: &mut jni::JNIEnv,
+    expected: &[String],
+    option_policy: &OptionPolicy,
+    policy: EnvOptionPolicy,
+) -> Result<(), GeneralError> {
+    let error = |e| GeneralError::jni("cannot read the VM input arguments", e);
     let array_args = {
-        let management_factory = env
-            .find_class("java/lang/management/ManagementFactory")
-            .unwrap();
-
         let mxbean = env
             .call_static_method(
-                management_factory,
+                "java/lang/management/ManagementFactory",
                 "getRuntimeMXBean",
                 "()Ljava/lang/management/RuntimeMXBean;",
                 &[],
             )
-            .unwrap()
-            .l()
-            .unwrap();
+            .and_then(|value| value.l())
+            .map_err(error)?;
 
         let list_args = env
             .call_method(mxbean, "getInputArguments", "()Ljava/util/List;", &[])
-            .unwrap()
-            .l()
-            .unwrap();
+            .and_then(|value| value.l())
+            .map_err(error)?;
 
         env.call_method(list_args, "toArray", "()[Ljava/lang/Object;", &[])
-            .unwrap()
-            .as_jni()
-            .l as jobjectArray
+            .and_then(|value| value.l())
+            .map_err(error)?
     };
 
-    let array_args = JObjectArray::from_raw(array_args);
-    let array_size = env.get_array_length(&array_args).unwrap();
-
-    let mut has_disable_attachmechanism = false;
-    let illegal_params = vec!["-agentpath", "-agentlib", "-javaagent"];
-    let disable_attachmechanism = "-XX:+DisableAttachMechanism";
+    let array_args = JObjectArray::from(array_args);
+    let array_size = env.get_array_length(&array_args).map_err(error)?;
 
+    let mut options = Vec::new();
     for i in 0..array_size {
-        let arv = env.get_object_array_element(&array_args, i).unwrap();
+        let arv = env.get_object_array_element(&array_args, i).map_err(error)?;
         let arv = JString::from(arv);
-        let option = env.get_string(&arv).unwrap();
-        let opt_value = option.to_str().unwrap();
+        let opt_value: String = env.get_string(&arv).map_err(error)?.into();
         debug!("VM option: {}", opt_value);
 
-        for p in &illegal_params {
-            if opt_value.contains(*p) {
-                error!("Illegal param {}", opt_value);
-                process::exit(1);
+        if !expected.contains(&opt_value) {
+            if option_policy.is_denied(&opt_value) {
+                return Err(GeneralError::SecurityError(format!("Illegal param {}", opt_value)));
+            }
+            if policy == EnvOptionPolicy::Warn {
+                warn!("VM option {} did not come from the launcher", opt_value);
+            } else {
+                return Err(GeneralError::SecurityError(format!(
+                    "VM option {} did not come from the launcher",
+                    opt_value
+                )));
             }
         }
-
-        if opt_value.eq(disable_attachmechanism) {
-            has_disable_attachmechanism = true;
-        }
+        options.push(opt_value);
     }
 
-    if !has_disable_attachmechanism {
-        error!("VM has no param -XX:+DisableAttachMechanism");
-        process::exit(1);
+    let missing = option_policy.missing(&options);
+    if !missing.is_empty() {
+        return Err(GeneralError::SecurityError(format!("VM has no param {}", missing.join(" "))));
     }
     debug!("Runtime vm param check pass!");
+    Ok(())
 }
 
 /**
- * We can check if some files have been tampered with
+ * We can check if some files have been tampered with.
+ * The runtime is compared against the signed manifest written at packaging time
+ * (--generate-manifest), every file that differs is reported.
  */
-#[inline(always)]
-pub fn md5_check(java_home: &str) -> bool {
-    true
-}
\ No newline at end of file
+pub fn runtime_check(java_home: &Path, manifest: Option<&Path>) -> Result<(), GeneralError> {
+    let manifest = match manifest {
+        Some(path) => path,
+        None => {
+            debug!("No runtime_manifest configured, skipping the runtime integrity check");
+            return Ok(());
+        }
+    };
+
+    let differences = integrity::verify_runtime(java_home, manifest)?;
+    if differences.is_empty() {
+        debug!("Runtime matches {}", manifest.display());
+        return Ok(());
+    }
+    for difference in &differences {
+        error!("Runtime {}: {}", java_home.display(), difference);
+    }
+    Err(GeneralError::IntegrityError(format!(
+        "{} file(s) of the runtime at {} differ from {}",
+        differences.len(),
+        java_home.display(),
+        manifest.display()
+    )))
+}
@@ -0,0 +1,142 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, process};
+
+use log::{error, info};
+
+use crate::crypto;
+use crate::errors::GeneralError;
+
+use super::format::Format;
+use super::{encrypted_path, is_encrypted_path, plain_path, read_config, APP_NAME};
+
+fn error(path: &Path, message: String) -> GeneralError {
+    GeneralError::ConfigError {
+        location: path.display().to_string(),
+        message,
+    }
+}
+
+/**
+ * Writes next to the target first so a failed write never leaves half a config behind.
+ */
+fn write_encrypted(path: &Path, data: &[u8]) -> Result<(), GeneralError> {
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, crypto::encrypt(data))
+        .and_then(|_| fs::rename(&temp, path))
+        .map_err(|e| error(path, format!("cannot write ({})", e)))
+}
+
+/**
+ * The plain file stays where it is, deleting it is left to the packaging step.
+ */
+pub fn encrypt(path: &Path) -> Result<PathBuf, GeneralError> {
+    if is_encrypted_path(path) {
+        return Err(error(path, String::from("already encrypted")));
+    }
+    // read_config only accepts encrypted files in an encrypted-config build
+    let data = fs::read_to_string(path).map_err(|e| error(path, format!("cannot read ({})", e)))?;
+    let format = Format::from_path(path).unwrap_or(Format::Ini);
+    format.parse(path, &data)?;
+
+    let target = encrypted_path(path);
+    write_encrypted(&target, data.as_bytes())?;
+    Ok(target)
+}
+
+pub fn decrypt(path: &Path) -> Result<String, GeneralError> {
+    if !is_encrypted_path(path) {
+        return Err(error(path, format!("expected a .{} file", super::ENCRYPTED_EXTENSION)));
+    }
+    read_config(path)
+}
+
+/**
+ * Decrypts into a private temporary file, opens $VISUAL or $EDITOR on it and encrypts
+ * the result back if it still parses. A missing file starts out empty.
+ * If it does not parse, the editor is opened again on the same text; closing it without
+ * a change gives up and keeps the temporary file, so the edit is not lost.
+ */
+pub fn edit(path: &Path) -> Result<(), GeneralError> {
+    if !is_encrypted_path(path) {
+        return Err(error(path, format!("expected a .{} file", super::ENCRYPTED_EXTENSION)));
+    }
+    let original = if path.exists() {
+        read_config(path)?
+    } else {
+        String::new()
+    };
+
+    let plain = plain_path(path);
+    let format = Format::from_path(&plain).unwrap_or(Format::Ini);
+    let extension = plain.extension().and_then(|e| e.to_str()).unwrap_or("ini");
+    let temp = env::temp_dir().join(format!("{}-{}.{}", APP_NAME, process::id(), extension));
+    create_private(&temp, &original)?;
+
+    let mut previous = original.clone();
+    let edited = loop {
+        let edited = match run_editor(&temp) {
+            Ok(edited) => edited,
+            Err(e) => {
+                let _ = fs::remove_file(&temp);
+                return Err(e);
+            }
+        };
+        match format.parse(&plain, &edited) {
+            Ok(_) => break edited,
+            Err(e) if edited == previous => {
+                return Err(error(
+                    path,
+                    format!("left unchanged, the edit does not parse and is kept in {} ({})", temp.display(), e),
+                ))
+            }
+            Err(e) => {
+                error!("{}, opening the editor again", e);
+                previous = edited;
+            }
+        }
+    };
+    let _ = fs::remove_file(&temp);
+
+    if edited == original {
+        info!("{} unchanged", path.display());
+        return Ok(());
+    }
+    write_encrypted(path, edited.as_bytes())?;
+    info!("{} updated", path.display());
+    Ok(())
+}
+
+fn create_private(temp: &Path, content: &str) -> Result<(), GeneralError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(temp)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|e| error(temp, format!("cannot create ({})", e)))
+}
+
+fn run_editor(temp: &Path) -> Result<String, GeneralError> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| String::from(if cfg!(windows) { "notepad" } else { "vi" }));
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    let status = Command::new(program)
+        .args(parts)
+        .arg(temp)
+        .status()
+        .map_err(|e| error(temp, format!("cannot start editor '{}' ({})", editor, e)))?;
+    if !status.success() {
+        return Err(error(temp, format!("editor '{}' exited with {}", editor, status)));
+    }
+
+    fs::read_to_string(temp).map_err(|e| error(temp, format!("cannot read back ({})", e)))
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::errors::GeneralError;
+
+/**
+ * A raw value as found in the config file, before it is checked against the schema.
+ * `list` is set when the format has real arrays (TOML, JSON), INI lists are split later.
+ */
+pub struct Property {
+    pub value: String,
+    pub list: Option<Vec<String>>,
+    pub line: u32,
+}
+
+/**
+ * Properties keyed by (section, key). Section names are upper case, a profile
+ * section keeps its suffix as written, e.g. `JVM.prod`.
+ */
+pub type Properties = HashMap<(String, String), Property>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ini,
+    Toml,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "ini" => Some(Format::Ini),
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    pub fn parse(&self, path: &Path, data: &str) -> Result<Properties, GeneralError> {
+        match self {
+            Format::Ini => parse_ini(path, data),
+            Format::Toml => parse_toml(path, data),
+            Format::Json => parse_json(path, data),
+        }
+    }
+}
+
+fn section_name(name: &str) -> String {
+    match name.split_once('.') {
+        Some((section, profile)) => format!("{}.{}", section.to_uppercase(), profile),
+        None => name.to_uppercase(),
+    }
+}
+
+fn error(path: &Path, line: u32, message: String) -> GeneralError {
+    let location = if line == 0 {
+        path.display().to_string()
+    } else {
+        format!("{}:{}", path.display(), line)
+    };
+    GeneralError::ConfigError { location, message }
+}
+
+fn parse_ini(path: &Path, s: &str) -> Result<Properties, GeneralError> {
+    use ini_core::*;
+    let mut map = HashMap::new();
+    let mut sect = String::new();
+    let mut parser = Parser::new(s).auto_trim(true);
+    loop {
+        let line = parser.line() + 1;
+        match parser.next() {
+            None => break,
+            Some(Item::Section(section)) => {
+                sect = section_name(section);
+            }
+            Some(Item::Property(key, Some(value))) => {
+                let property = Property {
+                    value: value.to_string(),
+                    list: None,
+                    line,
+                };
+                map.insert((sect.clone(), key.to_string()), property);
+            }
+            Some(Item::Property(key, None)) => {
+                return Err(error(
+                    path,
+                    line,
+                    format!("key '{}' in [{}] has no value, expected {}=<value>", key, sect, key),
+                ));
+            }
+            Some(Item::Error(text)) => {
+                return Err(error(path, line, format!("malformed section header '{}'", text)));
+            }
+            _ => (),
+        }
+    }
+    Ok(map)
+}
+
+/**
+ * Both TOML and JSON documents look like
+ * `{ "jvm": { "jvm_args": [..], "prod": { "jvm_args": [..] } } }`:
+ * top level tables are sections, tables inside a section are profiles.
+ * Every key carries the line it was found on, 0 if unknown.
+ */
+enum Node<'a> {
+    Scalar(String),
+    List(Vec<String>),
+    Table(Vec<(&'a str, u32, Node<'a>)>),
+}
+
+fn collect(path: &Path, sections: Vec<(&str, u32, Node)>) -> Result<Properties, GeneralError> {
+    let mut map = HashMap::new();
+    for (section, line, node) in sections {
+        let entries = match node {
+            Node::Table(entries) => entries,
+            _ => {
+                return Err(error(
+                    path,
+                    line,
+                    format!("'{}' must be a section, keys belong inside a section", section),
+                ))
+            }
+        };
+
+        let mut tables = vec![(section_name(section), entries)];
+        while let Some((name, entries)) = tables.pop() {
+            for (key, line, node) in entries {
+                let (value, list) = match node {
+                    Node::Scalar(value) => (value, None),
+                    Node::List(items) => (items.join(" "), Some(items)),
+                    Node::Table(profile) if !name.contains('.') => {
+                        tables.push((format!("{}.{}", name, key), profile));
+                        continue;
+                    }
+                    Node::Table(_) => {
+                        return Err(error(
+                            path,
+                            line,
+                            format!("[{}] {} is nested too deeply", name, key),
+                        ))
+                    }
+                };
+                let property = Property { value, list, line };
+                map.insert((name.clone(), key.to_string()), property);
+            }
+        }
+    }
+    Ok(map)
+}
+
+/**
+ * 1-based line of a byte offset.
+ */
+fn line_at(s: &str, offset: usize) -> u32 {
+    s[..offset.min(s.len())].matches('\n').count() as u32 + 1
+}
+
+fn parse_toml(path: &Path, s: &str) -> Result<Properties, GeneralError> {
+    use toml_edit::{ImDocument, Item, TableLike, Value};
+
+    fn scalar(value: &Value) -> String {
+        match value {
+            Value::String(s) => s.value().clone(),
+            Value::Integer(i) => i.value().to_string(),
+            Value::Float(f) => f.value().to_string(),
+            Value::Boolean(b) => b.value().to_string(),
+            Value::Datetime(d) => d.value().to_string(),
+            other => other.clone().decorated("", "").to_string(),
+        }
+    }
+
+    fn table<'a>(s: &str, table: &'a dyn TableLike) -> Vec<(&'a str, u32, Node<'a>)> {
+        table
+            .iter()
+            .map(|(key, item)| {
+                let line = table
+                    .get_key_value(key)
+                    .and_then(|(key, _)| key.span())
+                    .map(|span| line_at(s, span.start))
+                    .unwrap_or(0);
+                (key, line, node(s, item))
+            })
+            .collect()
+    }
+
+    fn node<'a>(s: &str, item: &'a Item) -> Node<'a> {
+        if let Some(nested) = item.as_table_like() {
+            return Node::Table(table(s, nested));
+        }
+        match item.as_value() {
+            Some(Value::Array(items)) => Node::List(items.iter().map(scalar).collect()),
+            Some(value) => Node::Scalar(scalar(value)),
+            None => Node::Scalar(String::new()),
+        }
+    }
+
+    let document = ImDocument::parse(s).map_err(|e| {
+        let line = e.span().map(|span| line_at(s, span.start)).unwrap_or(0);
+        error(path, line, e.message().trim().replace('\n', ", "))
+    })?;
+    collect(path, table(s, document.as_table()))
+}
+
+/**
+ * The json crate keeps no positions, so the lines of the object keys are looked up in the text.
+ * Keys are found by their path from the top level object, e.g. `["jvm", "prod", "jvm_args"]`.
+ */
+fn json_key_lines(s: &str) -> HashMap<Vec<String>, u32> {
+    let mut lines = HashMap::new();
+    // one entry per open object or array, objects hold the key read last
+    let mut open: Vec<Option<String>> = Vec::new();
+    let mut line = 1;
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            '{' => open.push(Some(String::new())),
+            '[' => open.push(None),
+            '}' | ']' => {
+                open.pop();
+            }
+            '"' => {
+                let mut text = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            if let Some(escaped) = chars.next() {
+                                text.push(escaped);
+                            }
+                        }
+                        '"' => break,
+                        c => text.push(c),
+                    }
+                }
+                while chars.peek().is_some_and(|c| c.is_whitespace() && *c != '\n') {
+                    chars.next();
+                }
+                if chars.peek() == Some(&':') {
+                    if let Some(Some(key)) = open.last_mut() {
+                        *key = text;
+                        let path: Option<Vec<String>> = open.iter().cloned().collect();
+                        if let Some(path) = path {
+                            lines.insert(path, line);
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    lines
+}
+
+fn parse_json(path: &Path, s: &str) -> Result<Properties, GeneralError> {
+    use json::JsonValue;
+
+    fn node<'a>(value: &'a JsonValue, key: &mut Vec<String>, lines: &HashMap<Vec<String>, u32>) -> Node<'a> {
+        match value {
+            JsonValue::Array(items) => Node::List(
+                items
+                    .iter()
+                    .map(|item| match item.as_str() {
+                        Some(s) => s.to_string(),
+                        None => item.dump(),
+                    })
+                    .collect(),
+            ),
+            JsonValue::Object(object) => Node::Table(table(object, key, lines)),
+            other => match other.as_str() {
+                Some(s) => Node::Scalar(s.to_string()),
+                None => Node::Scalar(other.dump()),
+            },
+        }
+    }
+
+    fn table<'a>(
+        object: &'a json::object::Object,
+        key: &mut Vec<String>,
+        lines: &HashMap<Vec<String>, u32>,
+    ) -> Vec<(&'a str, u32, Node<'a>)> {
+        object
+            .iter()
+            .map(|(name, value)| {
+                key.push(name.to_string());
+                let entry = (name, lines.get(key).copied().unwrap_or(0), node(value, key, lines));
+                key.pop();
+                entry
+            })
+            .collect()
+    }
+
+    let document = json::parse(s).map_err(|e| match e {
+        json::Error::UnexpectedCharacter { line, .. } => error(path, line as u32, e.to_string()),
+        e => error(path, 0, e.to_string()),
+    })?;
+    let sections = match &document {
+        JsonValue::Object(object) => table(object, &mut Vec::new(), &json_key_lines(s)),
+        _ => return Err(error(path, 0, String::from("the top level must be an object"))),
+    };
+    collect(path, sections)
+}
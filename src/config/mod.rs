@@ -0,0 +1,834 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{Display, Formatter, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn, LevelFilter};
+
+use crate::crypto;
+use crate::errors::GeneralError;
+use crate::jdk::VersionRange;
+use crate::utility;
+
+use self::format::{Format, Properties};
+
+pub mod container;
+mod format;
+
+pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
+pub const CONFIG_FILE_NAME: &str = "config.ini";
+/**
+ * Looked for in this order in every directory `locate` searches.
+ */
+const CONFIG_FILE_NAMES: &[&str] = &[CONFIG_FILE_NAME, "config.toml", "config.json"];
+/**
+ * `config.ini.enc` is `config.ini` encrypted with the class key, see `crypto`.
+ */
+pub const ENCRYPTED_EXTENSION: &str = "enc";
+pub const CONFIG_ENV: &str = "APP_CONFIG";
+pub const PROFILE_ENV: &str = "APP_PROFILE";
+const ENV_PREFIX: &str = "APP_";
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Str,
+    Path,
+    List,
+    Bool,
+    Memory,
+    Number,
+    Choice(&'static [&'static str]),
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Kind::Str => write!(f, "a string"),
+            Kind::Path => write!(f, "a path"),
+            Kind::List => write!(f, "a space separated list"),
+            Kind::Bool => write!(f, "true or false"),
+            Kind::Memory => write!(f, "a memory size such as 512M or 2G"),
+            Kind::Number => write!(f, "a whole number"),
+            Kind::Choice(choices) => write!(f, "one of {}", choices.join(", ")),
+        }
+    }
+}
+
+/**
+ * Every key the launcher understands: section, key, type and default value.
+ * Each key can be overridden by the environment variable APP_<KEY>, except the `LOCKED_SECTIONS` ones.
+ */
+const KEYS: &[(&str, &str, Kind, &str)] = &[
+    ("JAR", "jar_path", Kind::Path, ""),
+    ("JAR", "main_args", Kind::List, ""),
+    ("JVM", "java_home", Kind::Path, ""),
+    ("JVM", "java_version", Kind::Str, ""),
+    ("JVM", "java_vm", Kind::Choice(&["server", "client"]), "server"),
+    ("JVM", "runtime", Kind::Path, ""),
+    ("JVM", "verify_runtime", Kind::Bool, "true"),
+    ("JVM", "min_heap", Kind::Memory, ""),
+    ("JVM", "max_heap", Kind::Memory, ""),
+    ("JVM", "jvm_args", Kind::List, ""),
+    ("SECURITY", "protected_packages", Kind::List, ""),
+    ("SECURITY", "runtime_manifest", Kind::Path, ""),
+    ("SECURITY", "jar_manifest", Kind::Path, ""),
+    ("SECURITY", "env_options", Kind::Choice(&["warn", "strip", "abort"]), "strip"),
+    ("SECURITY", "anti_debug", Kind::Bool, "true"),
+    ("SECURITY", "watchdog", Kind::Bool, "false"),
+    ("SECURITY", "watchdog_interval", Kind::Number, "5"),
+    ("SECURITY", "watchdog_action", Kind::Choice(&["log", "callback", "terminate"]), "terminate"),
+    ("SECURITY", "watchdog_callback", Kind::Str, ""),
+    ("POLICY", "allow", Kind::List, ""),
+    ("POLICY", "deny", Kind::List, ""),
+    ("POLICY", "require", Kind::List, ""),
+    ("LOGGING", "log_level", Kind::Choice(&["off", "error", "warn", "info", "debug", "trace"]), ""),
+    ("LOGGING", "log_file", Kind::Path, ""),
+    ("TRANSFORMERS", "decrypt_classes", Kind::Bool, "true"),
+    ("TRANSFORMERS", "decrypt_packages", Kind::List, ""),
+    ("DIAGNOSTICS", "histogram_signal", Kind::Bool, "false"),
+    ("DIAGNOSTICS", "histogram_format", Kind::Choice(&["text", "json", "csv"]), "text"),
+    ("DIAGNOSTICS", "histogram_file", Kind::Path, ""),
+    ("DIAGNOSTICS", "histogram_diff", Kind::Bool, "false"),
+    ("DIAGNOSTICS", "histogram_limit", Kind::Number, "0"),
+    ("DIAGNOSTICS", "heap_dump_signal", Kind::Bool, "false"),
+    ("DIAGNOSTICS", "heap_dump_on_oom", Kind::Bool, "false"),
+    ("DIAGNOSTICS", "heap_dump_dir", Kind::Path, ""),
+];
+
+/**
+ * Sections only the config file can set, neither APP_<KEY> nor --set reach them.
+ * Everything is locked when the config file is encrypted.
+ */
+const LOCKED_SECTIONS: &[&str] = &["SECURITY", "POLICY"];
+
+/**
+ * Keys holding a path that is relative to the config file.
+ */
+const PATH_KEYS: &[&str] = &[
+    "jar_path",
+    "java_home",
+    "log_file",
+    "runtime_manifest",
+    "jar_manifest",
+    "histogram_file",
+    "heap_dump_dir",
+];
+
+/**
+ * Where the effective value of a key came from, lowest priority first.
+ */
+#[derive(Clone, Debug)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Profile(String),
+    Env(String),
+    Cli,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "file {}", path.display()),
+            Source::Profile(section) => write!(f, "profile [{}]", section),
+            Source::Env(name) => write!(f, "env {}", name),
+            Source::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+pub struct ConfigEntry {
+    pub section: &'static str,
+    pub key: &'static str,
+    pub value: String,
+    pub source: Source,
+    pub line: u32,
+    list: Option<Vec<String>>,
+    kind: Kind,
+}
+
+impl ConfigEntry {
+    /**
+     * file:line for values read from the config file, the variable or flag otherwise.
+     */
+    pub fn location(&self, file: &Path) -> String {
+        match &self.source {
+            Source::File(_) | Source::Profile(_) if self.line > 0 => {
+                format!("{}:{}", file.display(), self.line)
+            }
+            Source::File(_) | Source::Profile(_) => {
+                format!("{} ([{}] {})", file.display(), self.section, self.key)
+            }
+            source => format!("{} ([{}] {})", source, self.section, self.key),
+        }
+    }
+}
+
+/**
+ * What to do about JAVA_TOOL_OPTIONS and friends, see `check::java_env_check`.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EnvOptionPolicy {
+    Warn,
+    #[default]
+    Strip,
+    Abort,
+}
+
+/**
+ * A heap size as accepted by -Xms/-Xmx, e.g. 128M.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemorySize(pub u64);
+
+impl MemorySize {
+    pub fn parse(value: &str) -> Option<MemorySize> {
+        let value = value.trim();
+        let value = value.strip_suffix(['b', 'B']).unwrap_or(value);
+        let (number, unit) = match value.char_indices().last()? {
+            (i, c) if c.is_ascii_alphabetic() => (&value[..i], c.to_ascii_lowercase()),
+            _ => (value, ' '),
+        };
+        let shift = match unit {
+            ' ' => 0,
+            'k' => 10,
+            'm' => 20,
+            'g' => 30,
+            't' => 40,
+            _ => return None,
+        };
+        let number: u64 = number.trim().parse().ok()?;
+        number.checked_mul(1 << shift).map(MemorySize)
+    }
+}
+
+impl Display for MemorySize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let units = [(40, "T"), (30, "G"), (20, "M"), (10, "K")];
+        for (shift, unit) in units {
+            if self.0 != 0 && self.0.is_multiple_of(1 << shift) {
+                return write!(f, "{}{}", self.0 >> shift, unit);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+/**
+ * Values layered on top of the config file: the selected profile and `SECTION.key=value` pairs from the command line.
+ */
+#[derive(Default)]
+pub struct Overrides {
+    pub profile: Option<String>,
+    pub values: Vec<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct SecurityConfig {
+    /**
+     * Classes in these packages must only ever be loaded from their encrypted form.
+     */
+    pub protected_packages: Vec<String>,
+    /**
+     * Signed list of runtime file hashes, see `integrity`. Without it the runtime is not checked.
+     */
+    pub runtime_manifest: Option<PathBuf>,
+    /**
+     * Signed hashes of the application jar and its nested jars. Without it jars are not checked.
+     */
+    pub jar_manifest: Option<PathBuf>,
+    pub env_options: EnvOptionPolicy,
+    /**
+     * Linux only, see `antidebug::protect`. Developers turn it off to use a debugger.
+     */
+    pub anti_debug: bool,
+    pub watchdog: bool,
+    /**
+     * Seconds between two watchdog runs.
+     */
+    pub watchdog_interval: u64,
+    /**
+     * log, callback or terminate
+     */
+    pub watchdog_action: String,
+    /**
+     * `some.Class.method`, a static method taking the violation as a String.
+     */
+    pub watchdog_callback: String,
+}
+
+/**
+ * JVM option patterns, see `policy::OptionPolicy`.
+ */
+#[derive(Clone, Default)]
+pub struct PolicyConfig {
+    /**
+     * Replaces the built-in allow-list when not empty.
+     */
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub require: Vec<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct LoggingConfig {
+    pub log_level: Option<LevelFilter>,
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Default)]
+pub struct TransformerConfig {
+    pub decrypt_classes: bool,
+    /**
+     * Package prefixes the class hook looks at, empty means every class.
+     */
+    pub decrypt_packages: Vec<String>,
+}
+
+/**
+ * Heap histograms and dumps, see `histogram` and `heapdump`.
+ */
+#[derive(Clone, Default)]
+pub struct DiagnosticsConfig {
+    /**
+     * Unix only: SIGUSR1 writes a histogram while the application runs.
+     */
+    pub histogram_signal: bool,
+    /**
+     * text, json or csv
+     */
+    pub histogram_format: String,
+    /**
+     * Histograms are appended to this file, stderr without it.
+     */
+    pub histogram_file: Option<PathBuf>,
+    /**
+     * Every histogram after the first is written as the difference to the one before.
+     */
+    pub histogram_diff: bool,
+    /**
+     * Classes per histogram, largest first. 0 writes all of them.
+     */
+    pub histogram_limit: u64,
+    /**
+     * Unix only: SIGUSR2 writes a heap dump while the application runs.
+     */
+    pub heap_dump_signal: bool,
+    /**
+     * Write a heap dump the first time the Java heap runs out.
+     */
+    pub heap_dump_on_oom: bool,
+    /**
+     * Where heap dumps go, the working directory without it.
+     */
+    pub heap_dump_dir: Option<PathBuf>,
+}
+
+#[derive(Default)]
+pub struct Config {
+    pub path: PathBuf,
+    pub profile: Option<String>,
+    pub jar_path: PathBuf,
+    pub main_args: Vec<String>,
+    pub java_home: Option<PathBuf>,
+    pub java_version: String,
+    pub java_vm: String,
+    pub runtime: Option<PathBuf>,
+    pub verify_runtime: bool,
+    pub min_heap: Option<MemorySize>,
+    pub max_heap: Option<MemorySize>,
+    pub jvm_args: Vec<String>,
+    pub security: SecurityConfig,
+    pub logging: LoggingConfig,
+    pub transformers: TransformerConfig,
+    pub policy: PolicyConfig,
+    pub diagnostics: DiagnosticsConfig,
+    pub entries: Vec<ConfigEntry>,
+}
+
+impl Config {
+    /**
+     * Finds the config file: the --config option, then the APP_CONFIG environment variable,
+     * then the directory of the executable, then /etc/<app>/.
+     * The current directory is never searched, the launcher has to behave the same wherever it is started.
+     * In each directory an encrypted config wins over a plain one.
+     * An explicitly given path is never silently replaced by another one.
+     */
+    pub fn locate(cli_path: Option<&str>) -> Option<PathBuf> {
+        if let Some(path) = cli_path {
+            return Some(PathBuf::from(path));
+        }
+        if let Some(path) = env::var_os(CONFIG_ENV) {
+            return Some(PathBuf::from(path));
+        }
+
+        let dirs = [utility::app_dir(), Path::new("/etc").join(APP_NAME)];
+        let candidates = dirs
+            .iter()
+            .flat_map(|dir| CONFIG_FILE_NAMES.iter().map(move |name| dir.join(name)))
+            .flat_map(|path| [encrypted_path(&path), path]);
+        for candidate in candidates {
+            debug!("Looking for config at {}", candidate.display());
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    /**
+     * Builds the effective configuration from its layers:
+     * defaults -> config file -> profile section -> environment -> command line.
+     * The file format is picked by extension (.ini, .toml or .json), anything else is read as INI.
+     * A trailing .enc is decrypted first and the extension before it picks the format.
+     */
+    pub fn load(path: &Path, overrides: &Overrides) -> std::result::Result<Config, GeneralError> {
+        let data = read_config(path)?;
+        let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        let format = Format::from_path(&plain_path(&path)).unwrap_or(Format::Ini);
+        let properties = format.parse(&path, data.as_str())?;
+        Self::check_unknown_keys(&path, &properties);
+
+        let profile = overrides
+            .profile
+            .clone()
+            .or_else(|| env::var(PROFILE_ENV).ok())
+            .filter(|p| !p.is_empty());
+        // an encrypted config is meant to be final, the environment and command line must not undo it
+        let encrypted = is_encrypted_path(&path);
+        let locked = |section: &str| encrypted || LOCKED_SECTIONS.iter().any(|s| s.eq_ignore_ascii_case(section));
+
+        let mut cli_values: HashMap<(String, &str), &str> = HashMap::new();
+        for item in &overrides.values {
+            let parsed = item
+                .split_once('=')
+                .and_then(|(name, value)| name.split_once('.').map(|(s, k)| (s, k, value)));
+            match parsed {
+                Some((section, key, _)) if Self::find_key(section, key).is_some() && locked(section) => {
+                    return Err(GeneralError::ConfigError {
+                        location: String::from("command line"),
+                        message: format!(
+                            "[{}] {} can only be set in the config file",
+                            section.to_uppercase(),
+                            key
+                        ),
+                    })
+                }
+                Some((section, key, value)) if Self::find_key(section, key).is_some() => {
+                    cli_values.insert((section.to_uppercase(), key), value);
+                }
+                _ => {
+                    return Err(GeneralError::ConfigError {
+                        location: String::from("command line"),
+                        message: format!("invalid override '{}', expected SECTION.key=value", item),
+                    })
+                }
+            }
+        }
+
+        // Relative paths are relative to the config file, not to wherever we were started from.
+        let base = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+
+        let mut entries = Vec::new();
+        for &(section, key, kind, default) in KEYS {
+            let mut value = default.to_string();
+            let mut source = Source::Default;
+            let mut line = 0;
+            let mut list = None;
+
+            if let Some(p) = properties.get(&(section.to_string(), key.to_string())) {
+                value = p.value.clone();
+                list = p.list.clone();
+                source = Source::File(path.clone());
+                line = p.line;
+            }
+
+            if let Some(profile) = &profile {
+                let profile_section = format!("{}.{}", section, profile);
+                if let Some(p) = properties.get(&(profile_section.clone(), key.to_string())) {
+                    value = p.value.clone();
+                    list = p.list.clone();
+                    source = Source::Profile(profile_section);
+                    line = p.line;
+                }
+            }
+
+            let env_name = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+            match env::var(&env_name) {
+                Ok(_) if locked(section) => {
+                    warn!("Ignoring {}, [{}] {} can only be set in the config file", env_name, section, key);
+                }
+                Ok(v) => {
+                    value = v;
+                    list = None;
+                    source = Source::Env(env_name);
+                }
+                Err(_) => (),
+            }
+
+            if let Some(v) = cli_values.get(&(section.to_string(), key)) {
+                value = v.to_string();
+                list = None;
+                source = Source::Cli;
+            }
+
+            let list = list.map(|items| {
+                items
+                    .iter()
+                    .map(|item| Self::interpolate(item, &base))
+                    .collect::<Vec<_>>()
+            });
+            let mut value = Self::interpolate(value.trim(), &base);
+            if PATH_KEYS.contains(&key) && matches!(source, Source::File(_) | Source::Profile(_)) {
+                value = Self::resolve(&base, value);
+            }
+
+            entries.push(ConfigEntry {
+                section,
+                key,
+                value,
+                source,
+                line,
+                list,
+                kind,
+            });
+        }
+
+        let mut config = Config {
+            path,
+            profile,
+            ..Default::default()
+        };
+        for entry in &entries {
+            config.apply(entry)?;
+        }
+        config.entries = entries;
+
+        Ok(config)
+    }
+
+    /**
+     * Checks that the configured files are usable before anything gets started.
+     */
+    pub fn validate(&self) -> std::result::Result<(), GeneralError> {
+        let jar_path = self.entry("JAR", "jar_path");
+        if self.jar_path.as_os_str().is_empty() {
+            return Err(self.error(jar_path, String::from("jar_path is required")));
+        }
+        if !self.jar_path.is_file() {
+            return Err(self.error(
+                jar_path,
+                format!("jar_path {} does not exist", self.jar_path.display()),
+            ));
+        }
+
+        let java_version = self.entry("JVM", "java_version");
+        if let Err(e) = VersionRange::parse(&self.java_version) {
+            return Err(self.error(java_version, e.to_string()));
+        }
+
+        // An unusable java_home is not fatal, the JDK resolver searches the usual places instead.
+        if let Some(java_home) = &self.java_home {
+            let looks_like_jdk = java_home.join("release").is_file()
+                || java_home.join("bin").join("java").is_file();
+            if !looks_like_jdk {
+                warn!(
+                    "{}: java_home {} does not look like a JDK, searching for one instead",
+                    self.entry("JVM", "java_home").location(&self.path),
+                    java_home.display()
+                );
+            }
+        }
+
+        let security = &self.security;
+        if security.watchdog_interval == 0 {
+            let entry = self.entry("SECURITY", "watchdog_interval");
+            return Err(self.error(entry, String::from("watchdog_interval must be at least 1 second")));
+        }
+        if security.watchdog_action == "callback" && !security.watchdog_callback.contains('.') {
+            let entry = self.entry("SECURITY", "watchdog_callback");
+            return Err(self.error(
+                entry,
+                String::from("watchdog_action=callback needs watchdog_callback=some.Class.method"),
+            ));
+        }
+
+        if let Some(dir) = &self.diagnostics.heap_dump_dir {
+            if !dir.is_dir() {
+                let entry = self.entry("DIAGNOSTICS", "heap_dump_dir");
+                return Err(self.error(entry, format!("heap_dump_dir {} is not a directory", dir.display())));
+            }
+        }
+
+        if let (Some(min), Some(max)) = (self.min_heap, self.max_heap) {
+            if min.0 > max.0 {
+                let entry = self.entry("JVM", "min_heap");
+                return Err(self.error(entry, format!("min_heap {} is larger than max_heap {}", min, max)));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * The effective configuration, one `[SECTION] key = value (source)` line per key.
+     */
+    pub fn describe(&self) -> String {
+        let mut result = format!("# {}", self.path.display());
+        if let Some(profile) = &self.profile {
+            result.push_str(&format!(", profile {}", profile));
+        }
+        result.push('\n');
+        for entry in &self.entries {
+            let source = match entry.source {
+                Source::File(_) | Source::Profile(_) if entry.line > 0 => {
+                    format!("{}, line {}", entry.source, entry.line)
+                }
+                _ => entry.source.to_string(),
+            };
+            result.push_str(&format!(
+                "[{}] {} = {}    ({})\n",
+                entry.section, entry.key, entry.value, source
+            ));
+        }
+        result
+    }
+
+    fn apply(&mut self, entry: &ConfigEntry) -> std::result::Result<(), GeneralError> {
+        let value = entry.value.as_str();
+        let invalid = || self.error(entry, format!("{} must be {}, got '{}'", entry.key, entry.kind, value));
+
+        match entry.kind {
+            Kind::Bool if !matches!(value, "true" | "false" | "yes" | "no" | "on" | "off" | "1" | "0") => {
+                return Err(invalid())
+            }
+            Kind::Memory if !value.is_empty() && MemorySize::parse(value).is_none() => {
+                return Err(invalid())
+            }
+            Kind::Number if value.parse::<u64>().is_err() => return Err(invalid()),
+            Kind::Choice(choices) if !value.is_empty() && !choices.contains(&value) => {
+                return Err(invalid())
+            }
+            _ => (),
+        }
+
+        let optional_path = || match value {
+            "" => None,
+            v => Some(PathBuf::from(v)),
+        };
+        let list = || match &entry.list {
+            Some(items) => items.clone(),
+            None => value.split_whitespace().map(String::from).collect::<Vec<_>>(),
+        };
+        let bool = || matches!(value, "true" | "yes" | "on" | "1");
+        match entry.key {
+            "jar_path" => self.jar_path = PathBuf::from(value),
+            "main_args" => self.main_args = list(),
+            "java_home" => self.java_home = optional_path(),
+            "java_version" => self.java_version = value.to_string(),
+            "java_vm" => self.java_vm = value.to_string(),
+            "runtime" => self.runtime = optional_path(),
+            "verify_runtime" => self.verify_runtime = bool(),
+            "min_heap" => self.min_heap = MemorySize::parse(value),
+            "max_heap" => self.max_heap = MemorySize::parse(value),
+            "jvm_args" => self.jvm_args = list(),
+            "protected_packages" => self.security.protected_packages = list(),
+            "runtime_manifest" => self.security.runtime_manifest = optional_path(),
+            "jar_manifest" => self.security.jar_manifest = optional_path(),
+            "anti_debug" => self.security.anti_debug = bool(),
+            "watchdog" => self.security.watchdog = bool(),
+            "watchdog_interval" => self.security.watchdog_interval = value.parse().unwrap_or(5),
+            "watchdog_action" => self.security.watchdog_action = value.to_string(),
+            "watchdog_callback" => self.security.watchdog_callback = value.to_string(),
+            "env_options" => {
+                self.security.env_options = match value {
+                    "warn" => EnvOptionPolicy::Warn,
+                    "abort" => EnvOptionPolicy::Abort,
+                    _ => EnvOptionPolicy::Strip,
+                }
+            }
+            "allow" => self.policy.allow = list(),
+            "deny" => self.policy.deny = list(),
+            "require" => self.policy.require = list(),
+            "log_level" => self.logging.log_level = value.parse().ok(),
+            "log_file" => self.logging.log_file = optional_path(),
+            "decrypt_classes" => self.transformers.decrypt_classes = bool(),
+            "decrypt_packages" => self.transformers.decrypt_packages = list(),
+            "histogram_signal" => self.diagnostics.histogram_signal = bool(),
+            "histogram_format" => self.diagnostics.histogram_format = value.to_string(),
+            "histogram_file" => self.diagnostics.histogram_file = optional_path(),
+            "histogram_diff" => self.diagnostics.histogram_diff = bool(),
+            "histogram_limit" => self.diagnostics.histogram_limit = value.parse().unwrap_or(0),
+            "heap_dump_signal" => self.diagnostics.heap_dump_signal = bool(),
+            "heap_dump_on_oom" => self.diagnostics.heap_dump_on_oom = bool(),
+            "heap_dump_dir" => self.diagnostics.heap_dump_dir = optional_path(),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn entry(&self, section: &str, key: &str) -> &ConfigEntry {
+        self.entries
+            .iter()
+            .find(|e| e.section == section && e.key == key)
+            .expect("every schema key has an entry")
+    }
+
+    fn error(&self, entry: &ConfigEntry, message: String) -> GeneralError {
+        GeneralError::ConfigError {
+            location: entry.location(&self.path),
+            message,
+        }
+    }
+
+    fn find_key(section: &str, key: &str) -> Option<Kind> {
+        KEYS.iter()
+            .find(|(s, k, _, _)| s.eq_ignore_ascii_case(section) && *k == key)
+            .map(|(_, _, kind, _)| *kind)
+    }
+
+    /**
+     * A typo in a key name would otherwise just fall back to the default and fail much later.
+     */
+    fn check_unknown_keys(path: &Path, properties: &Properties) {
+        let mut unknown: Vec<_> = properties
+            .iter()
+            .filter(|((section, key), _)| {
+                let base = section.split('.').next().unwrap_or_default();
+                Self::find_key(base, key).is_none()
+            })
+            .collect();
+        unknown.sort_by_key(|(_, p)| p.line);
+
+        for ((section, key), property) in unknown {
+            let base = section.split('.').next().unwrap_or_default();
+            let location = if property.line > 0 {
+                format!("{}:{}", path.display(), property.line)
+            } else {
+                path.display().to_string()
+            };
+            let suggestion = KEYS
+                .iter()
+                .filter(|(s, _, _, _)| s.eq_ignore_ascii_case(base))
+                .map(|(_, k, _, _)| (*k, levenshtein(key, k)))
+                .filter(|(k, distance)| *distance <= 2.max(k.len() / 3))
+                .min_by_key(|(_, distance)| *distance);
+
+            match suggestion {
+                Some((k, _)) => warn!(
+                    "{}: unknown key '{}' in [{}], did you mean '{}'?",
+                    location, key, section, k
+                ),
+                None => warn!("{}: unknown key '{}' in [{}]", location, key, section),
+            }
+        }
+    }
+
+    /**
+     * Expands `${VAR}` from the environment, plus `${app.dir}` and `${config.dir}`.
+     */
+    fn interpolate(value: &str, config_dir: &Path) -> String {
+        let mut result = String::new();
+        let mut rest = value;
+        while let Some(start) = rest.find("${") {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            result.push_str(&rest[..start]);
+
+            let name = &rest[start + 2..end];
+            let replacement = match name {
+                "app.dir" => utility::app_dir().display().to_string(),
+                "config.dir" => config_dir.display().to_string(),
+                name => env::var(name).unwrap_or_else(|_| {
+                    warn!("Variable ${{{}}} is not set", name);
+                    String::new()
+                }),
+            };
+            result.push_str(&replacement);
+            rest = &rest[end + 1..];
+        }
+        result.push_str(rest);
+        result
+    }
+
+    fn resolve(base: &Path, value: String) -> String {
+        if value.is_empty() || Path::new(&value).is_absolute() {
+            value
+        } else {
+            base.join(value).display().to_string()
+        }
+    }
+}
+
+pub fn is_encrypted_path(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == ENCRYPTED_EXTENSION)
+}
+
+/**
+ * `config.ini` -> `config.ini.enc`
+ */
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ENCRYPTED_EXTENSION);
+    PathBuf::from(name)
+}
+
+/**
+ * `config.ini.enc` -> `config.ini`, other paths are returned unchanged.
+ */
+pub fn plain_path(path: &Path) -> PathBuf {
+    if is_encrypted_path(path) {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/**
+ * Reads a config file as text, decrypting it if it is an encrypted container.
+ */
+pub fn read_config(path: &Path) -> std::result::Result<String, GeneralError> {
+    let error = |message: String| GeneralError::ConfigError {
+        location: path.display().to_string(),
+        message,
+    };
+    let data = fs::read(path).map_err(|e| error(format!("cannot read config file ({})", e)))?;
+    let data = if is_encrypted_path(path) {
+        crypto::decrypt(&data).map_err(|e| error(format!("cannot decrypt config file ({})", e)))?
+    } else if cfg!(feature = "encrypted-config") {
+        return Err(error(String::from("this build only accepts an encrypted config file")));
+    } else {
+        data
+    };
+    String::from_utf8(data).map_err(|_| error(String::from("config file is not valid UTF-8")))
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            current.push((previous[j] + cost).min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "{{\n jar_path: {},\n main_args: {},\n jvm_args: {},\n }}",
+            self.jar_path.display(),
+            self.main_args.join(" "),
+            self.jvm_args.join(" "),
+        )
+    }
+}
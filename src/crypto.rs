@@ -0,0 +1,129 @@
+use std::io::{self, Read};
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+
+use crate::errors::GeneralError;
+use crate::utility;
+
+/**
+ * Marks encrypted classes and encrypted config files alike.
+ */
+pub const ENCRYPT_MAGIC_NUMBER: u32 = 0xDEADC0DE;
+
+const MAGIC_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+/**
+ * Build with `APP_KEY=<secret> cargo build --release` to use your own key.
+ * The fallback only exists so a development build can run out of the box,
+ * a release build without APP_KEY does not compile.
+ */
+const KEY_SEED: &str = match option_env!("APP_KEY") {
+    Some(seed) => seed,
+    None => "rust-jni-example-development-key",
+};
+
+#[cfg(not(debug_assertions))]
+const _: () = assert!(
+    option_env!("APP_KEY").is_some(),
+    "release builds need their own key: APP_KEY=<secret> cargo build --release"
+);
+
+static CIPHER: Lazy<Aes256Gcm> = Lazy::new(|| {
+    let key = Sha256::digest(KEY_SEED.as_bytes());
+    Aes256Gcm::new(&key)
+});
+
+/**
+ * Signing uses its own key derived from the same seed, never the cipher key itself.
+ */
+static SIGNING_KEY: Lazy<[u8; 32]> = Lazy::new(|| {
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_SEED.as_bytes());
+    hasher.update(b"/signing");
+    hasher.finalize().into()
+});
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.len() >= MAGIC_LEN && utility::read_to::<u32>(&data[..MAGIC_LEN]) == ENCRYPT_MAGIC_NUMBER
+}
+
+/**
+ * Layout: magic | nonce | AES-256-GCM ciphertext and tag.
+ */
+pub fn encrypt(data: &[u8]) -> Vec<u8> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = CIPHER
+        .encrypt(&nonce, data)
+        .expect("AES-GCM encryption cannot fail for in-memory data");
+
+    let mut result = Vec::with_capacity(MAGIC_LEN + NONCE_LEN + ciphertext.len());
+    result.extend_from_slice(&ENCRYPT_MAGIC_NUMBER.to_ne_bytes());
+    result.extend_from_slice(&nonce);
+    result.extend_from_slice(&ciphertext);
+    result
+}
+
+pub fn decrypt(data: &[u8]) -> Result<Vec<u8>, GeneralError> {
+    if !is_encrypted(data) || data.len() < MAGIC_LEN + NONCE_LEN {
+        return Err(GeneralError::CryptoError(String::from("not an encrypted payload")));
+    }
+    let (nonce, ciphertext) = data[MAGIC_LEN..].split_at(NONCE_LEN);
+    CIPHER
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| GeneralError::CryptoError(String::from("wrong key or corrupted data")))
+}
+
+fn mac() -> Hmac<Sha256> {
+    <Hmac<Sha256> as Mac>::new_from_slice(SIGNING_KEY.as_slice()).expect("HMAC accepts any key length")
+}
+
+/**
+ * HMAC-SHA256 of `data`, used to sign the integrity manifests.
+ */
+pub fn sign(data: &[u8]) -> Vec<u8> {
+    let mut mac = mac();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn verify_signature(data: &[u8], signature: &[u8]) -> bool {
+    let mut mac = mac();
+    mac.update(data);
+    mac.verify_slice(signature).is_ok()
+}
+
+/**
+ * SHA-256 of everything `reader` yields, read in chunks so large files like lib/modules
+ * never sit in memory.
+ */
+pub fn sha256<R: Read>(mut reader: R) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
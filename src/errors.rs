@@ -1,6 +1,26 @@
+use std::error::Error as _;
+use std::{io, process};
+
 use jni::errors::StartJvmError;
+use log::{debug, error};
 use thiserror::Error;
 
+use crate::jvmti::errors::JvmtiError;
+
+/**
+ * Process exit codes, one per error category. 1 is what java itself exits with
+ * when the main method throws, 2 is reserved for command line usage errors.
+ */
+pub const EXIT_APPLICATION: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_CONFIG: i32 = 3;
+pub const EXIT_JDK: i32 = 4;
+pub const EXIT_VM_CREATION: i32 = 5;
+pub const EXIT_JNI: i32 = 6;
+pub const EXIT_JVMTI: i32 = 7;
+pub const EXIT_SECURITY: i32 = 8;
+pub const EXIT_DIAGNOSTICS: i32 = 9;
+
 #[derive(Debug, Error)]
 pub enum GeneralError {
     #[error("{0}")]
@@ -12,6 +32,120 @@ pub enum GeneralError {
 
     #[error("{0}")]
     JvmArgsError(String),
+
+    #[error("{0}")]
+    JdkError(String),
+
+    #[error("{0}")]
+    CryptoError(String),
+
+    #[error("{0}")]
+    IntegrityError(String),
+
+    #[error("{0}")]
+    SecurityError(String),
+
+    #[error("{location}: {message}")]
+    ConfigError { location: String, message: String },
+
+    #[error("{context}: {source}")]
+    JniError {
+        context: String,
+        #[source]
+        source: jni::errors::Error,
+    },
+
+    #[error("{context}: {source}")]
+    JvmtiError {
+        context: String,
+        #[source]
+        source: JvmtiError,
+    },
+
+    /**
+     * A heap histogram or heap dump could not be written out.
+     */
+    #[error("{context}: {source}")]
+    DiagnosticsError {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /**
+     * The application itself failed, e.g. its main method threw.
+     */
+    #[error("{0}")]
+    ApplicationError(String),
 }
 
+impl GeneralError {
+    pub fn jni(context: &str, source: jni::errors::Error) -> GeneralError {
+        GeneralError::JniError {
+            context: context.to_string(),
+            source,
+        }
+    }
 
+    pub fn jvmti(context: &str, source: JvmtiError) -> GeneralError {
+        GeneralError::JvmtiError {
+            context: context.to_string(),
+            source,
+        }
+    }
+
+    pub fn diagnostics(context: String, source: io::Error) -> GeneralError {
+        GeneralError::DiagnosticsError { context, source }
+    }
+
+    pub fn category(&self) -> &'static str {
+        match self {
+            GeneralError::ConfigError { .. } => "Configuration error",
+            GeneralError::JdkError(_) => "JDK error",
+            GeneralError::StartJvmError(_) => "JVM creation failed",
+            GeneralError::JniError { .. } => "JNI error",
+            GeneralError::JvmtiError { .. } => "JVMTI error",
+            GeneralError::JvmArgsError(_) => "JVM option rejected",
+            GeneralError::CryptoError(_) => "Decryption failed",
+            GeneralError::IntegrityError(_) => "Integrity check failed",
+            GeneralError::SecurityError(_) => "Security check failed",
+            GeneralError::DiagnosticsError { .. } => "Diagnostics failed",
+            GeneralError::ApplicationError(_) => "Application error",
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GeneralError::ConfigError { .. } => EXIT_CONFIG,
+            GeneralError::JdkError(_) => EXIT_JDK,
+            GeneralError::StartJvmError(_) => EXIT_VM_CREATION,
+            GeneralError::JniError { .. } => EXIT_JNI,
+            GeneralError::JvmtiError { .. } => EXIT_JVMTI,
+            GeneralError::JvmArgsError(_)
+            | GeneralError::CryptoError(_)
+            | GeneralError::IntegrityError(_)
+            | GeneralError::SecurityError(_) => EXIT_SECURITY,
+            GeneralError::DiagnosticsError { .. } => EXIT_DIAGNOSTICS,
+            GeneralError::ApplicationError(_) => EXIT_APPLICATION,
+        }
+    }
+}
+
+/**
+ * The one place a failed launch ends up: what went wrong, why, and an exit code
+ * that tells scripts which category it was.
+ * Also the way out for JVM callbacks and background threads that find something too dangerous
+ * to carry on with; the VM is not destroyed then.
+ */
+pub fn report(e: &GeneralError) -> ! {
+    error!("{}: {}", e.category(), e);
+    // the message already includes the immediate cause
+    let mut source = e.source().and_then(|cause| cause.source());
+    while let Some(cause) = source {
+        debug!("Caused by: {}", cause);
+        source = cause.source();
+    }
+    debug!("Exiting with {}", e.exit_code());
+    log::logger().flush();
+    process::exit(e.exit_code());
+}
@@ -0,0 +1,680 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::Local;
+use jni::objects::JObject;
+use jni::sys::{jclass, jint, jlong};
+use jni::{JNIEnv, JavaVM};
+use log::{debug, error, info, warn};
+use thiserror::Error;
+
+use crate::config::DiagnosticsConfig;
+use crate::errors::GeneralError;
+use crate::hprof::{self, ClassDump, HprofWriter, Id, Root, Value};
+use crate::jvmti::capabilities::{self, Capabilities, CapabilityError};
+use crate::jvmti::errors::{JvmtiError, JvmtiResult};
+use crate::jvmti::events::EventRegistry;
+use crate::jvmti::heap::{HeapFilter, HeapValue, ReferenceKind, Visit};
+use crate::jvmti::jvmti_sys::JVMTI_VERSION;
+use crate::jvmti::jvmti_wrapper::JvmtiEnv;
+use crate::jvmti::sync::JvmtiSupplier;
+
+const ACC_STATIC: jint = 0x0008;
+
+/**
+ * The stack trace every object points to, JVMTI does not know where an object was allocated.
+ * Thread stacks are numbered from here on.
+ */
+const NO_STACK_TRACE: u32 = 1;
+const MAX_FRAMES: jint = 1024;
+
+/**
+ * Object identifiers are their tags, counted from 1. Strings and frames are numbered from here
+ * so the two never meet.
+ */
+const NAME_ID_BASE: Id = 1 << 48;
+
+#[derive(Debug, Error)]
+pub enum DumpError {
+    #[error("{0}")]
+    Jvmti(
+        #[from]
+        #[source]
+        JvmtiError
+    ),
+
+    #[error("{0}")]
+    Jni(
+        #[from]
+        #[source]
+        jni::errors::Error
+    ),
+
+    #[error("{0}")]
+    Io(
+        #[from]
+        #[source]
+        io::Error
+    ),
+}
+
+/**
+ * What a dump contained, for the log.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DumpStats {
+    pub classes: usize,
+    pub objects: usize,
+    /**
+     * Objects that died between the two heap walks or whose class was loaded during the dump.
+     */
+    pub skipped: usize,
+}
+
+/**
+ * STRING records, written in batches as names come up.
+ */
+#[derive(Default)]
+struct Names {
+    ids: HashMap<String, Id>,
+    pending: Vec<(Id, String)>,
+    next: Id,
+}
+
+impl Names {
+    fn id(&mut self, name: &str) -> Id {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.next_id();
+        self.ids.insert(name.to_string(), id);
+        self.pending.push((id, name.to_string()));
+        id
+    }
+
+    fn next_id(&mut self) -> Id {
+        self.next += 1;
+        NAME_ID_BASE + self.next
+    }
+
+    fn write<W: io::Write>(&mut self, writer: &mut HprofWriter<W>) -> io::Result<()> {
+        for (id, name) in self.pending.drain(..) {
+            writer.string(id, &name)?;
+        }
+        Ok(())
+    }
+}
+
+struct Field {
+    name: Id,
+    basic_type: u8,
+    is_static: bool,
+}
+
+/**
+ * A loaded class, its identifier is its position plus one. Fields are in GetClassFields order,
+ * the links to other classes and objects come from the reference walk.
+ */
+#[derive(Default)]
+struct Class {
+    name: Id,
+    is_interface: bool,
+    object_array: bool,
+    primitive_array: bool,
+    fields: Vec<Field>,
+    reached: bool,
+    superclass: Id,
+    loader: Id,
+    signers: Id,
+    protection_domain: Id,
+    interfaces: Vec<Id>,
+}
+
+/**
+ * Where the values of a class's fields are found in the reports of a heap walk.
+ */
+#[derive(Default)]
+struct Layout {
+    /**
+     * JVMTI index and basic type, in INSTANCE DUMP order.
+     */
+    instance: Vec<(jint, u8)>,
+    /**
+     * JVMTI index, name and basic type of the class's own static fields.
+     */
+    statics: Vec<(jint, Id, u8)>,
+    /**
+     * Name and basic type of the class's own instance fields.
+     */
+    declared: Vec<(Id, u8)>,
+}
+
+/**
+ * An object found by the reference walk. `values` hold field values or array elements by index.
+ */
+struct Entry {
+    class: Id,
+    length: jint,
+    values: Vec<(jint, Value)>,
+}
+
+impl Default for Entry {
+    fn default() -> Self {
+        Entry {
+            class: 0,
+            length: -1,
+            values: Vec::new(),
+        }
+    }
+}
+
+/**
+ * Writes the objects reachable from the roots (what `jmap -dump:live` writes, without the GC)
+ * to `path` in HPROF format.
+ *
+ * Two heap walks do the work: FollowReferences numbers the objects through their tags and
+ * records roots and references, IterateThroughHeap then adds primitive field values and
+ * writes primitive arrays as they come. The application keeps running in between, objects
+ * that die meanwhile are left out.
+ *
+ * Needs can_tag_objects, and an environment of its own since every tag is overwritten.
+ * Thread stacks get line numbers and source files if the environment can provide them.
+ */
+pub fn write_dump(jvmti: &JvmtiEnv, env: &mut JNIEnv, path: &Path) -> Result<DumpStats, DumpError> {
+    let mut names = Names::default();
+    let mut classes = load_classes(jvmti, env, &mut names)?;
+    let mut objects: Vec<Entry> = classes.iter().map(|_| Entry::default()).collect();
+    let mut roots: Vec<(ReferenceKind, Id)> = Vec::new();
+    let mut threads: HashMap<Id, u32> = HashMap::new();
+
+    jvmti.for_each_reference(HeapFilter::empty(), ptr::null_mut(), ptr::null_mut(), |reference| {
+        let referee = match reference.referee.tag() {
+            0 => {
+                objects.push(Entry::default());
+                reference.referee.set_tag(objects.len() as jlong);
+                objects.len() as Id
+            }
+            tag => tag as Id,
+        };
+        if let Some(class) = class_mut(&mut classes, referee) {
+            class.reached = true;
+        }
+
+        let referrer = match &reference.referrer {
+            Some(referrer) => referrer.tag() as Id,
+            None => {
+                if let ReferenceKind::Thread = reference.kind {
+                    let serial = threads.len() as u32 + 1;
+                    threads.entry(referee).or_insert(serial);
+                }
+                roots.push((reference.kind, referee));
+                return Visit::Continue;
+            }
+        };
+        match reference.kind {
+            ReferenceKind::Field { index }
+            | ReferenceKind::StaticField { index }
+            | ReferenceKind::ArrayElement { index } => {
+                if let Some(entry) = (referrer as usize).checked_sub(1).and_then(|i| objects.get_mut(i)) {
+                    entry.values.push((index, Value::Object(referee)));
+                }
+            }
+            kind => {
+                if let Some(class) = class_mut(&mut classes, referrer) {
+                    match kind {
+                        ReferenceKind::Superclass => class.superclass = referee,
+                        ReferenceKind::ClassLoader => class.loader = referee,
+                        ReferenceKind::Signers => class.signers = referee,
+                        ReferenceKind::ProtectionDomain => class.protection_domain = referee,
+                        ReferenceKind::Interface => class.interfaces.push(referee),
+                        _ => (),
+                    }
+                }
+            }
+        }
+        Visit::Continue
+    })?;
+
+    let file = File::create(path)?;
+    let timestamp = Local::now().timestamp_millis() as u64;
+    let mut writer = HprofWriter::new(BufWriter::new(file), timestamp)?;
+
+    names.write(&mut writer)?;
+    for (i, class) in classes.iter().enumerate() {
+        if class.reached {
+            // LOAD CLASS serials are the class identifiers, which keeps STACK FRAME simple
+            writer.load_class(i as u32 + 1, i as Id + 1, NO_STACK_TRACE, class.name)?;
+        }
+    }
+    writer.stack_trace(NO_STACK_TRACE, 0, &[])?;
+    write_threads(jvmti, &mut writer, &mut names, &threads)?;
+
+    let mut failure: Option<io::Error> = None;
+    jvmti.for_each_value(HeapFilter::UNTAGGED, ptr::null_mut(), |value| {
+        match value {
+            HeapValue::Object(object) => {
+                if let Some(entry) = (object.tag() as usize).checked_sub(1).and_then(|i| objects.get_mut(i)) {
+                    entry.class = object.class_tag as Id;
+                    entry.length = object.length.unwrap_or(-1);
+                }
+            }
+            HeapValue::Field(field) => {
+                if let ReferenceKind::Field { index } | ReferenceKind::StaticField { index } = field.kind {
+                    if let Some(entry) = (field.tag() as usize).checked_sub(1).and_then(|i| objects.get_mut(i)) {
+                        entry.values.push((index, Value::Primitive(field.value)));
+                    }
+                }
+            }
+            HeapValue::Array(object, elements) => {
+                if let Err(e) = writer.primitive_array(object.tag() as Id, NO_STACK_TRACE, &elements) {
+                    failure = Some(e);
+                    return Visit::Abort;
+                }
+            }
+        }
+        Visit::Continue
+    })?;
+    if let Some(e) = failure {
+        return Err(e.into());
+    }
+
+    for (kind, object) in &roots {
+        writer.root(&root(kind, *object, &threads))?;
+    }
+
+    let object_class = classes.iter().position(|class| names.ids.get("java/lang/Object") == Some(&class.name));
+    let layouts: Vec<Layout> = (0..classes.len()).map(|i| layout(&classes, i as Id + 1)).collect();
+    let mut stats = DumpStats::default();
+
+    for (i, class) in classes.iter().enumerate() {
+        if !class.reached {
+            continue;
+        }
+        let id = i as Id + 1;
+        let layout = &layouts[i];
+        let entry = &mut objects[i];
+        entry.values.sort_unstable_by_key(|(index, _)| *index);
+
+        let statics: Vec<(Id, u8, Option<Value>)> = layout
+            .statics
+            .iter()
+            .map(|(index, name, basic_type)| (*name, *basic_type, value_at(&entry.values, *index)))
+            .collect();
+        // interfaces and arrays extend Object in a heap dump as well
+        let superclass = match (class.superclass, object_class) {
+            (0, Some(object)) if object != i => object as Id + 1,
+            (superclass, _) => superclass,
+        };
+        writer.class_dump(&ClassDump {
+            class: id,
+            stack_serial: NO_STACK_TRACE,
+            superclass,
+            loader: class.loader,
+            signers: class.signers,
+            protection_domain: class.protection_domain,
+            instance_size: layout.instance.iter().map(|(_, basic_type)| hprof::type_size(*basic_type)).sum(),
+            statics: &statics,
+            fields: &layout.declared,
+        })?;
+        stats.classes += 1;
+    }
+
+    let mut values = Vec::new();
+    for (i, entry) in objects.iter_mut().enumerate().skip(classes.len()) {
+        let id = i as Id + 1;
+        let class = match (entry.class as usize).checked_sub(1).and_then(|c| classes.get(c)) {
+            Some(class) => class,
+            None => {
+                stats.skipped += 1;
+                continue;
+            }
+        };
+        stats.objects += 1;
+
+        if class.primitive_array {
+            continue;
+        }
+        if class.object_array {
+            let mut elements = vec![0; entry.length.max(0) as usize];
+            for (index, value) in &entry.values {
+                if let (Some(element), Value::Object(object)) = (elements.get_mut(*index as usize), value) {
+                    *element = *object;
+                }
+            }
+            writer.object_array(id, NO_STACK_TRACE, entry.class, &elements)?;
+            continue;
+        }
+
+        entry.values.sort_unstable_by_key(|(index, _)| *index);
+        values.clear();
+        for (index, basic_type) in &layouts[entry.class as usize - 1].instance {
+            hprof::put_value(&mut values, *basic_type, value_at(&entry.values, *index));
+        }
+        writer.instance_dump(id, NO_STACK_TRACE, entry.class, &values)?;
+    }
+
+    writer.finish()?;
+    Ok(stats)
+}
+
+/**
+ * Tags every loaded class with its identifier and reads its name and fields. The class
+ * references are released right away, they would show up as JNI local roots otherwise.
+ */
+fn load_classes(jvmti: &JvmtiEnv, env: &mut JNIEnv, names: &mut Names) -> Result<Vec<Class>, DumpError> {
+    let loaded = jvmti.get_loaded_classes()?;
+    let mut classes = Vec::with_capacity(loaded.len());
+
+    for (i, class) in loaded.iter().enumerate() {
+        let read = jvmti
+            .set_tag(*class, i as jlong + 1)
+            .and_then(|_| read_class(jvmti, *class, names));
+        env.delete_local_ref(unsafe { JObject::from_raw(*class) })?;
+        classes.push(read?);
+    }
+    Ok(classes)
+}
+
+/**
+ * A class that is not prepared yet has no fields to report.
+ */
+fn read_class(jvmti: &JvmtiEnv, class: jclass, names: &mut Names) -> JvmtiResult<Class> {
+    let (signature, _) = jvmti.get_class_signature(class)?;
+
+    let field_ids = match jvmti.get_class_fields(class) {
+        Ok(fields) => fields.to_vec(),
+        Err(JvmtiError::ClassNotPrepared) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let mut fields = Vec::with_capacity(field_ids.len());
+    for field in field_ids {
+        let (name, field_signature, _) = jvmti.get_field_name(class, field)?;
+        let modifiers = jvmti.get_field_modifiers(class, field)?;
+        fields.push(Field {
+            name: names.id(&name),
+            basic_type: hprof::basic_type(&field_signature),
+            is_static: modifiers & ACC_STATIC != 0,
+        });
+    }
+
+    // HotSpot names classes java/lang/String in a dump, arrays keep their signature
+    let name = match signature.strip_prefix('L').and_then(|name| name.strip_suffix(';')) {
+        Some(name) => name,
+        None => signature.as_str(),
+    };
+    Ok(Class {
+        name: names.id(name),
+        is_interface: jvmti.is_interface(class)?,
+        object_array: signature.starts_with("[L") || signature.starts_with("[["),
+        primitive_array: signature.starts_with('[') && signature.len() == 2,
+        fields,
+        ..Default::default()
+    })
+}
+
+fn class_mut(classes: &mut [Class], id: Id) -> Option<&mut Class> {
+    (id as usize).checked_sub(1).and_then(|i| classes.get_mut(i))
+}
+
+fn value_at(values: &[(jint, Value)], index: jint) -> Option<Value> {
+    values
+        .binary_search_by_key(&index, |(index, _)| *index)
+        .ok()
+        .map(|position| values[position].1)
+}
+
+/**
+ * Field indexes as the JVMTI specification numbers them: the fields of every interface
+ * the class implements come first, then those of java.lang.Object down to the class itself,
+ * each class in GetClassFields order. An interface only counts its superinterfaces.
+ */
+fn layout(classes: &[Class], id: Id) -> Layout {
+    let class = match (id as usize).checked_sub(1).and_then(|i| classes.get(i)) {
+        Some(class) => class,
+        None => return Layout::default(),
+    };
+
+    // the class and its superclasses, the class first
+    let mut chain = vec![class];
+    let mut superclass = class.superclass;
+    while let Some(next) = (superclass as usize).checked_sub(1).and_then(|i| classes.get(i)) {
+        if chain.len() > classes.len() {
+            break;
+        }
+        chain.push(next);
+        superclass = next.superclass;
+    }
+
+    let mut pending: Vec<Id> = chain.iter().flat_map(|class| class.interfaces.iter().copied()).collect();
+    let mut interfaces = HashSet::new();
+    while let Some(interface) = pending.pop() {
+        if let Some(declared) = (interface as usize).checked_sub(1).and_then(|i| classes.get(i)) {
+            if interfaces.insert(interface) {
+                pending.extend(declared.interfaces.iter().copied());
+            }
+        }
+    }
+    let interface_fields: usize = interfaces
+        .iter()
+        .map(|interface| classes[*interface as usize - 1].fields.len())
+        .sum();
+
+    if class.is_interface {
+        chain.truncate(1);
+    }
+
+    let mut layout = Layout::default();
+    let mut first = (interface_fields + chain.iter().map(|class| class.fields.len()).sum::<usize>()) as jint;
+    for (depth, declaring) in chain.iter().enumerate() {
+        first -= declaring.fields.len() as jint;
+        for (position, field) in declaring.fields.iter().enumerate() {
+            let index = first + position as jint;
+            match (field.is_static, depth) {
+                (true, 0) => layout.statics.push((index, field.name, field.basic_type)),
+                (true, _) => (),
+                (false, depth) => {
+                    layout.instance.push((index, field.basic_type));
+                    if depth == 0 {
+                        layout.declared.push((field.name, field.basic_type));
+                    }
+                }
+            }
+        }
+    }
+    layout
+}
+
+fn root(kind: &ReferenceKind, object: Id, threads: &HashMap<Id, u32>) -> Root {
+    let thread_serial = |thread_tag: jlong| threads.get(&(thread_tag as Id)).copied().unwrap_or(0);
+
+    match *kind {
+        ReferenceKind::JniGlobal => Root::JniGlobal(object),
+        ReferenceKind::SystemClass => Root::StickyClass(object),
+        ReferenceKind::Monitor => Root::MonitorUsed(object),
+        ReferenceKind::StackLocal { thread_tag, depth, .. } => Root::JavaFrame {
+            object,
+            thread_serial: thread_serial(thread_tag),
+            frame: depth,
+        },
+        ReferenceKind::JniLocal { thread_tag, depth, .. } => Root::JniLocal {
+            object,
+            thread_serial: thread_serial(thread_tag),
+            frame: depth,
+        },
+        ReferenceKind::Thread => {
+            let thread_serial = thread_serial(object as jlong);
+            Root::ThreadObject {
+                object,
+                thread_serial,
+                stack_serial: NO_STACK_TRACE + thread_serial,
+            }
+        }
+        _ => Root::Unknown(object),
+    }
+}
+
+/**
+ * One STACK TRACE per thread root, so MAT can show what each thread was doing
+ * and which frame holds which local. Threads that ended since get an empty one.
+ */
+fn write_threads<W: io::Write>(
+    jvmti: &JvmtiEnv,
+    writer: &mut HprofWriter<W>,
+    names: &mut Names,
+    threads: &HashMap<Id, u32>,
+) -> Result<(), DumpError> {
+    let mut written = HashSet::new();
+
+    for thread in jvmti.get_all_threads()?.iter() {
+        let serial = match threads.get(&(jvmti.get_tag(*thread)? as Id)) {
+            Some(serial) => *serial,
+            None => continue,
+        };
+        let frames = match jvmti.get_stack_trace(*thread, 0, MAX_FRAMES) {
+            Ok(frames) => frames,
+            Err(JvmtiError::ThreadNotAlive) => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut frame_ids = Vec::with_capacity(frames.len());
+        for frame in frames {
+            let resolved = frame.resolve(jvmti)?;
+            let class_serial = jvmti.get_tag(jvmti.get_method_declaring_class(frame.method)?)? as u32;
+            let line = match (resolved.native, resolved.line_number) {
+                (true, _) => hprof::LINE_NATIVE,
+                (false, Some(line)) => line,
+                (false, None) => hprof::LINE_UNKNOWN,
+            };
+
+            let frame_id = names.next_id();
+            let method = names.id(&resolved.method_name);
+            let signature = names.id(&resolved.signature);
+            let source_file = resolved.source_file.map(|file| names.id(&file)).unwrap_or(0);
+            names.write(writer)?;
+            writer.stack_frame(frame_id, method, signature, source_file, class_serial, line)?;
+            frame_ids.push(frame_id);
+        }
+        writer.stack_trace(NO_STACK_TRACE + serial, serial, &frame_ids)?;
+        written.insert(serial);
+    }
+
+    for serial in threads.values().filter(|serial| !written.contains(*serial)) {
+        writer.stack_trace(NO_STACK_TRACE + serial, *serial, &[])?;
+    }
+    Ok(())
+}
+
+/**
+ * Writes heap dumps on request into [DIAGNOSTICS] heap_dump_dir, one at a time.
+ */
+pub struct HeapDumper {
+    dir: Option<PathBuf>,
+    lock: Mutex<()>,
+    dumped_on_oom: AtomicBool,
+}
+
+impl HeapDumper {
+    pub fn new(settings: &DiagnosticsConfig) -> Arc<HeapDumper> {
+        Arc::new(HeapDumper {
+            dir: settings.heap_dump_dir.clone(),
+            lock: Mutex::new(()),
+            dumped_on_oom: AtomicBool::new(false),
+        })
+    }
+
+    /**
+     * The calling thread must be attached to `vm`. Every dump gets a JVMTI environment of its own,
+     * the local references it creates are released before it returns.
+     */
+    pub fn dump(&self, vm: &JavaVM, env: &mut JNIEnv) -> Result<(), GeneralError> {
+        let _dumping = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let started = Instant::now();
+        let path = self.dir.clone().unwrap_or_default().join(format!(
+            "heap-{}-{}.hprof",
+            process::id(),
+            Local::now().format("%Y%m%d-%H%M%S%3f")
+        ));
+        debug!("Writing a heap dump to {}", path.display());
+
+        let jvmti = vm
+            .get_jvmti_env(JVMTI_VERSION)
+            .map_err(|e| GeneralError::jvmti("cannot get a JVMTI environment for the heap dump", e))?;
+        let required = Capabilities::new().can_tag_objects();
+        let optional = Capabilities::new().can_get_line_numbers().can_get_source_file_name();
+
+        let result = capabilities::negotiate(&jvmti, required, optional)
+            .map_err(|e| match e {
+                CapabilityError::Missing(missing) => {
+                    GeneralError::JdkError(format!("the VM does not support {}", missing))
+                }
+                CapabilityError::Jvmti(e) => GeneralError::jvmti("cannot add the heap dump capabilities", e),
+            })
+            .and_then(|_| {
+                env.with_local_frame(64, |env| write_dump(&jvmti, env, &path))
+                    .map_err(|e| dump_error(&path, e))
+            });
+        let _ = jvmti.dispose_environment();
+
+        match result {
+            Ok(stats) => {
+                info!(
+                    "Heap dump written to {} in {:?}: {} classes, {} objects",
+                    path.display(),
+                    started.elapsed(),
+                    stats.classes,
+                    stats.objects
+                );
+                if stats.skipped > 0 {
+                    debug!("{} objects left out of the heap dump", stats.skipped);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&path);
+                Err(e)
+            }
+        }
+    }
+
+    /**
+     * Writes one heap dump, the first time the Java heap runs out, from the thread that ran out.
+     * Adds can_generate_resource_exhaustion_heap_events to `jvmti` and handles ResourceExhausted there.
+     */
+    pub fn dump_on_oom(self: &Arc<Self>, jvmti: &JvmtiEnv) -> Result<(), GeneralError> {
+        jvmti
+            .add_capabilities(Capabilities::new().can_generate_resource_exhaustion_heap_events().into())
+            .map_err(|e| GeneralError::jvmti("cannot add can_generate_resource_exhaustion_heap_events", e))?;
+
+        let dumper = Arc::clone(self);
+        EventRegistry::new(jvmti)
+            .on_resource_exhausted(move |env, event| {
+                if !event.is_java_heap() || dumper.dumped_on_oom.swap(true, Ordering::SeqCst) {
+                    return;
+                }
+                warn!("{}, writing a heap dump", event.description);
+                let result = env
+                    .get_java_vm()
+                    .map_err(|e| GeneralError::jni("cannot get the VM for the heap dump", e))
+                    .and_then(|vm| dumper.dump(&vm, env));
+                if let Err(e) = result {
+                    error!("{}", e);
+                }
+            })
+            .map_err(|e| GeneralError::jvmti("cannot handle ResourceExhausted", e))
+    }
+}
+
+fn dump_error(path: &Path, e: DumpError) -> GeneralError {
+    match e {
+        DumpError::Jvmti(e) => GeneralError::jvmti("cannot write the heap dump", e),
+        DumpError::Jni(e) => GeneralError::jni("cannot write the heap dump", e),
+        DumpError::Io(e) => GeneralError::diagnostics(format!("cannot write the heap dump to {}", path.display()), e),
+    }
+}
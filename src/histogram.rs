@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local};
+use jni::objects::JObject;
+use jni::sys::jlong;
+use jni::{JNIEnv, JavaVM};
+use log::{debug, info};
+
+use crate::config::DiagnosticsConfig;
+use crate::errors::GeneralError;
+use crate::jvmti::capabilities::Capabilities;
+use crate::jvmti::errors::JvmtiResult;
+use crate::jvmti::heap::{HeapFilter, Visit};
+use crate::jvmti::jvmti_sys::JVMTI_VERSION;
+use crate::jvmti::jvmti_wrapper::JvmtiEnv;
+use crate::jvmti::stack::class_name;
+use crate::jvmti::sync::JvmtiSupplier;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+impl ReportFormat {
+    /**
+     * The config only lets text, json and csv through, anything else is text.
+     */
+    pub fn parse(value: &str) -> ReportFormat {
+        match value {
+            "json" => ReportFormat::Json,
+            "csv" => ReportFormat::Csv,
+            _ => ReportFormat::Text,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ClassStats {
+    pub class_name: String,
+    pub instances: u64,
+    pub bytes: u64,
+}
+
+/**
+ * Instances and shallow size of the live objects per class, largest first, like `jmap -histo:live`.
+ */
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    pub taken: DateTime<Local>,
+    pub classes: Vec<ClassStats>,
+}
+
+/**
+ * What changed between two histograms. Counts are signed, classes that grew the most come first
+ * and unchanged classes are left out.
+ */
+#[derive(Clone, Debug)]
+pub struct ClassDelta {
+    pub class_name: String,
+    pub instances: i64,
+    pub bytes: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct HistogramDiff {
+    pub from: DateTime<Local>,
+    pub to: DateTime<Local>,
+    pub classes: Vec<ClassDelta>,
+}
+
+impl Histogram {
+    /**
+     * Tags every loaded class with its index so a single heap walk can attribute each object
+     * to its class. Classes loaded during the walk are not counted, classes of the same name
+     * from different loaders are merged. A full collection runs first, garbage would only make
+     * the diffs noisy.
+     * Needs can_tag_objects. Tags belong to the environment, so `jvmti` should be one of its own.
+     */
+    pub fn take(jvmti: &JvmtiEnv, env: &mut JNIEnv) -> JvmtiResult<Histogram> {
+        jvmti.force_garbage_collection()?;
+        let taken = Local::now();
+        let classes = jvmti.get_loaded_classes()?;
+        let mut totals = vec![(0u64, 0u64); classes.len()];
+
+        let walked = classes
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, class)| jvmti.set_tag(*class, i as jlong + 1))
+            .and_then(|_| {
+                jvmti.for_each_object(HeapFilter::CLASS_UNTAGGED, ptr::null_mut(), |object| {
+                    if let Some((instances, bytes)) = totals.get_mut(object.class_tag as usize - 1) {
+                        *instances += 1;
+                        *bytes += object.size as u64;
+                    }
+                    Visit::Continue
+                })
+            });
+        let named: JvmtiResult<Vec<(String, u64, u64)>> = walked.and_then(|_| {
+            classes
+                .iter()
+                .zip(&totals)
+                .filter(|(_, (instances, _))| *instances > 0)
+                .map(|(class, &(instances, bytes))| {
+                    jvmti
+                        .get_class_signature(*class)
+                        .map(|(signature, _)| (class_name(&signature), instances, bytes))
+                })
+                .collect()
+        });
+
+        // GetLoadedClasses hands out a local reference per class
+        for class in classes.iter() {
+            let _ = jvmti.set_tag(*class, 0);
+            let _ = env.delete_local_ref(unsafe { JObject::from_raw(*class) });
+        }
+
+        let mut merged: HashMap<String, (u64, u64)> = HashMap::new();
+        for (name, instances, bytes) in named? {
+            let total = merged.entry(name).or_default();
+            total.0 += instances;
+            total.1 += bytes;
+        }
+        let mut classes: Vec<ClassStats> = merged
+            .into_iter()
+            .map(|(class_name, (instances, bytes))| ClassStats {
+                class_name,
+                instances,
+                bytes,
+            })
+            .collect();
+        classes.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.class_name.cmp(&b.class_name)));
+
+        Ok(Histogram { taken, classes })
+    }
+
+    pub fn total_instances(&self) -> u64 {
+        self.classes.iter().map(|class| class.instances).sum()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.classes.iter().map(|class| class.bytes).sum()
+    }
+
+    pub fn diff(&self, previous: &Histogram) -> HistogramDiff {
+        let mut deltas: HashMap<&str, (i64, i64)> = HashMap::new();
+        for class in &self.classes {
+            let delta = deltas.entry(&class.class_name).or_default();
+            delta.0 += class.instances as i64;
+            delta.1 += class.bytes as i64;
+        }
+        for class in &previous.classes {
+            let delta = deltas.entry(&class.class_name).or_default();
+            delta.0 -= class.instances as i64;
+            delta.1 -= class.bytes as i64;
+        }
+
+        let mut classes: Vec<ClassDelta> = deltas
+            .into_iter()
+            .filter(|(_, delta)| *delta != (0, 0))
+            .map(|(class_name, (instances, bytes))| ClassDelta {
+                class_name: class_name.to_string(),
+                instances,
+                bytes,
+            })
+            .collect();
+        classes.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.class_name.cmp(&b.class_name)));
+
+        HistogramDiff {
+            from: previous.taken,
+            to: self.taken,
+            classes,
+        }
+    }
+
+    /**
+     * At most `limit` classes, all of them for 0. Totals always cover the whole heap.
+     * JSON is one object per line so appended histograms stay readable line by line.
+     */
+    pub fn render(&self, format: ReportFormat, limit: usize) -> String {
+        let classes = limited(&self.classes, limit);
+        let taken = self.taken.format(TIME_FORMAT).to_string();
+
+        match format {
+            ReportFormat::Text => {
+                let mut out = format!("Heap histogram at {}\n\n", taken);
+                out.push_str(" num     #instances         #bytes  class name\n");
+                out.push_str("----------------------------------------------\n");
+                for (i, class) in classes.iter().enumerate() {
+                    out.push_str(&format!(
+                        "{:>4}: {:>14} {:>14}  {}\n",
+                        i + 1,
+                        class.instances,
+                        class.bytes,
+                        class.class_name
+                    ));
+                }
+                out.push_str(&format!(
+                    "Total {:>14} {:>14}\n\n",
+                    self.total_instances(),
+                    self.total_bytes()
+                ));
+                out
+            }
+            ReportFormat::Json => {
+                let classes: Vec<json::JsonValue> = classes
+                    .iter()
+                    .map(|class| {
+                        json::object! {
+                            class: class.class_name.as_str(),
+                            instances: class.instances,
+                            bytes: class.bytes,
+                        }
+                    })
+                    .collect();
+                let report = json::object! {
+                    taken: taken,
+                    total_instances: self.total_instances(),
+                    total_bytes: self.total_bytes(),
+                    classes: classes,
+                };
+                report.dump() + "\n"
+            }
+            ReportFormat::Csv => {
+                let mut out = String::from("taken,class,instances,bytes\n");
+                for class in classes {
+                    out.push_str(&format!(
+                        "{},{},{},{}\n",
+                        taken,
+                        csv_field(&class.class_name),
+                        class.instances,
+                        class.bytes
+                    ));
+                }
+                out
+            }
+        }
+    }
+}
+
+impl HistogramDiff {
+    pub fn render(&self, format: ReportFormat, limit: usize) -> String {
+        let classes = limited(&self.classes, limit);
+        let from = self.from.format(TIME_FORMAT).to_string();
+        let to = self.to.format(TIME_FORMAT).to_string();
+        let total_instances: i64 = self.classes.iter().map(|class| class.instances).sum();
+        let total_bytes: i64 = self.classes.iter().map(|class| class.bytes).sum();
+
+        match format {
+            ReportFormat::Text => {
+                let mut out = format!("Heap histogram changes from {} to {}\n\n", from, to);
+                out.push_str(" num     #instances         #bytes  class name\n");
+                out.push_str("----------------------------------------------\n");
+                for (i, class) in classes.iter().enumerate() {
+                    out.push_str(&format!(
+                        "{:>4}: {:>+14} {:>+14}  {}\n",
+                        i + 1,
+                        class.instances,
+                        class.bytes,
+                        class.class_name
+                    ));
+                }
+                out.push_str(&format!("Total {:>+14} {:>+14}\n\n", total_instances, total_bytes));
+                out
+            }
+            ReportFormat::Json => {
+                let classes: Vec<json::JsonValue> = classes
+                    .iter()
+                    .map(|class| {
+                        json::object! {
+                            class: class.class_name.as_str(),
+                            instances_delta: class.instances,
+                            bytes_delta: class.bytes,
+                        }
+                    })
+                    .collect();
+                let report = json::object! {
+                    from: from,
+                    to: to,
+                    instances_delta: total_instances,
+                    bytes_delta: total_bytes,
+                    classes: classes,
+                };
+                report.dump() + "\n"
+            }
+            ReportFormat::Csv => {
+                let mut out = String::from("from,to,class,instances_delta,bytes_delta\n");
+                for class in classes {
+                    out.push_str(&format!(
+                        "{},{},{},{},{}\n",
+                        from,
+                        to,
+                        csv_field(&class.class_name),
+                        class.instances,
+                        class.bytes
+                    ));
+                }
+                out
+            }
+        }
+    }
+}
+
+fn limited<T>(classes: &[T], limit: usize) -> &[T] {
+    match limit {
+        0 => classes,
+        limit => &classes[..limit.min(classes.len())],
+    }
+}
+
+/**
+ * Hidden and lambda classes can contain characters CSV cares about.
+ */
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/**
+ * Takes histograms on request and writes them as [DIAGNOSTICS] says.
+ * The last one is kept to diff the next one against.
+ */
+pub struct HeapReporter {
+    settings: DiagnosticsConfig,
+    previous: Mutex<Option<Histogram>>,
+}
+
+impl HeapReporter {
+    pub fn new(settings: &DiagnosticsConfig) -> Arc<HeapReporter> {
+        Arc::new(HeapReporter {
+            settings: settings.clone(),
+            previous: Mutex::new(None),
+        })
+    }
+
+    /**
+     * The calling thread must be attached to `vm`. Every histogram gets a JVMTI environment
+     * of its own, so its tags neither collide with nor outlive anyone else's.
+     */
+    pub fn report(&self, vm: &JavaVM, env: &mut JNIEnv) -> Result<(), GeneralError> {
+        // also keeps a signal and the exit histogram from walking the heap at the same time
+        let mut previous = self.previous.lock().unwrap_or_else(|e| e.into_inner());
+
+        let jvmti = vm
+            .get_jvmti_env(JVMTI_VERSION)
+            .map_err(|e| GeneralError::jvmti("cannot get a JVMTI environment for the heap histogram", e))?;
+        let histogram = jvmti
+            .add_capabilities(Capabilities::new().can_tag_objects().into())
+            .map_err(|e| GeneralError::jvmti("cannot add can_tag_objects", e))
+            .and_then(|_| {
+                Histogram::take(&jvmti, env).map_err(|e| GeneralError::jvmti("cannot take a heap histogram", e))
+            });
+        let _ = jvmti.dispose_environment();
+        let histogram = histogram?;
+        debug!(
+            "Heap histogram: {} classes, {} instances, {} bytes",
+            histogram.classes.len(),
+            histogram.total_instances(),
+            histogram.total_bytes()
+        );
+
+        let format = ReportFormat::parse(&self.settings.histogram_format);
+        let limit = self.settings.histogram_limit as usize;
+        let report = match previous.as_ref() {
+            Some(previous) if self.settings.histogram_diff => histogram.diff(previous).render(format, limit),
+            _ => histogram.render(format, limit),
+        };
+        *previous = Some(histogram);
+        drop(previous);
+
+        self.write(&report)
+    }
+
+    fn write(&self, report: &str) -> Result<(), GeneralError> {
+        match &self.settings.histogram_file {
+            Some(path) => {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(report.as_bytes()))
+                    .map_err(|e| {
+                        GeneralError::diagnostics(format!("cannot write the heap histogram to {}", path.display()), e)
+                    })?;
+                info!("Heap histogram written to {}", path.display());
+                Ok(())
+            }
+            None => io::stderr()
+                .write_all(report.as_bytes())
+                .map_err(|e| GeneralError::diagnostics(String::from("cannot write the heap histogram to stderr"), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn histogram(second: u32, classes: &[(&str, u64, u64)]) -> Histogram {
+        Histogram {
+            taken: Local.with_ymd_and_hms(2026, 1, 2, 12, 0, second).unwrap(),
+            classes: classes
+                .iter()
+                .map(|&(class_name, instances, bytes)| ClassStats {
+                    class_name: class_name.to_string(),
+                    instances,
+                    bytes,
+                })
+                .collect(),
+        }
+    }
+
+    fn deltas(diff: &HistogramDiff) -> Vec<(&str, i64, i64)> {
+        diff.classes
+            .iter()
+            .map(|class| (class.class_name.as_str(), class.instances, class.bytes))
+            .collect()
+    }
+
+    #[test]
+    fn diff_orders_by_growth_and_leaves_out_unchanged_classes() {
+        let before = histogram(0, &[("a.Same", 10, 100), ("a.Shrinks", 5, 500), ("a.Grows", 1, 10), ("a.Gone", 2, 20)]);
+        let after = histogram(1, &[("a.Same", 10, 100), ("a.Shrinks", 1, 100), ("a.Grows", 9, 90), ("a.New", 3, 90)]);
+
+        let diff = after.diff(&before);
+        assert_eq!(
+            deltas(&diff),
+            [("a.New", 3, 90), ("a.Grows", 8, 80), ("a.Gone", -2, -20), ("a.Shrinks", -4, -400)]
+        );
+        assert_eq!(diff.from, before.taken);
+        assert_eq!(diff.to, after.taken);
+        assert!(after.diff(&after).classes.is_empty());
+    }
+
+    #[test]
+    fn diff_breaks_ties_by_class_name() {
+        let before = histogram(0, &[]);
+        let after = histogram(1, &[("b.B", 1, 16), ("a.A", 2, 16), ("c.C", 1, 24)]);
+        assert_eq!(deltas(&after.diff(&before)), [("c.C", 1, 24), ("a.A", 2, 16), ("b.B", 1, 16)]);
+    }
+
+    #[test]
+    fn diff_counts_only_changed_sizes() {
+        // same instance count, objects of a different size
+        let before = histogram(0, &[("a.Buffer", 2, 64)]);
+        let after = histogram(1, &[("a.Buffer", 2, 128)]);
+        assert_eq!(deltas(&after.diff(&before)), [("a.Buffer", 0, 64)]);
+    }
+
+    #[test]
+    fn renders_text_with_totals_over_the_whole_heap() {
+        let histogram = histogram(0, &[("a.Big", 2, 200), ("a.Small", 1, 10)]);
+        let text = histogram.render(ReportFormat::Text, 1);
+        assert!(text.starts_with("Heap histogram at 2026-01-02 12:00:00.000\n"));
+        assert!(text.contains("   1:              2            200  a.Big\n"));
+        assert!(!text.contains("a.Small"));
+        assert!(text.contains("Total              3            210\n"));
+    }
+
+    #[test]
+    fn escapes_csv_fields() {
+        let histogram = histogram(0, &[("a.Plain", 1, 16), ("a.Odd,\"Name\"", 1, 8)]);
+        let csv = histogram.render(ReportFormat::Csv, 0);
+        assert_eq!(
+            csv,
+            concat!(
+                "taken,class,instances,bytes\n",
+                "2026-01-02 12:00:00.000,a.Plain,1,16\n",
+                "2026-01-02 12:00:00.000,\"a.Odd,\"\"Name\"\"\",1,8\n"
+            )
+        );
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+    }
+
+    #[test]
+    fn renders_json_one_object_per_line() {
+        let histogram = histogram(0, &[("a.\"Quoted\"", 1, 8)]);
+        let rendered = histogram.render(ReportFormat::Json, 0);
+        assert_eq!(rendered.lines().count(), 1);
+
+        let parsed = json::parse(&rendered).unwrap();
+        assert_eq!(parsed["taken"], "2026-01-02 12:00:00.000");
+        assert_eq!(parsed["total_bytes"], 8);
+        assert_eq!(parsed["classes"][0]["class"], "a.\"Quoted\"");
+    }
+
+    #[test]
+    fn renders_diff_with_signed_counts() {
+        let before = histogram(0, &[("a.Gone", 1, 16)]);
+        let after = histogram(1, &[("a.New", 2, 32)]);
+        let diff = after.diff(&before);
+
+        let text = diff.render(ReportFormat::Text, 0);
+        assert!(text.contains("   1:             +2            +32  a.New\n"));
+        assert!(text.contains("   2:             -1            -16  a.Gone\n"));
+        assert!(text.contains("Total             +1            +16\n"));
+
+        let csv = diff.render(ReportFormat::Csv, 1);
+        assert_eq!(csv.lines().nth(1), Some("2026-01-02 12:00:00.000,2026-01-02 12:00:01.000,a.New,2,32"));
+        assert_eq!(csv.lines().count(), 2);
+
+        let parsed = json::parse(&diff.render(ReportFormat::Json, 0)).unwrap();
+        assert_eq!(parsed["bytes_delta"], 16);
+        assert_eq!(parsed["classes"][1]["instances_delta"], -1);
+    }
+}
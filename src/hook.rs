@@ -15,9 +15,13 @@ use jni::{
     sys::{jclass, jint, jobject},
 };
 
-use log::{debug, info, trace};
-use once_cell::sync::Lazy;
+use log::{debug, trace};
+use once_cell::sync::{Lazy, OnceCell};
 
+use crate::config::{SecurityConfig, TransformerConfig};
+use crate::crypto::{self, ENCRYPT_MAGIC_NUMBER};
+use crate::errors::{self, GeneralError};
+use crate::integrity;
 use crate::jvmti::jvmti_sys::jvmtiEnv;
 use crate::jvmti::jvmti_wrapper::JvmtiEnv;
 use crate::utility::{self, JNIEnvUtility};
@@ -28,8 +32,35 @@ static LOADED_JAR_MAP: Lazy<Mutex<HashMap<String, bool>>> =
 static PRELOAD_CLASS_MAP: Lazy<Mutex<HashMap<String, Vec<u8>>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
+static SETTINGS: OnceCell<(TransformerConfig, SecurityConfig)> = OnceCell::new();
 
-const ENCRYPT_MAGIC_NUMBER : u32 = 0xDEADC0DE;
+/**
+ * Must be called before the ClassFileLoadHook is enabled, without it every class is decrypted
+ * and nothing is protected.
+ */
+pub fn configure(transformers: &TransformerConfig, security: &SecurityConfig) {
+    let _ = SETTINGS.set((transformers.clone(), security.clone()));
+}
+
+fn in_packages(class_name: &str, packages: &[String]) -> bool {
+    packages.iter().any(|package| {
+        let package = package.trim_end_matches('.');
+        class_name.len() > package.len()
+            && class_name.starts_with(package)
+            && class_name.as_bytes()[package.len()] == b'.'
+    })
+}
+
+/**
+ * A class from a protected package arrived in plain form, someone replaced or
+ * repacked the jar. Refuse to run rather than load it.
+ */
+fn reject_plain_class(class_name: &str) -> ! {
+    errors::report(&GeneralError::SecurityError(format!(
+        "class {} is in a protected package but is not encrypted",
+        class_name
+    )))
+}
 
 /**
  * We can perform some operations on the class in the class_hoke_event, 
@@ -38,11 +69,11 @@ const ENCRYPT_MAGIC_NUMBER : u32 = 0xDEADC0DE;
  * This example uses class_hook_event to decrypt the class.
  * It supports two methods of bytecode decryption:
  *  1. Encrypting the class itself. 
- *      This method does not support annotation scanning by frameworks like Spring.
+ *     This method does not support annotation scanning by frameworks like Spring.
  * 
  *  2. Clearing the functions of the class and storing the original encrypted copy in META-INF/.classes/, 
- *      retaining fields, function signatures, and annotations. 
- *      This method allows annotation scanning by frameworks like Spring.
+ *     retaining fields, function signatures, and annotations. 
+ *     This method allows annotation scanning by frameworks like Spring.
  */
 #[allow(unused)]
 pub unsafe extern "C" fn class_hook_event(
@@ -64,6 +95,21 @@ pub unsafe extern "C" fn class_hook_event(
     let class_name = unsafe { CStr::from_ptr(name).to_str().unwrap() };
     let class_name = class_name.replace("/", ".");
 
+    let (decrypt, protected) = match SETTINGS.get() {
+        Some((transformers, security)) => (
+            transformers.decrypt_classes
+                && (transformers.decrypt_packages.is_empty()
+                    || in_packages(&class_name, &transformers.decrypt_packages)),
+            in_packages(&class_name, &security.protected_packages),
+        ),
+        None => (true, false),
+    };
+    // every jar a class comes from is verified, whether or not anything in it is decrypted
+    let verify = integrity::verifies_jars();
+    if !decrypt && !protected && !verify {
+        return;
+    }
+
     let mut env = JNIEnv::from_raw(jni_env).unwrap();
     let jvmti = JvmtiEnv::from(jvmti_env);
 
@@ -72,14 +118,19 @@ pub unsafe extern "C" fn class_hook_event(
     let magic_number = &class_data[0..4];
     let magic_number = utility::read_to::<u32>(magic_number);
 
-    let decrypter = |class_data: &mut [u8]| -> Vec<u8> {
-        // Implement your own decryption logic here.
-        class_data.to_owned()
-    };
-
     if magic_number == ENCRYPT_MAGIC_NUMBER {
+        if !decrypt {
+            debug!("{} is encrypted but outside decrypt_packages", class_name);
+            return;
+        }
         // Encryption mode one, we directly decrypt it.
-        let mut decrypt_class_data = decrypter(&mut class_data);
+        let decrypt_class_data = match crypto::decrypt(&class_data) {
+            Ok(data) => data,
+            Err(e) => errors::report(&GeneralError::CryptoError(format!(
+                "cannot decrypt class {} ({})",
+                class_name, e
+            ))),
+        };
         let memory = jvmti.allocate(decrypt_class_data.len() as jlong);
         *new_class_data = memory.unwrap();
         *new_class_data_len = decrypt_class_data.len() as jint;
@@ -91,45 +142,50 @@ pub unsafe extern "C" fn class_hook_event(
         return;
     }
 
-    if protection_domain.is_null() {
+    if protection_domain.is_null() || (!decrypt && !verify) {
+        if protected {
+            reject_plain_class(&class_name);
+        }
         return;
     }
 
     let protection_domain = unsafe { JObject::from_raw(protection_domain) };
     let class_location = env.get_code_location(&protection_domain);
     if class_location.is_empty() {
+        if protected {
+            reject_plain_class(&class_name);
+        }
         return;
     }
 
     let path_decode = url_decode(class_location.as_str());
-    let path = if path_decode.starts_with("file:/") {
-        &path_decode[6..]
-    } else if class_location.starts_with("/") {
-        &path_decode[1..]
-    } else {
-        path_decode.as_str()
-    };
+    let path = local_path(&path_decode);
 
     let path = match path.find(".jar") {
         Some(index) => &path[0..index + 4],
         None => {
             debug!("{} not belongs to jar", class_name);
+            if protected {
+                reject_plain_class(&class_name);
+            }
             return;
         }
     };
 
-    // If the JAR has not been parsed before, parse the JAR to find the encrypted class copies and decrypt them
+    // If the JAR has not been parsed before, verify it and parse it to find the encrypted class copies and decrypt them.
+    // A jar that cannot be read or verified stops the launch before any of its classes runs.
     {
         let mut loaded_jar_map = LOADED_JAR_MAP.lock().unwrap();
         if !loaded_jar_map.contains_key(path) {
+            if let Err(e) = load_jar(Path::new(path)) {
+                errors::report(&e);
+            }
             loaded_jar_map.insert(path.to_owned(), true);
-
-            let path = Path::new(path);
-            let buffer = BufReader::new(fs::File::open(path).unwrap());
-            debug!("Load jar {}", path.display());
-            read_and_decrypt_jar_files(buffer);
         }
     }
+    if !decrypt {
+        return;
+    }
 
     // The class files encrypted using encryption method two must have their decrypted data available
     {
@@ -140,13 +196,42 @@ pub unsafe extern "C" fn class_hook_event(
             *new_class_data = memory.unwrap();
             *new_class_data_len = class_data.len() as jint;
             std::ptr::copy_nonoverlapping(class_data.as_ptr(), *new_class_data, class_data.len());
+            return;
         }
     }
 
+    if protected {
+        reject_plain_class(&class_name);
+    }
+
     // Unencrypted files do not require processing
     debug!("class_hook_event: {}", class_name);
 }
 
+/**
+ * Verifies a jar the first time one of its classes is loaded, then collects its encrypted class copies.
+ */
+fn load_jar(path: &Path) -> Result<(), GeneralError> {
+    let data = fs::read(path)
+        .map_err(|e| GeneralError::IntegrityError(format!("cannot read {} ({})", path.display(), e)))?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    integrity::verify_jar(&name, &data)?;
+    debug!("Load jar {}", path.display());
+    read_and_decrypt_jar_files(&name, Cursor::new(data))
+}
+
+/**
+ * The file path in a code source URL path. It stays absolute, only `/C:/app/app.jar`
+ * loses its leading slash so it can be opened on Windows.
+ */
+fn local_path(url_path: &str) -> &str {
+    let path = url_path.strip_prefix("file:").unwrap_or(url_path);
+    match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => &path[1..],
+        _ => path,
+    }
+}
+
 fn url_decode(input: &str) -> String {
     let mut data = input.chars().peekable();
     let mut result = String::new();
@@ -188,13 +273,14 @@ fn url_decode(input: &str) -> String {
  * Read the original class file from the /META-INF/.classes/ folder and decrypt it
  */
 #[allow(unused)]
-pub fn read_and_decrypt_jar_files<R: Read + Seek>(reader: R) -> bool {
+pub fn read_and_decrypt_jar_files<R: Read + Seek>(name: &str, reader: R) -> Result<(), GeneralError> {
     const ZIP_CLASS_PATH: &str = "META-INF/.classes/";
     const ZIP_CLASS_PATH_LEN: usize = ZIP_CLASS_PATH.len();
 
-    let mut archive = zip::ZipArchive::new(reader).unwrap();
+    let unreadable = |e: zip::result::ZipError| GeneralError::IntegrityError(format!("cannot read {} ({})", name, e));
+    let mut archive = zip::ZipArchive::new(reader).map_err(unreadable)?;
     for i in 0..archive.len() {
-        let mut file = archive.by_index(i).unwrap();
+        let mut file = archive.by_index(i).map_err(unreadable)?;
         let outpath = match file.enclosed_name() {
             Some(path) => path.to_owned(),
             None => continue,
@@ -212,9 +298,13 @@ pub fn read_and_decrypt_jar_files<R: Read + Seek>(reader: R) -> bool {
                     continue;
                 }
 
+                // A nested jar is verified on its own, the outer jar's hash says nothing about what we unpack
+                let nested_name = format!("{}!/{}", name, file_name);
+                integrity::verify_jar(&nested_name, &data)?;
+
                 let cursor = Cursor::new(data);
                 let buffer = BufReader::new(cursor);
-                read_and_decrypt_jar_files(buffer);
+                read_and_decrypt_jar_files(&nested_name, buffer)?;
                 continue;
             }
 
@@ -233,28 +323,25 @@ pub fn read_and_decrypt_jar_files<R: Read + Seek>(reader: R) -> bool {
                     }
                 }
                 Err(e) => {
-                    debug!(
-                        "Read file<{}> magic error. Cause: ({})",
-                        &class_name,
-                        e.to_string()
-                    );
+                    debug!("Read file<{}> magic error. Cause: ({})", &class_name, e);
                     continue;
                 }
             }
 
-            let decrypter = |class_data: &mut [u8]| -> Vec<u8> {
-                // Implement your own decryption logic here.
-                class_data.to_owned()
-            };
-
             let decrypt_class_data = {
-                let mut data: Vec<u8> = Vec::new();
+                let mut data: Vec<u8> = magic_number.to_vec();
                 let result = file.read_to_end(&mut data);
                 if let Err(e) = result {
                     debug!("Reading class error {}", &class_name);
                     continue;
                 }
-                decrypter(&mut data)
+                match crypto::decrypt(&data) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        debug!("Cannot decrypt class {}. Cause: ({})", &class_name, e);
+                        continue;
+                    }
+                }
             };
 
             // The purpose is to narrow the scope, allowing CLASS_MAP to automatically release the lock.
@@ -269,5 +356,30 @@ pub fn read_and_decrypt_jar_files<R: Read + Seek>(reader: R) -> bool {
             }
         }
     }
-    true
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_code_source_paths_absolute() {
+        let cases = [
+            ("/tmp/rv/app/app.jar", "/tmp/rv/app/app.jar"),
+            ("file:/opt/app/app.jar!/BOOT-INF/lib/dep.jar", "/opt/app/app.jar!/BOOT-INF/lib/dep.jar"),
+            ("/C:/Program Files/app/app.jar", "C:/Program Files/app/app.jar"),
+            ("file:/d:/app/app.jar", "d:/app/app.jar"),
+            ("/1:/app.jar", "/1:/app.jar"),
+            ("app.jar", "app.jar"),
+        ];
+        for (url_path, expected) in cases {
+            assert_eq!(local_path(url_path), expected, "{}", url_path);
+        }
+    }
+
+    #[test]
+    fn decodes_url_escapes() {
+        assert_eq!(url_decode("/opt/my%20app/app.jar"), "/opt/my app/app.jar");
+    }
 }
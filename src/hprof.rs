@@ -0,0 +1,403 @@
+use std::io::{self, Write};
+
+use crate::jvmti::heap::{PrimitiveArray, PrimitiveValue};
+
+/**
+ * Object, class, string and frame identifiers. Dumps are written with 8 byte identifiers,
+ * like HotSpot does on 64 bit VMs.
+ */
+pub type Id = u64;
+
+const HEADER: &[u8] = b"JAVA PROFILE 1.0.2\0";
+const ID_SIZE: u32 = 8;
+
+/**
+ * Heap dump sub-records are collected into HEAP DUMP SEGMENT records of about this size.
+ */
+const SEGMENT_SIZE: usize = 1 << 20;
+
+const TAG_STRING: u8 = 0x01;
+const TAG_LOAD_CLASS: u8 = 0x02;
+const TAG_STACK_FRAME: u8 = 0x04;
+const TAG_STACK_TRACE: u8 = 0x05;
+const TAG_HEAP_DUMP_SEGMENT: u8 = 0x1C;
+const TAG_HEAP_DUMP_END: u8 = 0x2C;
+
+const SUB_ROOT_UNKNOWN: u8 = 0xFF;
+const SUB_ROOT_JNI_GLOBAL: u8 = 0x01;
+const SUB_ROOT_JNI_LOCAL: u8 = 0x02;
+const SUB_ROOT_JAVA_FRAME: u8 = 0x03;
+const SUB_ROOT_STICKY_CLASS: u8 = 0x05;
+const SUB_ROOT_MONITOR_USED: u8 = 0x07;
+const SUB_ROOT_THREAD_OBJECT: u8 = 0x08;
+const SUB_CLASS_DUMP: u8 = 0x20;
+const SUB_INSTANCE_DUMP: u8 = 0x21;
+const SUB_OBJ_ARRAY_DUMP: u8 = 0x22;
+const SUB_PRIM_ARRAY_DUMP: u8 = 0x23;
+
+/**
+ * HPROF basic types, used for fields and array elements.
+ */
+pub const TYPE_OBJECT: u8 = 2;
+pub const TYPE_BOOLEAN: u8 = 4;
+pub const TYPE_CHAR: u8 = 5;
+pub const TYPE_FLOAT: u8 = 6;
+pub const TYPE_DOUBLE: u8 = 7;
+pub const TYPE_BYTE: u8 = 8;
+pub const TYPE_SHORT: u8 = 9;
+pub const TYPE_INT: u8 = 10;
+pub const TYPE_LONG: u8 = 11;
+
+/**
+ * Line numbers of a STACK FRAME that are not line numbers.
+ */
+pub const LINE_UNKNOWN: i32 = 0;
+pub const LINE_NATIVE: i32 = -3;
+
+/**
+ * The basic type of a field or array element by its JVM signature, `I`, `[B`, `Ljava/lang/String;`...
+ */
+pub fn basic_type(signature: &str) -> u8 {
+    match signature.as_bytes().first() {
+        Some(b'Z') => TYPE_BOOLEAN,
+        Some(b'C') => TYPE_CHAR,
+        Some(b'F') => TYPE_FLOAT,
+        Some(b'D') => TYPE_DOUBLE,
+        Some(b'B') => TYPE_BYTE,
+        Some(b'S') => TYPE_SHORT,
+        Some(b'I') => TYPE_INT,
+        Some(b'J') => TYPE_LONG,
+        _ => TYPE_OBJECT,
+    }
+}
+
+pub fn type_size(basic_type: u8) -> u32 {
+    match basic_type {
+        TYPE_BOOLEAN | TYPE_BYTE => 1,
+        TYPE_CHAR | TYPE_SHORT => 2,
+        TYPE_FLOAT | TYPE_INT => 4,
+        TYPE_DOUBLE | TYPE_LONG => 8,
+        _ => ID_SIZE,
+    }
+}
+
+/**
+ * The value of a field. A missing value is written as 0 or null.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Object(Id),
+    Primitive(PrimitiveValue),
+}
+
+/**
+ * Appends `value` in the layout of `basic_type`. A value of another type is written as 0,
+ * so a record keeps the size its class dump promises.
+ */
+pub fn put_value(out: &mut Vec<u8>, basic_type: u8, value: Option<Value>) {
+    use PrimitiveValue::*;
+
+    match (basic_type, value) {
+        (TYPE_OBJECT, Some(Value::Object(id))) => out.extend_from_slice(&id.to_be_bytes()),
+        (TYPE_BOOLEAN, Some(Value::Primitive(Boolean(value)))) => out.push(value as u8),
+        (TYPE_BYTE, Some(Value::Primitive(Byte(value)))) => out.extend_from_slice(&value.to_be_bytes()),
+        (TYPE_CHAR, Some(Value::Primitive(Char(value)))) => out.extend_from_slice(&value.to_be_bytes()),
+        (TYPE_SHORT, Some(Value::Primitive(Short(value)))) => out.extend_from_slice(&value.to_be_bytes()),
+        (TYPE_INT, Some(Value::Primitive(Int(value)))) => out.extend_from_slice(&value.to_be_bytes()),
+        (TYPE_LONG, Some(Value::Primitive(Long(value)))) => out.extend_from_slice(&value.to_be_bytes()),
+        (TYPE_FLOAT, Some(Value::Primitive(Float(value)))) => out.extend_from_slice(&value.to_be_bytes()),
+        (TYPE_DOUBLE, Some(Value::Primitive(Double(value)))) => out.extend_from_slice(&value.to_be_bytes()),
+        (basic_type, _) => out.resize(out.len() + type_size(basic_type) as usize, 0),
+    }
+}
+
+pub enum Root {
+    Unknown(Id),
+    JniGlobal(Id),
+    JniLocal { object: Id, thread_serial: u32, frame: i32 },
+    JavaFrame { object: Id, thread_serial: u32, frame: i32 },
+    StickyClass(Id),
+    MonitorUsed(Id),
+    ThreadObject { object: Id, thread_serial: u32, stack_serial: u32 },
+}
+
+/**
+ * A CLASS DUMP. The constant pool is always written empty.
+ */
+pub struct ClassDump<'a> {
+    pub class: Id,
+    pub stack_serial: u32,
+    pub superclass: Id,
+    pub loader: Id,
+    pub signers: Id,
+    pub protection_domain: Id,
+    /**
+     * The size of the instance field values in an INSTANCE DUMP of this class, superclasses included.
+     */
+    pub instance_size: u32,
+    /**
+     * Name, basic type and value.
+     */
+    pub statics: &'a [(Id, u8, Option<Value>)],
+    /**
+     * Name and basic type of the fields declared by this class, in the order
+     * INSTANCE DUMP values are written.
+     */
+    pub fields: &'a [(Id, u8)],
+}
+
+/**
+ * Writes the HPROF binary format (JAVA PROFILE 1.0.2) that Eclipse MAT, VisualVM and
+ * `jhsdb` read. Top level records are written as they come, heap dump sub-records are
+ * collected into segments. Nothing checks that identifiers refer to anything.
+ */
+pub struct HprofWriter<W: Write> {
+    out: W,
+    segment: Vec<u8>,
+}
+
+impl<W: Write> HprofWriter<W> {
+    /**
+     * Writes the header, `timestamp` is in milliseconds since the epoch.
+     */
+    pub fn new(mut out: W, timestamp: u64) -> io::Result<HprofWriter<W>> {
+        out.write_all(HEADER)?;
+        out.write_all(&ID_SIZE.to_be_bytes())?;
+        out.write_all(&timestamp.to_be_bytes())?;
+        Ok(HprofWriter {
+            out,
+            segment: Vec::with_capacity(SEGMENT_SIZE),
+        })
+    }
+
+    pub fn string(&mut self, id: Id, value: &str) -> io::Result<()> {
+        let mut body = Vec::with_capacity(8 + value.len());
+        body.extend_from_slice(&id.to_be_bytes());
+        body.extend_from_slice(value.as_bytes());
+        self.record(TAG_STRING, &body)
+    }
+
+    pub fn load_class(&mut self, class_serial: u32, class: Id, stack_serial: u32, name: Id) -> io::Result<()> {
+        let mut body = Vec::with_capacity(24);
+        body.extend_from_slice(&class_serial.to_be_bytes());
+        body.extend_from_slice(&class.to_be_bytes());
+        body.extend_from_slice(&stack_serial.to_be_bytes());
+        body.extend_from_slice(&name.to_be_bytes());
+        self.record(TAG_LOAD_CLASS, &body)
+    }
+
+    /**
+     * `source_file` is 0 when unknown. `line` is a line number, `LINE_UNKNOWN` or `LINE_NATIVE`.
+     */
+    pub fn stack_frame(
+        &mut self,
+        frame: Id,
+        method: Id,
+        signature: Id,
+        source_file: Id,
+        class_serial: u32,
+        line: i32,
+    ) -> io::Result<()> {
+        let mut body = Vec::with_capacity(40);
+        for id in [frame, method, signature, source_file] {
+            body.extend_from_slice(&id.to_be_bytes());
+        }
+        body.extend_from_slice(&class_serial.to_be_bytes());
+        body.extend_from_slice(&line.to_be_bytes());
+        self.record(TAG_STACK_FRAME, &body)
+    }
+
+    pub fn stack_trace(&mut self, stack_serial: u32, thread_serial: u32, frames: &[Id]) -> io::Result<()> {
+        let mut body = Vec::with_capacity(12 + 8 * frames.len());
+        body.extend_from_slice(&stack_serial.to_be_bytes());
+        body.extend_from_slice(&thread_serial.to_be_bytes());
+        body.extend_from_slice(&(frames.len() as u32).to_be_bytes());
+        for frame in frames {
+            body.extend_from_slice(&frame.to_be_bytes());
+        }
+        self.record(TAG_STACK_TRACE, &body)
+    }
+
+    pub fn root(&mut self, root: &Root) -> io::Result<()> {
+        let mut body = Vec::with_capacity(17);
+        match *root {
+            Root::Unknown(object) => {
+                body.push(SUB_ROOT_UNKNOWN);
+                body.extend_from_slice(&object.to_be_bytes());
+            }
+            Root::JniGlobal(object) => {
+                body.push(SUB_ROOT_JNI_GLOBAL);
+                body.extend_from_slice(&object.to_be_bytes());
+                // the JNI global reference itself, which JVMTI does not tell
+                body.extend_from_slice(&0u64.to_be_bytes());
+            }
+            Root::JniLocal {
+                object,
+                thread_serial,
+                frame,
+            } => {
+                body.push(SUB_ROOT_JNI_LOCAL);
+                body.extend_from_slice(&object.to_be_bytes());
+                body.extend_from_slice(&thread_serial.to_be_bytes());
+                body.extend_from_slice(&frame.to_be_bytes());
+            }
+            Root::JavaFrame {
+                object,
+                thread_serial,
+                frame,
+            } => {
+                body.push(SUB_ROOT_JAVA_FRAME);
+                body.extend_from_slice(&object.to_be_bytes());
+                body.extend_from_slice(&thread_serial.to_be_bytes());
+                body.extend_from_slice(&frame.to_be_bytes());
+            }
+            Root::StickyClass(object) => {
+                body.push(SUB_ROOT_STICKY_CLASS);
+                body.extend_from_slice(&object.to_be_bytes());
+            }
+            Root::MonitorUsed(object) => {
+                body.push(SUB_ROOT_MONITOR_USED);
+                body.extend_from_slice(&object.to_be_bytes());
+            }
+            Root::ThreadObject {
+                object,
+                thread_serial,
+                stack_serial,
+            } => {
+                body.push(SUB_ROOT_THREAD_OBJECT);
+                body.extend_from_slice(&object.to_be_bytes());
+                body.extend_from_slice(&thread_serial.to_be_bytes());
+                body.extend_from_slice(&stack_serial.to_be_bytes());
+            }
+        }
+        self.sub_record(&body)
+    }
+
+    pub fn class_dump(&mut self, class: &ClassDump) -> io::Result<()> {
+        let mut body = Vec::with_capacity(64 + 17 * class.statics.len() + 9 * class.fields.len());
+        body.push(SUB_CLASS_DUMP);
+        body.extend_from_slice(&class.class.to_be_bytes());
+        body.extend_from_slice(&class.stack_serial.to_be_bytes());
+        for id in [class.superclass, class.loader, class.signers, class.protection_domain, 0, 0] {
+            body.extend_from_slice(&id.to_be_bytes());
+        }
+        body.extend_from_slice(&class.instance_size.to_be_bytes());
+        body.extend_from_slice(&0u16.to_be_bytes());
+
+        body.extend_from_slice(&(class.statics.len() as u16).to_be_bytes());
+        for (name, basic_type, value) in class.statics {
+            body.extend_from_slice(&name.to_be_bytes());
+            body.push(*basic_type);
+            put_value(&mut body, *basic_type, *value);
+        }
+        body.extend_from_slice(&(class.fields.len() as u16).to_be_bytes());
+        for (name, basic_type) in class.fields {
+            body.extend_from_slice(&name.to_be_bytes());
+            body.push(*basic_type);
+        }
+        self.sub_record(&body)
+    }
+
+    /**
+     * `values` are the instance field values laid out as `put_value` writes them: the fields
+     * of the object's class first, then those of each superclass.
+     */
+    pub fn instance_dump(&mut self, object: Id, stack_serial: u32, class: Id, values: &[u8]) -> io::Result<()> {
+        let mut body = Vec::with_capacity(25 + values.len());
+        body.push(SUB_INSTANCE_DUMP);
+        body.extend_from_slice(&object.to_be_bytes());
+        body.extend_from_slice(&stack_serial.to_be_bytes());
+        body.extend_from_slice(&class.to_be_bytes());
+        body.extend_from_slice(&(values.len() as u32).to_be_bytes());
+        body.extend_from_slice(values);
+        self.sub_record(&body)
+    }
+
+    pub fn object_array(&mut self, object: Id, stack_serial: u32, class: Id, elements: &[Id]) -> io::Result<()> {
+        let mut body = Vec::with_capacity(25 + 8 * elements.len());
+        body.push(SUB_OBJ_ARRAY_DUMP);
+        body.extend_from_slice(&object.to_be_bytes());
+        body.extend_from_slice(&stack_serial.to_be_bytes());
+        body.extend_from_slice(&(elements.len() as u32).to_be_bytes());
+        body.extend_from_slice(&class.to_be_bytes());
+        for element in elements {
+            body.extend_from_slice(&element.to_be_bytes());
+        }
+        self.sub_record(&body)
+    }
+
+    pub fn primitive_array(&mut self, object: Id, stack_serial: u32, elements: &PrimitiveArray) -> io::Result<()> {
+        let (basic_type, size) = match elements {
+            PrimitiveArray::Boolean(_) => (TYPE_BOOLEAN, 1),
+            PrimitiveArray::Byte(_) => (TYPE_BYTE, 1),
+            PrimitiveArray::Char(_) => (TYPE_CHAR, 2),
+            PrimitiveArray::Short(_) => (TYPE_SHORT, 2),
+            PrimitiveArray::Int(_) => (TYPE_INT, 4),
+            PrimitiveArray::Long(_) => (TYPE_LONG, 8),
+            PrimitiveArray::Float(_) => (TYPE_FLOAT, 4),
+            PrimitiveArray::Double(_) => (TYPE_DOUBLE, 8),
+        };
+        let mut body = Vec::with_capacity(18 + size * elements.len());
+        body.push(SUB_PRIM_ARRAY_DUMP);
+        body.extend_from_slice(&object.to_be_bytes());
+        body.extend_from_slice(&stack_serial.to_be_bytes());
+        body.extend_from_slice(&(elements.len() as u32).to_be_bytes());
+        body.push(basic_type);
+        match elements {
+            PrimitiveArray::Boolean(elements) => body.extend_from_slice(elements),
+            PrimitiveArray::Byte(elements) => body.extend(elements.iter().map(|element| *element as u8)),
+            PrimitiveArray::Char(elements) => body.extend(elements.iter().flat_map(|element| element.to_be_bytes())),
+            PrimitiveArray::Short(elements) => body.extend(elements.iter().flat_map(|element| element.to_be_bytes())),
+            PrimitiveArray::Int(elements) => body.extend(elements.iter().flat_map(|element| element.to_be_bytes())),
+            PrimitiveArray::Long(elements) => body.extend(elements.iter().flat_map(|element| element.to_be_bytes())),
+            PrimitiveArray::Float(elements) => body.extend(elements.iter().flat_map(|element| element.to_be_bytes())),
+            PrimitiveArray::Double(elements) => {
+                body.extend(elements.iter().flat_map(|element| element.to_be_bytes()))
+            }
+        }
+        self.sub_record(&body)
+    }
+
+    /**
+     * Closes the heap dump and flushes, the writer is handed back.
+     */
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_segment()?;
+        self.record(TAG_HEAP_DUMP_END, &[])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn record(&mut self, tag: u8, body: &[u8]) -> io::Result<()> {
+        // a top level record ends the current segment
+        self.flush_segment()?;
+        self.write_record(tag, body)
+    }
+
+    fn write_record(&mut self, tag: u8, body: &[u8]) -> io::Result<()> {
+        self.out.write_all(&[tag])?;
+        // microseconds since the header's timestamp, which no reader looks at
+        self.out.write_all(&0u32.to_be_bytes())?;
+        self.out.write_all(&(body.len() as u32).to_be_bytes())?;
+        self.out.write_all(body)
+    }
+
+    fn sub_record(&mut self, body: &[u8]) -> io::Result<()> {
+        if !self.segment.is_empty() && self.segment.len() + body.len() > SEGMENT_SIZE {
+            self.flush_segment()?;
+        }
+        self.segment.extend_from_slice(body);
+        Ok(())
+    }
+
+    fn flush_segment(&mut self) -> io::Result<()> {
+        if self.segment.is_empty() {
+            return Ok(());
+        }
+        let segment = std::mem::take(&mut self.segment);
+        self.write_record(TAG_HEAP_DUMP_SEGMENT, &segment)?;
+        self.segment = segment;
+        self.segment.clear();
+        Ok(())
+    }
+}
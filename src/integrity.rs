@@ -0,0 +1,439 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter, Result};
+use std::fs;
+use std::io::{Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+
+use once_cell::sync::OnceCell;
+
+use crate::config::APP_NAME;
+use crate::crypto;
+use crate::errors::GeneralError;
+
+const SIGNATURE_PREFIX: &str = "# signature ";
+
+/**
+ * The runtime files worth hashing: the VM, the module image, the native libraries
+ * next to them and the security policy. Paths are relative to java.home with '/' separators.
+ */
+const RUNTIME_FILES: &[&str] = &["lib/modules", "conf/security/java.security"];
+const RUNTIME_LIBRARY_DIRS: &[&str] = &["lib", "lib/server", "lib/client", "bin", "bin/server", "bin/client"];
+const LIBRARY_EXTENSIONS: &[&str] = &["so", "dylib", "dll"];
+
+/**
+ * The application jar manifest, loaded once before the JVM starts. Jars are listed by file name,
+ * nested jars as `outer.jar!/path/inner.jar`.
+ */
+static JAR_MANIFEST: OnceCell<Manifest> = OnceCell::new();
+
+/**
+ * File name -> SHA-256 hex, written one `<hash>  <name>` line per file like sha256sum,
+ * followed by an HMAC signature over everything above it.
+ */
+#[derive(Default)]
+pub struct Manifest {
+    entries: BTreeMap<String, String>,
+}
+
+pub enum Difference {
+    Modified(String),
+    Missing(String),
+    Unexpected(String),
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Difference::Modified(name) => write!(f, "{} has been modified", name),
+            Difference::Missing(name) => write!(f, "{} is missing", name),
+            Difference::Unexpected(name) => write!(f, "{} is not in the manifest", name),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn insert(&mut self, name: String, hash: String) {
+        self.entries.insert(name, hash);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.get(name).map(String::as_str)
+    }
+
+    pub fn to_signed_string(&self) -> String {
+        let mut body = format!("# {} integrity manifest\n", APP_NAME);
+        for (name, hash) in &self.entries {
+            body.push_str(&format!("{}  {}\n", hash, name));
+        }
+        let signature = crypto::to_hex(&crypto::sign(body.as_bytes()));
+        body.push_str(&format!("{}{}\n", SIGNATURE_PREFIX, signature));
+        body
+    }
+
+    /**
+     * Reads a manifest and checks its signature, an unsigned or re-signed manifest is rejected.
+     */
+    pub fn read(path: &Path) -> std::result::Result<Manifest, GeneralError> {
+        let error = |message: String| GeneralError::IntegrityError(format!("{}: {}", path.display(), message));
+        let data = fs::read_to_string(path).map_err(|e| error(format!("cannot read manifest ({})", e)))?;
+
+        let start = data.rfind(SIGNATURE_PREFIX).ok_or_else(|| error(String::from("manifest is not signed")))?;
+        let (body, signature) = data.split_at(start);
+        let signature = crypto::from_hex(signature[SIGNATURE_PREFIX.len()..].trim())
+            .ok_or_else(|| error(String::from("malformed signature")))?;
+        if !crypto::verify_signature(body.as_bytes(), &signature) {
+            return Err(error(String::from("signature does not match, the manifest has been altered")));
+        }
+
+        let mut manifest = Manifest::default();
+        for (number, line) in body.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once("  ") {
+                Some((hash, name)) => manifest.insert(name.to_string(), hash.to_string()),
+                None => return Err(error(format!("line {} is malformed", number + 1))),
+            }
+        }
+        Ok(manifest)
+    }
+
+    /**
+     * What changed going from this (expected) manifest to `actual`.
+     */
+    pub fn diff(&self, actual: &Manifest) -> Vec<Difference> {
+        let mut differences = Vec::new();
+        for (name, hash) in &self.entries {
+            match actual.get(name) {
+                None => differences.push(Difference::Missing(name.clone())),
+                Some(actual) if actual != hash => differences.push(Difference::Modified(name.clone())),
+                _ => (),
+            }
+        }
+        for name in actual.entries.keys() {
+            if !self.entries.contains_key(name) {
+                differences.push(Difference::Unexpected(name.clone()));
+            }
+        }
+        differences
+    }
+}
+
+pub fn hash_file(path: &Path) -> std::result::Result<String, GeneralError> {
+    fs::File::open(path)
+        .and_then(crypto::sha256)
+        .map(|hash| crypto::to_hex(&hash))
+        .map_err(|e| GeneralError::IntegrityError(format!("cannot hash {} ({})", path.display(), e)))
+}
+
+fn runtime_files(java_home: &Path) -> Vec<String> {
+    let mut files: Vec<String> = RUNTIME_FILES
+        .iter()
+        .filter(|name| java_home.join(name).is_file())
+        .map(|name| name.to_string())
+        .collect();
+
+    for dir in RUNTIME_LIBRARY_DIRS {
+        let entries = match fs::read_dir(java_home.join(dir)) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_library = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| LIBRARY_EXTENSIONS.contains(&e));
+            if is_library && path.is_file() {
+                files.push(format!("{}/{}", dir, entry.file_name().to_string_lossy()));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+fn hash_runtime(java_home: &Path) -> std::result::Result<Manifest, GeneralError> {
+    let mut manifest = Manifest::default();
+    for name in runtime_files(java_home) {
+        let hash = hash_file(&java_home.join(&name))?;
+        manifest.insert(name, hash);
+    }
+    Ok(manifest)
+}
+
+/**
+ * The signed manifest for the runtime at `java_home`, run at packaging time.
+ */
+pub fn generate_runtime_manifest(java_home: &Path) -> std::result::Result<String, GeneralError> {
+    let manifest = hash_runtime(java_home)?;
+    if manifest.entries.is_empty() {
+        return Err(GeneralError::IntegrityError(format!(
+            "{} does not look like a Java runtime",
+            java_home.display()
+        )));
+    }
+    Ok(manifest.to_signed_string())
+}
+
+/**
+ * Every runtime file that differs from the manifest, empty if the runtime is intact.
+ */
+pub fn verify_runtime(java_home: &Path, manifest: &Path) -> std::result::Result<Vec<Difference>, GeneralError> {
+    let expected = Manifest::read(manifest)?;
+    let actual = hash_runtime(java_home)?;
+    Ok(expected.diff(&actual))
+}
+
+fn hash_bytes(data: &[u8]) -> String {
+    crypto::to_hex(&crypto::sha256(data).expect("reading from memory cannot fail"))
+}
+
+fn jar_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+fn hash_nested_jars<R: Read + Seek>(
+    manifest: &mut Manifest,
+    name: &str,
+    reader: R,
+) -> std::result::Result<(), GeneralError> {
+    let error = |message: String| GeneralError::IntegrityError(format!("{}: {}", name, message));
+    let mut archive = zip::ZipArchive::new(reader).map_err(|e| error(e.to_string()))?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(|e| error(e.to_string()))?;
+        if !file.is_file() || !file.name().ends_with(".jar") {
+            continue;
+        }
+        let nested_name = format!("{}!/{}", name, file.name());
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|e| error(e.to_string()))?;
+        manifest.insert(nested_name.clone(), hash_bytes(&data));
+        hash_nested_jars(manifest, &nested_name, Cursor::new(data))?;
+    }
+    Ok(())
+}
+
+/**
+ * The relative jar paths in the Class-Path attribute of a MANIFEST.MF, continuation lines joined.
+ * Directories and absolute URLs are left out, only jars next to the application can be hashed.
+ */
+fn class_path(manifest: &str) -> Vec<String> {
+    let mut attributes = Vec::<String>::new();
+    for line in manifest.lines() {
+        match (line.strip_prefix(' '), attributes.last_mut()) {
+            (Some(continuation), Some(attribute)) => attribute.push_str(continuation),
+            _ => attributes.push(line.to_string()),
+        }
+    }
+    attributes
+        .iter()
+        .filter_map(|attribute| attribute.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("Class-Path"))
+        .flat_map(|(_, value)| value.split_whitespace())
+        .filter(|entry| entry.ends_with(".jar") && !entry.contains(':'))
+        .map(|entry| entry.replace("%20", " "))
+        .collect()
+}
+
+/**
+ * The Class-Path jars of `jar` that exist, resolved against the jar's directory like the JVM does.
+ */
+fn class_path_jars(jar: &Path, data: &[u8]) -> Vec<PathBuf> {
+    let mut text = String::new();
+    let read = zip::ZipArchive::new(Cursor::new(data))
+        .ok()
+        .and_then(|mut archive| archive.by_name("META-INF/MANIFEST.MF").ok()?.read_to_string(&mut text).ok());
+    if read.is_none() {
+        return Vec::new();
+    }
+    let dir = jar.parent().unwrap_or(Path::new(""));
+    class_path(&text)
+        .iter()
+        .map(|entry| dir.join(entry))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/**
+ * The signed manifest for an application jar, the jars nested inside it and the jars its
+ * Class-Path pulls in, transitively. Run at packaging time.
+ * Jars are listed by file name, two different jars with the same name are refused.
+ */
+pub fn generate_jar_manifest(jar: &Path) -> std::result::Result<String, GeneralError> {
+    let mut manifest = Manifest::default();
+    let mut seen = HashSet::new();
+    let mut pending = vec![jar.to_path_buf()];
+    while let Some(jar) = pending.pop() {
+        if !seen.insert(fs::canonicalize(&jar).unwrap_or(jar.clone())) {
+            continue;
+        }
+        let data = fs::read(&jar)
+            .map_err(|e| GeneralError::IntegrityError(format!("cannot read {} ({})", jar.display(), e)))?;
+        let name = jar_name(&jar);
+        if manifest.get(&name).is_some() {
+            return Err(GeneralError::IntegrityError(format!(
+                "{}: another jar named {} is already on the class path",
+                jar.display(),
+                name
+            )));
+        }
+        manifest.insert(name.clone(), hash_bytes(&data));
+        pending.extend(class_path_jars(&jar, &data));
+        hash_nested_jars(&mut manifest, &name, Cursor::new(data))?;
+    }
+    Ok(manifest.to_signed_string())
+}
+
+/**
+ * Loads the jar manifest every later `verify_jar` call checks against.
+ * Without it jars are not verified at all.
+ */
+pub fn load_jar_manifest(path: &Path) -> std::result::Result<(), GeneralError> {
+    let manifest = Manifest::read(path)?;
+    let _ = JAR_MANIFEST.set(manifest);
+    Ok(())
+}
+
+/**
+ * Whether a jar manifest is loaded, i.e. whether `verify_jar` checks anything.
+ */
+pub fn verifies_jars() -> bool {
+    JAR_MANIFEST.get().is_some()
+}
+
+/**
+ * Checks a jar's content against the loaded manifest. `name` is the manifest name,
+ * the file name for a jar on disk or `outer.jar!/inner.jar` for a nested one.
+ */
+pub fn verify_jar(name: &str, data: &[u8]) -> std::result::Result<(), GeneralError> {
+    let manifest = match JAR_MANIFEST.get() {
+        Some(manifest) => manifest,
+        None => return Ok(()),
+    };
+    match manifest.get(name) {
+        Some(hash) if hash == hash_bytes(data) => Ok(()),
+        Some(_) => Err(GeneralError::IntegrityError(format!(
+            "{} has been modified, refusing to run it",
+            name
+        ))),
+        None => Err(GeneralError::IntegrityError(format!(
+            "{} is not in the jar manifest, refusing to run it",
+            name
+        ))),
+    }
+}
+
+pub fn verify_jar_file(path: &Path) -> std::result::Result<(), GeneralError> {
+    let data = fs::read(path)
+        .map_err(|e| GeneralError::IntegrityError(format!("cannot read {} ({})", path.display(), e)))?;
+    verify_jar(&jar_name(path), &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn manifest(entries: &[(&str, &str)]) -> Manifest {
+        let mut manifest = Manifest::default();
+        for (name, hash) in entries {
+            manifest.insert(name.to_string(), hash.to_string());
+        }
+        manifest
+    }
+
+    /**
+     * Writes `content` to a file of its own and reads it back as a manifest.
+     */
+    fn read(name: &str, content: &str) -> std::result::Result<Manifest, GeneralError> {
+        let path: PathBuf = std::env::temp_dir().join(format!("{}-{}-{}.sha256", APP_NAME, std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        let manifest = Manifest::read(&path);
+        let _ = fs::remove_file(&path);
+        manifest
+    }
+
+    fn assert_rejected(name: &str, content: &str, reason: &str) {
+        match read(name, content) {
+            Ok(_) => panic!("{} was accepted", name),
+            Err(e) => assert!(e.to_string().contains(reason), "{}: {}", name, e),
+        }
+    }
+
+    fn signed() -> String {
+        manifest(&[("app.jar", "aa11"), ("app.jar!/lib/dep.jar", "bb22")]).to_signed_string()
+    }
+
+    #[test]
+    fn reads_signed_manifest() {
+        let manifest = read("signed", &signed()).unwrap();
+        assert_eq!(manifest.get("app.jar"), Some("aa11"));
+        assert_eq!(manifest.get("app.jar!/lib/dep.jar"), Some("bb22"));
+        assert_eq!(manifest.get("other.jar"), None);
+    }
+
+    #[test]
+    fn rejects_edited_hash() {
+        let edited = signed().replace("aa11  app.jar", "cc33  app.jar");
+        assert_rejected("edited", &edited, "signature does not match");
+    }
+
+    #[test]
+    fn rejects_added_line() {
+        let added = signed().replacen('\n', "\ndd44  evil.jar\n", 1);
+        assert_rejected("added", &added, "signature does not match");
+    }
+
+    #[test]
+    fn rejects_missing_signature() {
+        let unsigned: String = signed()
+            .lines()
+            .filter(|line| !line.starts_with(SIGNATURE_PREFIX))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert_rejected("unsigned", &unsigned, "not signed");
+    }
+
+    #[test]
+    fn rejects_malformed_signature() {
+        let start = signed().rfind(SIGNATURE_PREFIX).unwrap();
+        let malformed = format!("{}{}not-hex\n", &signed()[..start], SIGNATURE_PREFIX);
+        assert_rejected("malformed", &malformed, "malformed signature");
+    }
+
+    #[test]
+    fn reads_class_path() {
+        let manifest = concat!(
+            "Manifest-Version: 1.0\r\n",
+            "Main-Class: app.Main\r\n",
+            "Class-Path: lib/a.jar lib/b\r\n",
+            " .jar lib/classes/ \r\n",
+            " http://example.com/c.jar lib/my%20d.jar\r\n",
+            "Created-By: 17\r\n"
+        );
+        assert_eq!(class_path(manifest), ["lib/a.jar", "lib/b.jar", "lib/my d.jar"]);
+        assert!(class_path("Manifest-Version: 1.0\n").is_empty());
+    }
+
+    #[test]
+    fn diff_reports_every_kind_of_change() {
+        let expected = manifest(&[("same.jar", "01"), ("changed.jar", "02"), ("gone.jar", "03")]);
+        let actual = manifest(&[("same.jar", "01"), ("changed.jar", "ff"), ("new.jar", "04")]);
+
+        let mut differences: Vec<String> = expected.diff(&actual).iter().map(ToString::to_string).collect();
+        differences.sort();
+        assert_eq!(
+            differences,
+            [
+                "changed.jar has been modified",
+                "gone.jar is missing",
+                "new.jar is not in the manifest"
+            ]
+        );
+        assert!(expected.diff(&expected).is_empty());
+    }
+}
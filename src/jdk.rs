@@ -0,0 +1,497 @@
+use std::cmp::{Ordering, Reverse};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::{env, fmt};
+
+use log::debug;
+
+use crate::config::Config;
+use crate::errors::GeneralError;
+use crate::utility;
+
+const BUNDLED_RUNTIME: &str = "jre";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct JavaVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl JavaVersion {
+    /**
+     * Accepts both the modern `17.0.2` scheme and the legacy `1.8.0_392` one.
+     */
+    pub fn parse(version: &str) -> Option<JavaVersion> {
+        let version = version.trim().trim_matches('"');
+        let version = version.split(['+', '-']).next()?;
+        let mut numbers = version
+            .split(['.', '_'])
+            .map(|n| n.parse::<u32>().ok());
+
+        let mut major = numbers.next()??;
+        let mut minor = numbers.next().flatten().unwrap_or(0);
+        let mut patch = numbers.next().flatten().unwrap_or(0);
+        if major == 1 {
+            major = minor;
+            minor = 0;
+            patch = numbers.next().flatten().unwrap_or(patch);
+        }
+        Some(JavaVersion {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+impl Display for JavaVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+/**
+ * A comma separated list of constraints, e.g. `>=17,<22`.
+ * A bare version such as `17` only matches that major release.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct VersionRange {
+    bounds: Vec<(Op, JavaVersion, bool)>,
+}
+
+impl VersionRange {
+    pub fn parse(range: &str) -> Result<VersionRange, GeneralError> {
+        let mut bounds = Vec::new();
+        for item in range.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (op, version) = if let Some(v) = item.strip_prefix(">=") {
+                (Op::Ge, v)
+            } else if let Some(v) = item.strip_prefix("<=") {
+                (Op::Le, v)
+            } else if let Some(v) = item.strip_prefix('>') {
+                (Op::Gt, v)
+            } else if let Some(v) = item.strip_prefix('<') {
+                (Op::Lt, v)
+            } else if let Some(v) = item.strip_prefix('=') {
+                (Op::Eq, v)
+            } else {
+                (Op::Eq, item)
+            };
+
+            let version = version.trim();
+            let major_only = !version.contains('.');
+            match JavaVersion::parse(version) {
+                Some(v) => bounds.push((op, v, major_only)),
+                None => {
+                    return Err(GeneralError::JdkError(format!(
+                        "Invalid java version constraint '{}'",
+                        item
+                    )))
+                }
+            }
+        }
+        Ok(VersionRange { bounds })
+    }
+
+    pub fn is_any(&self) -> bool {
+        self.bounds.is_empty()
+    }
+
+    pub fn matches(&self, version: &JavaVersion) -> bool {
+        self.bounds.iter().all(|(op, bound, major_only)| {
+            let ordering = if *major_only {
+                version.major.cmp(&bound.major)
+            } else {
+                version.cmp(bound)
+            };
+            match op {
+                Op::Eq => ordering == Ordering::Equal,
+                Op::Gt => ordering == Ordering::Greater,
+                Op::Ge => ordering != Ordering::Less,
+                Op::Lt => ordering == Ordering::Less,
+                Op::Le => ordering != Ordering::Greater,
+            }
+        })
+    }
+}
+
+pub struct Jdk {
+    pub home: PathBuf,
+    pub version: JavaVersion,
+    pub vm: String,
+    pub libjvm: PathBuf,
+    pub bundled: bool,
+}
+
+impl Jdk {
+    /**
+     * Sanity checks a runtime before its libjvm gets loaded into our process.
+     *
+     * A jlink'd runtime must be self contained: the release file lists java.base,
+     * lib/modules is present, and libjvm is a real shared object that does not
+     * point outside of the runtime directory.
+     */
+    pub fn verify(&self) -> Result<(), GeneralError> {
+        let fail = |reason: String| {
+            GeneralError::JdkError(format!(
+                "Runtime {} failed verification: {}",
+                self.home.display(),
+                reason
+            ))
+        };
+
+        let release = fs::read_to_string(self.home.join("release"))
+            .map_err(|e| fail(format!("cannot read release file ({})", e)))?;
+        let has_java_base = release
+            .lines()
+            .find_map(|line| line.strip_prefix("MODULES="))
+            .map(|modules| modules.trim_matches('"').split(' ').any(|m| m == "java.base"))
+            .unwrap_or(false);
+        if !has_java_base {
+            return Err(fail(String::from("release file does not list java.base")));
+        }
+
+        let modules = self.home.join("lib").join("modules");
+        match fs::metadata(&modules) {
+            Ok(meta) if meta.is_file() && meta.len() > 0 => (),
+            _ => return Err(fail(format!("{} is missing or empty", modules.display()))),
+        }
+
+        let libjvm = fs::canonicalize(&self.libjvm)
+            .map_err(|e| fail(format!("cannot resolve {} ({})", self.libjvm.display(), e)))?;
+        let home = fs::canonicalize(&self.home).unwrap_or(self.home.clone());
+        if !libjvm.starts_with(&home) {
+            return Err(fail(format!("{} points outside the runtime", self.libjvm.display())));
+        }
+
+        let mut magic = [0u8; 4];
+        let readable = fs::File::open(&libjvm).and_then(|mut f| f.read_exact(&mut magic));
+        if readable.is_err() || !is_shared_library(&magic) {
+            return Err(fail(format!("{} is not a shared library", libjvm.display())));
+        }
+        Ok(())
+    }
+}
+
+fn is_shared_library(magic: &[u8; 4]) -> bool {
+    matches!(
+        magic,
+        [0x7f, b'E', b'L', b'F'] | [b'M', b'Z', _, _] | [0xcf, 0xfa, 0xed, 0xfe] | [0xca, 0xfe, 0xba, 0xbe]
+    )
+}
+
+struct Candidate {
+    home: PathBuf,
+    origin: &'static str,
+}
+
+/**
+ * Picks the JDK the launcher runs on.
+ *
+ * The configured java_home wins when it is valid, followed by the configured
+ * bundled runtime. Otherwise JAVA_HOME, PATH, /usr/lib/jvm, SDKMAN and a jre
+ * directory next to the launcher are searched in that order.
+ */
+pub struct JdkResolver {
+    java_home: Option<PathBuf>,
+    runtime: Option<PathBuf>,
+    range: VersionRange,
+    vm: String,
+}
+
+impl JdkResolver {
+    pub fn new(config: &Config) -> Result<JdkResolver, GeneralError> {
+        let vm = match config.java_vm.trim() {
+            "" => "server".to_string(),
+            v @ ("server" | "client") => v.to_string(),
+            v => {
+                return Err(GeneralError::JdkError(format!(
+                    "Unknown java_vm '{}', expected server or client",
+                    v
+                )))
+            }
+        };
+
+        // The bundled runtime ships next to the launcher, so it is never relative to the CWD.
+        let runtime = config
+            .runtime
+            .as_ref()
+            .map(|runtime| utility::app_dir().join(runtime));
+
+        Ok(JdkResolver {
+            java_home: config.java_home.clone(),
+            runtime,
+            range: VersionRange::parse(&config.java_version)?,
+            vm,
+        })
+    }
+
+    pub fn resolve(&self) -> Result<Jdk, GeneralError> {
+        let mut selected: Option<Jdk> = None;
+        let mut fallback: Option<Jdk> = None;
+
+        for candidate in self.candidates() {
+            let jdk = match self.inspect(&candidate) {
+                Ok(jdk) => jdk,
+                Err(reason) => {
+                    debug!(
+                        "JDK candidate {} ({}): rejected, {}",
+                        candidate.home.display(),
+                        candidate.origin,
+                        reason
+                    );
+                    continue;
+                }
+            };
+
+            debug!(
+                "JDK candidate {} ({}): version {}, {} vm",
+                jdk.home.display(),
+                candidate.origin,
+                jdk.version,
+                jdk.vm
+            );
+            if jdk.vm == self.vm {
+                selected.get_or_insert(jdk);
+            } else {
+                fallback.get_or_insert(jdk);
+            }
+        }
+
+        selected.or(fallback).ok_or_else(|| {
+            GeneralError::JdkError(format!(
+                "No JDK matching version '{}' was found",
+                self.range_display()
+            ))
+        })
+    }
+
+    fn range_display(&self) -> String {
+        if self.range.is_any() {
+            String::from("any")
+        } else {
+            self.range
+                .bounds
+                .iter()
+                .map(|(op, v, major_only)| {
+                    let op = match op {
+                        Op::Eq => "=",
+                        Op::Gt => ">",
+                        Op::Ge => ">=",
+                        Op::Lt => "<",
+                        Op::Le => "<=",
+                    };
+                    if *major_only {
+                        format!("{}{}", op, v.major)
+                    } else {
+                        format!("{}{}", op, v)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+    }
+
+    fn inspect(&self, candidate: &Candidate) -> Result<Jdk, String> {
+        let home = candidate.home.as_path();
+        let version = read_release_version(home)?;
+        if !self.range.matches(&version) {
+            return Err(format!("version {} out of range", version));
+        }
+
+        let (vm, libjvm) = locate_libjvm(home, &self.vm).ok_or("no libjvm found")?;
+        Ok(Jdk {
+            home: home.to_path_buf(),
+            version,
+            vm,
+            libjvm,
+            bundled: candidate.origin == "bundled",
+        })
+    }
+
+    fn candidates(&self) -> Vec<Candidate> {
+        let mut result: Vec<Candidate> = Vec::new();
+        let mut push = |home: PathBuf, origin: &'static str| {
+            let home = fs::canonicalize(&home).unwrap_or(home);
+            if home.is_dir() && !result.iter().any(|c| c.home == home) {
+                result.push(Candidate { home, origin });
+            }
+        };
+
+        if let Some(java_home) = &self.java_home {
+            push(java_home.clone(), "config");
+        }
+
+        if let Some(runtime) = &self.runtime {
+            push(runtime.clone(), "bundled");
+        }
+
+        if let Some(home) = env::var_os("JAVA_HOME") {
+            push(PathBuf::from(home), "JAVA_HOME");
+        }
+
+        if let Some(paths) = env::var_os("PATH") {
+            for dir in env::split_paths(&paths) {
+                let java = dir.join("java");
+                if let Ok(java) = fs::canonicalize(java) {
+                    if let Some(home) = java.parent().and_then(Path::parent) {
+                        push(home.to_path_buf(), "PATH");
+                    }
+                }
+            }
+        }
+
+        for home in list_dirs(Path::new("/usr/lib/jvm")) {
+            push(home, "/usr/lib/jvm");
+        }
+
+        let sdkman = env::var_os("SDKMAN_DIR")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".sdkman")));
+        if let Some(sdkman) = sdkman {
+            for home in list_dirs(&sdkman.join("candidates").join("java")) {
+                push(home, "SDKMAN");
+            }
+        }
+
+        push(utility::app_dir().join(BUNDLED_RUNTIME), "bundled");
+        result
+    }
+}
+
+fn list_dirs(path: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = match fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    // Newest release first, directories without a readable version last.
+    dirs.sort_by_key(|dir| Reverse(read_release_version(dir).ok()));
+    dirs
+}
+
+fn read_release_version(home: &Path) -> Result<JavaVersion, String> {
+    let release = home.join("release");
+    let data = fs::read_to_string(&release).map_err(|_| String::from("no release file"))?;
+    data.lines()
+        .find_map(|line| line.strip_prefix("JAVA_VERSION="))
+        .and_then(JavaVersion::parse)
+        .ok_or_else(|| String::from("no JAVA_VERSION in release file"))
+}
+
+/**
+ * Looks for libjvm in both the JDK 9+ layout (lib/server) and the JDK 8 one (jre/lib/amd64/server).
+ * The preferred vm is tried first, the other one is used if it is the only one shipped.
+ */
+fn locate_libjvm(home: &Path, preferred: &str) -> Option<(String, PathBuf)> {
+    let file_name = java_locator::get_jvm_dyn_lib_file_name();
+    let other = if preferred == "server" { "client" } else { "server" };
+    let arch = match env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "i386",
+        arch => arch,
+    };
+
+    for vm in [preferred, other] {
+        let dirs = [
+            home.join("lib").join(vm),
+            home.join("bin").join(vm),
+            home.join("jre").join("lib").join(vm),
+            home.join("lib").join(arch).join(vm),
+            home.join("jre").join("lib").join(arch).join(vm),
+        ];
+        if let Some(path) = dirs.iter().map(|d| d.join(file_name)).find(|p| p.is_file()) {
+            return Some((vm.to_string(), path));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(major: u32, minor: u32, patch: u32) -> JavaVersion {
+        JavaVersion { major, minor, patch }
+    }
+
+    #[test]
+    fn parses_versions() {
+        let cases = [
+            ("17.0.2", version(17, 0, 2)),
+            ("17.0.2+8", version(17, 0, 2)),
+            ("\"21.0.1\"", version(21, 0, 1)),
+            ("21-ea", version(21, 0, 0)),
+            ("11", version(11, 0, 0)),
+            ("1.8.0_392", version(8, 0, 392)),
+            ("1.8.0_392-b08", version(8, 0, 392)),
+            ("1.8", version(8, 0, 0)),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(JavaVersion::parse(text), Some(expected), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_versions() {
+        for text in ["", "abc", "+17", "x.1"] {
+            assert_eq!(JavaVersion::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn legacy_versions_order_before_modern_ones() {
+        assert!(JavaVersion::parse("1.8.0_392") < JavaVersion::parse("11.0.1"));
+        assert!(JavaVersion::parse("17.0.10") > JavaVersion::parse("17.0.9"));
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let cases = [
+            (">=17,<22", "17.0.2", true),
+            (">=17,<22", "21.0.9", true),
+            (">=17,<22", "22", false),
+            (">=17,<22", "11.0.20", false),
+            // a bare major matches the whole release line
+            ("17", "17.0.8", true),
+            ("17", "18.0.1", false),
+            ("=17", "17.0.8", true),
+            ("<=17", "17.0.9", true),
+            (">17", "17.0.9", false),
+            (">17", "18", true),
+            ("<17.0.5", "17.0.4", true),
+            ("<17.0.5", "17.0.5", false),
+            ("8", "1.8.0_392", true),
+            (">=11", "1.8.0_392", false),
+            ("", "1.8.0_392", true),
+        ];
+        for (range, text, expected) in cases {
+            let version = JavaVersion::parse(text).unwrap();
+            let matches = VersionRange::parse(range).unwrap().matches(&version);
+            assert_eq!(matches, expected, "{} against {}", text, range);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        assert!(VersionRange::parse("").unwrap().is_any());
+        assert!(VersionRange::parse(" , ").unwrap().is_any());
+        for range in [">=abc", "17,<", "~17"] {
+            assert!(VersionRange::parse(range).is_err(), "{}", range);
+        }
+    }
+}
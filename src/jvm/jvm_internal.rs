@@ -1,4 +1,4 @@
-#![allow(non_snake_case, non_camel_case_types, dead_code)]
+#![allow(non_snake_case, non_camel_case_types, dead_code, clippy::not_unsafe_ptr_arg_deref)]
 use jni::{
     objects::JClass,
     strings::JNIString,
@@ -17,10 +17,7 @@ pub struct JvmInternal {
 
 impl JvmInternal {
     pub fn new(libjvm_path: &str) -> Result<JvmInternal, libloading::Error> {
-        let libjvm = match unsafe { Library::new(libjvm_path) } {
-            Ok(lib) => lib,
-            Err(e) => return Err(e),
-        };
+        let libjvm = unsafe { Library::new(libjvm_path) }?;
 
         let internal = unsafe {
             let function_ptr = libjvm.get(b"JVM_FindClassFromBootLoader\0").unwrap();
@@ -40,10 +37,10 @@ impl JvmInternal {
      * 获取name所对应的jclass
      */
     pub fn find_class_from_bootloader(
-        self: &Self,
+        &self,
         penv: *mut sys::JNIEnv,
         name: &str,
-    ) -> Option<JClass> {
+    ) -> Option<JClass<'_>> {
         let name = JNIString::from(name);
         let clz = unsafe { (self.find_class_from_bootloader)(penv, name.as_ptr() as *mut c_char) };
         if clz.is_null() {
@@ -57,7 +54,7 @@ impl JvmInternal {
     /**
      * 获取jclass的类名
      */
-    pub fn get_class_name_utf(self: &Self, penv: *mut sys::JNIEnv, cls: &jclass) -> Option<String> {
+    pub fn get_class_name_utf(&self, penv: *mut sys::JNIEnv, cls: &jclass) -> Option<String> {
         let class_name = unsafe { (self.get_class_name_utf)(penv, *cls as *mut _) };
         if class_name.is_null() {
             None
@@ -1,64 +1,266 @@
 #![allow(non_snake_case, non_camel_case_types, dead_code)]
 
-use crate::{jvmti::errors::JvmtiError, jvmti::jvmti_sys::jvmtiCapabilities};
-
-pub const CAN_TAG_OBJECTS: usize = 0;
-pub const CAN_GENERATE_FIELD_MODIFICATION_EVENTS: usize = 1;
-pub const CAN_GENERATE_FIELD_ACCESS_EVENTS: usize = 2;
-pub const CAN_GET_BYTECODES: usize = 3;
-pub const CAN_GET_SYNTHETIC_ATTRIBUTE: usize = 4;
-pub const CAN_GET_OWNED_MONITOR_INFO: usize = 5;
-pub const CAN_GET_CURRENT_CONTENDED_MONITOR: usize = 6;
-pub const CAN_GET_MONITOR_INFO: usize = 7;
-pub const CAN_POP_FRAME: usize = 8;
-pub const CAN_REDEFINE_CLASSES: usize = 9;
-pub const CAN_SIGNAL_THREAD: usize = 10;
-pub const CAN_GET_SOURCE_FILE_NAME: usize = 11;
-pub const CAN_GET_LINE_NUMBERS: usize = 12;
-pub const CAN_GET_SOURCE_DEBUG_EXTENSION: usize = 13;
-pub const CAN_ACCESS_LOCAL_VARIABLES: usize = 14;
-pub const CAN_MAINTAIN_ORIGINAL_METHOD_ORDER: usize = 15;
-pub const CAN_GENERATE_SINGLE_STEP_EVENTS: usize = 16;
-pub const CAN_GENERATE_EXCEPTION_EVENTS: usize = 17;
-pub const CAN_GENERATE_FRAME_POP_EVENTS: usize = 18;
-pub const CAN_GENERATE_BREAKPOINT_EVENTS: usize = 19;
-pub const CAN_SUSPEND: usize = 20;
-pub const CAN_REDEFINE_ANY_CLASS: usize = 21;
-pub const CAN_GET_CURRENT_THREAD_CPU_TIME: usize = 22;
-pub const CAN_GET_THREAD_CPU_TIME: usize = 23;
-pub const CAN_GENERATE_METHOD_ENTRY_EVENTS: usize = 24;
-pub const CAN_GENERATE_METHOD_EXIT_EVENTS: usize = 25;
-pub const CAN_GENERATE_ALL_CLASS_HOOK_EVENTS: usize = 26;
-pub const CAN_GENERATE_COMPILED_METHOD_LOAD_EVENTS: usize = 27;
-pub const CAN_GENERATE_MONITOR_EVENTS: usize = 28;
-pub const CAN_GENERATE_VM_OBJECT_ALLOC_EVENTS: usize = 29;
-pub const CAN_GENERATE_NATIVE_METHOD_BIND_EVENTS: usize = 30;
-pub const CAN_GENERATE_GARBAGE_COLLECTION_EVENTS: usize = 31;
-pub const CAN_GENERATE_OBJECT_FREE_EVENTS: usize = 32;
-pub const CAN_FORCE_EARLY_RETURN: usize = 33;
-pub const CAN_GET_OWNED_MONITOR_STACK_DEPTH_INFO: usize = 34;
-pub const CAN_GET_CONSTANT_POOL: usize = 35;
-pub const CAN_SET_NATIVE_METHOD_PREFIX: usize = 36;
-pub const CAN_RETRANSFORM_CLASSES: usize = 37;
-pub const CAN_RETRANSFORM_ANY_CLASS: usize = 38;
-pub const CAN_GENERATE_RESOURCE_EXHAUSTION_HEAP_EVENTS: usize = 39;
-pub const CAN_GENERATE_RESOURCE_EXHAUSTION_THREADS_EVENTS: usize = 40;
-pub const CAN_GENERATE_EARLY_VMSTART: usize = 41;
-pub const CAN_GENERATE_EARLY_CLASS_HOOK_EVENTS: usize = 42;
-pub const CAN_GENERATE_SAMPLED_OBJECT_ALLOC_EVENTS: usize = 43;
-
-pub fn set_capability(capabilities: &mut jvmtiCapabilities, idx: usize) -> Result<(), JvmtiError> {
-    let n = idx / 32;
-    let bit = idx & 31;
-    match n {
-        0 => {
-            capabilities._bindgen_bitfield_1_ |= 1 << bit;
-            Ok(())
+use std::fmt::{Debug, Display, Formatter, Result};
+use std::ops::{BitAnd, BitOr, Sub};
+use std::os::raw::c_uint;
+
+use thiserror::Error;
+
+use crate::jvmti::errors::JvmtiError;
+use crate::jvmti::jvmti_sys::jvmtiCapabilities;
+use crate::jvmti::jvmti_wrapper::JvmtiEnv;
+
+/**
+ * Declares, for every capability, its bit index in jvmtiCapabilities (the order of the
+ * bit fields in jvmti.h), its name and a builder method of the same name.
+ */
+macro_rules! capabilities {
+    ($($index:literal => $constant:ident, $method:ident;)*) => {
+        $(pub const $constant: usize = $index;)*
+
+        const NAMES: &[(usize, &str)] = &[$(($index, stringify!($method))),*];
+
+        impl Capabilities {
+            $(
+                pub fn $method(self) -> Capabilities {
+                    self.with($constant)
+                }
+            )*
         }
-        1 => {
-            capabilities._bindgen_bitfield_2_ |= 1 << bit;
-            Ok(())
+    };
+}
+
+capabilities! {
+    0 => CAN_TAG_OBJECTS, can_tag_objects;
+    1 => CAN_GENERATE_FIELD_MODIFICATION_EVENTS, can_generate_field_modification_events;
+    2 => CAN_GENERATE_FIELD_ACCESS_EVENTS, can_generate_field_access_events;
+    3 => CAN_GET_BYTECODES, can_get_bytecodes;
+    4 => CAN_GET_SYNTHETIC_ATTRIBUTE, can_get_synthetic_attribute;
+    5 => CAN_GET_OWNED_MONITOR_INFO, can_get_owned_monitor_info;
+    6 => CAN_GET_CURRENT_CONTENDED_MONITOR, can_get_current_contended_monitor;
+    7 => CAN_GET_MONITOR_INFO, can_get_monitor_info;
+    8 => CAN_POP_FRAME, can_pop_frame;
+    9 => CAN_REDEFINE_CLASSES, can_redefine_classes;
+    10 => CAN_SIGNAL_THREAD, can_signal_thread;
+    11 => CAN_GET_SOURCE_FILE_NAME, can_get_source_file_name;
+    12 => CAN_GET_LINE_NUMBERS, can_get_line_numbers;
+    13 => CAN_GET_SOURCE_DEBUG_EXTENSION, can_get_source_debug_extension;
+    14 => CAN_ACCESS_LOCAL_VARIABLES, can_access_local_variables;
+    15 => CAN_MAINTAIN_ORIGINAL_METHOD_ORDER, can_maintain_original_method_order;
+    16 => CAN_GENERATE_SINGLE_STEP_EVENTS, can_generate_single_step_events;
+    17 => CAN_GENERATE_EXCEPTION_EVENTS, can_generate_exception_events;
+    18 => CAN_GENERATE_FRAME_POP_EVENTS, can_generate_frame_pop_events;
+    19 => CAN_GENERATE_BREAKPOINT_EVENTS, can_generate_breakpoint_events;
+    20 => CAN_SUSPEND, can_suspend;
+    21 => CAN_REDEFINE_ANY_CLASS, can_redefine_any_class;
+    22 => CAN_GET_CURRENT_THREAD_CPU_TIME, can_get_current_thread_cpu_time;
+    23 => CAN_GET_THREAD_CPU_TIME, can_get_thread_cpu_time;
+    24 => CAN_GENERATE_METHOD_ENTRY_EVENTS, can_generate_method_entry_events;
+    25 => CAN_GENERATE_METHOD_EXIT_EVENTS, can_generate_method_exit_events;
+    26 => CAN_GENERATE_ALL_CLASS_HOOK_EVENTS, can_generate_all_class_hook_events;
+    27 => CAN_GENERATE_COMPILED_METHOD_LOAD_EVENTS, can_generate_compiled_method_load_events;
+    28 => CAN_GENERATE_MONITOR_EVENTS, can_generate_monitor_events;
+    29 => CAN_GENERATE_VM_OBJECT_ALLOC_EVENTS, can_generate_vm_object_alloc_events;
+    30 => CAN_GENERATE_NATIVE_METHOD_BIND_EVENTS, can_generate_native_method_bind_events;
+    31 => CAN_GENERATE_GARBAGE_COLLECTION_EVENTS, can_generate_garbage_collection_events;
+    32 => CAN_GENERATE_OBJECT_FREE_EVENTS, can_generate_object_free_events;
+    33 => CAN_FORCE_EARLY_RETURN, can_force_early_return;
+    34 => CAN_GET_OWNED_MONITOR_STACK_DEPTH_INFO, can_get_owned_monitor_stack_depth_info;
+    35 => CAN_GET_CONSTANT_POOL, can_get_constant_pool;
+    36 => CAN_SET_NATIVE_METHOD_PREFIX, can_set_native_method_prefix;
+    37 => CAN_RETRANSFORM_CLASSES, can_retransform_classes;
+    38 => CAN_RETRANSFORM_ANY_CLASS, can_retransform_any_class;
+    39 => CAN_GENERATE_RESOURCE_EXHAUSTION_HEAP_EVENTS, can_generate_resource_exhaustion_heap_events;
+    40 => CAN_GENERATE_RESOURCE_EXHAUSTION_THREADS_EVENTS, can_generate_resource_exhaustion_threads_events;
+    41 => CAN_GENERATE_EARLY_VMSTART, can_generate_early_vmstart;
+    42 => CAN_GENERATE_EARLY_CLASS_HOOK_EVENTS, can_generate_early_class_hook_events;
+    43 => CAN_GENERATE_SAMPLED_OBJECT_ALLOC_EVENTS, can_generate_sampled_object_alloc_events;
+    44 => CAN_SUPPORT_VIRTUAL_THREADS, can_support_virtual_threads;
+}
+
+/**
+ * A set of JVMTI capabilities, built with one method per capability:
+ * `Capabilities::new().can_tag_objects().can_get_line_numbers()`.
+ */
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities {
+    words: [c_uint; 4],
+}
+
+impl Capabilities {
+    pub fn new() -> Capabilities {
+        Capabilities::default()
+    }
+
+    /**
+     * Adds a capability by index, one of the `CAN_*` constants.
+     */
+    pub fn with(mut self, index: usize) -> Capabilities {
+        self.words[index / 32] |= 1 << (index % 32);
+        self
+    }
+
+    pub fn without(mut self, index: usize) -> Capabilities {
+        self.words[index / 32] &= !(1 << (index % 32));
+        self
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.words[index / 32] & (1 << (index % 32)) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|word| *word == 0)
+    }
+
+    pub fn is_subset(&self, other: &Capabilities) -> bool {
+        self.difference(other).is_empty()
+    }
+
+    pub fn union(&self, other: &Capabilities) -> Capabilities {
+        self.zip(other, |a, b| a | b)
+    }
+
+    pub fn intersection(&self, other: &Capabilities) -> Capabilities {
+        self.zip(other, |a, b| a & b)
+    }
+
+    pub fn difference(&self, other: &Capabilities) -> Capabilities {
+        self.zip(other, |a, b| a & !b)
+    }
+
+    /**
+     * The names of the enabled capabilities, as spelled in jvmti.h.
+     * Bits this version does not know about are skipped.
+     */
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        NAMES
+            .iter()
+            .filter(|(index, _)| self.contains(*index))
+            .map(|(_, name)| *name)
+    }
+
+    fn zip(&self, other: &Capabilities, op: impl Fn(c_uint, c_uint) -> c_uint) -> Capabilities {
+        let mut words = self.words;
+        for (word, other) in words.iter_mut().zip(other.words) {
+            *word = op(*word, other);
+        }
+        Capabilities { words }
+    }
+}
+
+impl From<jvmtiCapabilities> for Capabilities {
+    fn from(raw: jvmtiCapabilities) -> Self {
+        Capabilities {
+            words: [
+                raw._bindgen_bitfield_1_,
+                raw._bindgen_bitfield_2_,
+                raw._bindgen_bitfield_3_,
+                raw._bindgen_bitfield_4_,
+            ],
         }
-        _ => Err(JvmtiError::NotAvailable),
     }
 }
+
+impl From<Capabilities> for jvmtiCapabilities {
+    fn from(capabilities: Capabilities) -> Self {
+        let [first, second, third, fourth] = capabilities.words;
+        jvmtiCapabilities {
+            _bindgen_bitfield_1_: first,
+            _bindgen_bitfield_2_: second,
+            _bindgen_bitfield_3_: third,
+            _bindgen_bitfield_4_: fourth,
+        }
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        self.union(&other)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, other: Capabilities) -> Capabilities {
+        self.intersection(&other)
+    }
+}
+
+impl Sub for Capabilities {
+    type Output = Capabilities;
+
+    fn sub(self, other: Capabilities) -> Capabilities {
+        self.difference(&other)
+    }
+}
+
+/**
+ * Comma separated names, `none` for the empty set.
+ */
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if self.is_empty() {
+            return f.write_str("none");
+        }
+        let names: Vec<&str> = self.names().collect();
+        f.write_str(&names.join(", "))
+    }
+}
+
+impl Debug for Capabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CapabilityError {
+    #[error("required capabilities not available: {0}")]
+    Missing(Capabilities),
+
+    #[error("{0}")]
+    Jvmti(
+        #[from]
+        #[source]
+        JvmtiError
+    ),
+}
+
+/**
+ * What `negotiate` added to the environment, and which of the optional capabilities
+ * the VM could not provide.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Negotiated {
+    pub granted: Capabilities,
+    pub unavailable: Capabilities,
+}
+
+/**
+ * Adds `required` plus whatever of `optional` the VM can provide in the current phase.
+ * Fails without adding anything if one of the required capabilities is not potentially available.
+ */
+pub fn negotiate(
+    jvmti: &JvmtiEnv,
+    required: Capabilities,
+    optional: Capabilities,
+) -> std::result::Result<Negotiated, CapabilityError> {
+    let potential = Capabilities::from(jvmti.get_potential_capabilities()?);
+
+    let missing = required - potential;
+    if !missing.is_empty() {
+        return Err(CapabilityError::Missing(missing));
+    }
+
+    let granted = required | (optional & potential);
+    jvmti.add_capabilities(granted.into())?;
+
+    Ok(Negotiated {
+        granted,
+        unavailable: optional - potential,
+    })
+}
@@ -0,0 +1,720 @@
+use std::any::Any;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::{Arc, RwLock};
+
+use jni::objects::{JClass, JObject};
+use jni::sys::{self, jboolean, jclass, jfieldID, jint, jlong, jmethodID, jobject, jvalue};
+use jni::JNIEnv;
+use log::error;
+use once_cell::sync::Lazy;
+
+use crate::jvmti::errors::JvmtiResult;
+use crate::jvmti::jvmti_sys::{
+    jlocation, jthread, jvmtiEnv, jvmtiEvent, jvmtiEventCallbacks, jvmtiEventClassFileLoadHook, jvmtiEventMode,
+    JVMTI_RESOURCE_EXHAUSTED_JAVA_HEAP, JVMTI_RESOURCE_EXHAUSTED_OOM_ERROR, JVMTI_RESOURCE_EXHAUSTED_THREADS,
+};
+use crate::jvmti::jvmti_wrapper::JvmtiEnv;
+
+pub type ThreadHandler = dyn Fn(&mut JNIEnv, &JObject) + Send + Sync;
+pub type VmDeathHandler = dyn Fn(&mut JNIEnv) + Send + Sync;
+pub type ClassHandler = dyn Fn(&mut JNIEnv, &JObject, &JClass) + Send + Sync;
+pub type ExceptionHandler = dyn Fn(&mut JNIEnv, &ExceptionEvent<'_>) + Send + Sync;
+pub type MethodEntryHandler = dyn Fn(&mut JNIEnv, &JObject, jmethodID) + Send + Sync;
+pub type MethodExitHandler = dyn Fn(&mut JNIEnv, &MethodExitEvent<'_>) + Send + Sync;
+pub type BreakpointHandler = dyn Fn(&mut JNIEnv, &JObject, jmethodID, jlocation) + Send + Sync;
+pub type FieldAccessHandler = dyn Fn(&mut JNIEnv, &FieldAccessEvent<'_>) + Send + Sync;
+pub type MonitorHandler = dyn Fn(&mut JNIEnv, &JObject, &JObject) + Send + Sync;
+pub type GarbageCollectionHandler = dyn Fn() + Send + Sync;
+pub type ObjectFreeHandler = dyn Fn(jlong) + Send + Sync;
+pub type ObjectAllocHandler = dyn Fn(&mut JNIEnv, &ObjectAllocEvent<'_>) + Send + Sync;
+pub type ResourceExhaustedHandler = dyn Fn(&mut JNIEnv, &ResourceExhaustedEvent) + Send + Sync;
+
+pub struct ExceptionEvent<'a> {
+    pub thread: JObject<'a>,
+    pub method: jmethodID,
+    pub location: jlocation,
+    pub exception: JObject<'a>,
+    /**
+     * Null when nothing up the stack catches the exception.
+     */
+    pub catch_method: jmethodID,
+    pub catch_location: jlocation,
+}
+
+pub struct MethodExitEvent<'a> {
+    pub thread: JObject<'a>,
+    pub method: jmethodID,
+    pub popped_by_exception: bool,
+    /**
+     * Typed by the method's return type, meaningless if popped by an exception.
+     */
+    pub return_value: jvalue,
+}
+
+pub struct FieldAccessEvent<'a> {
+    pub thread: JObject<'a>,
+    pub method: jmethodID,
+    pub location: jlocation,
+    pub field_class: JClass<'a>,
+    /**
+     * Null for a static field.
+     */
+    pub object: JObject<'a>,
+    pub field: jfieldID,
+}
+
+pub struct ObjectAllocEvent<'a> {
+    pub thread: JObject<'a>,
+    pub object: JObject<'a>,
+    pub class: JClass<'a>,
+    pub size: jlong,
+}
+
+/**
+ * `flags` are the JVMTI_RESOURCE_EXHAUSTED_* bits, `description` is what the VM says ran out,
+ * e.g. "Java heap space".
+ */
+pub struct ResourceExhaustedEvent {
+    pub flags: jint,
+    pub description: String,
+}
+
+impl ResourceExhaustedEvent {
+    /**
+     * An OutOfMemoryError is about to be thrown.
+     */
+    pub fn is_oom_error(&self) -> bool {
+        self.flags & JVMTI_RESOURCE_EXHAUSTED_OOM_ERROR as jint != 0
+    }
+
+    pub fn is_java_heap(&self) -> bool {
+        self.flags & JVMTI_RESOURCE_EXHAUSTED_JAVA_HEAP as jint != 0
+    }
+
+    pub fn is_threads(&self) -> bool {
+        self.flags & JVMTI_RESOURCE_EXHAUSTED_THREADS as jint != 0
+    }
+}
+
+/**
+ * One handler per event. JVMTI callbacks carry no user data, so the handlers live here
+ * and the trampolines below look them up, which means one registry per process.
+ */
+#[derive(Default)]
+struct Handlers {
+    vm_init: Option<Arc<ThreadHandler>>,
+    vm_death: Option<Arc<VmDeathHandler>>,
+    thread_start: Option<Arc<ThreadHandler>>,
+    thread_end: Option<Arc<ThreadHandler>>,
+    class_load: Option<Arc<ClassHandler>>,
+    class_prepare: Option<Arc<ClassHandler>>,
+    exception: Option<Arc<ExceptionHandler>>,
+    method_entry: Option<Arc<MethodEntryHandler>>,
+    method_exit: Option<Arc<MethodExitHandler>>,
+    breakpoint: Option<Arc<BreakpointHandler>>,
+    field_access: Option<Arc<FieldAccessHandler>>,
+    monitor_contended_enter: Option<Arc<MonitorHandler>>,
+    monitor_contended_entered: Option<Arc<MonitorHandler>>,
+    garbage_collection_start: Option<Arc<GarbageCollectionHandler>>,
+    garbage_collection_finish: Option<Arc<GarbageCollectionHandler>>,
+    object_free: Option<Arc<ObjectFreeHandler>>,
+    vm_object_alloc: Option<Arc<ObjectAllocHandler>>,
+    sampled_object_alloc: Option<Arc<ObjectAllocHandler>>,
+    resource_exhausted: Option<Arc<ResourceExhaustedHandler>>,
+    /**
+     * Rewrites class bytes, so it stays a raw callback.
+     */
+    class_file_load_hook: jvmtiEventClassFileLoadHook,
+}
+
+static HANDLERS: Lazy<RwLock<Handlers>> = Lazy::new(|| RwLock::new(Handlers::default()));
+
+impl Handlers {
+    fn callbacks(&self) -> jvmtiEventCallbacks {
+        let mut callbacks = jvmtiEventCallbacks::default();
+        if self.vm_init.is_some() {
+            callbacks.VMInit = Some(vm_init);
+        }
+        if self.vm_death.is_some() {
+            callbacks.VMDeath = Some(vm_death);
+        }
+        if self.thread_start.is_some() {
+            callbacks.ThreadStart = Some(thread_start);
+        }
+        if self.thread_end.is_some() {
+            callbacks.ThreadEnd = Some(thread_end);
+        }
+        if self.class_load.is_some() {
+            callbacks.ClassLoad = Some(class_load);
+        }
+        if self.class_prepare.is_some() {
+            callbacks.ClassPrepare = Some(class_prepare);
+        }
+        if self.exception.is_some() {
+            callbacks.Exception = Some(exception);
+        }
+        if self.method_entry.is_some() {
+            callbacks.MethodEntry = Some(method_entry);
+        }
+        if self.method_exit.is_some() {
+            callbacks.MethodExit = Some(method_exit);
+        }
+        if self.breakpoint.is_some() {
+            callbacks.Breakpoint = Some(breakpoint);
+        }
+        if self.field_access.is_some() {
+            callbacks.FieldAccess = Some(field_access);
+        }
+        if self.monitor_contended_enter.is_some() {
+            callbacks.MonitorContendedEnter = Some(monitor_contended_enter);
+        }
+        if self.monitor_contended_entered.is_some() {
+            callbacks.MonitorContendedEntered = Some(monitor_contended_entered);
+        }
+        if self.garbage_collection_start.is_some() {
+            callbacks.GarbageCollectionStart = Some(garbage_collection_start);
+        }
+        if self.garbage_collection_finish.is_some() {
+            callbacks.GarbageCollectionFinish = Some(garbage_collection_finish);
+        }
+        if self.object_free.is_some() {
+            callbacks.ObjectFree = Some(object_free);
+        }
+        if self.vm_object_alloc.is_some() {
+            callbacks.VMObjectAlloc = Some(vm_object_alloc);
+        }
+        if self.sampled_object_alloc.is_some() {
+            callbacks.SampledObjectAlloc = Some(sampled_object_alloc);
+        }
+        if self.resource_exhausted.is_some() {
+            callbacks.ResourceExhausted = Some(resource_exhausted);
+        }
+        callbacks.ClassFileLoadHook = self.class_file_load_hook;
+        callbacks
+    }
+
+    fn remove(&mut self, event: jvmtiEvent) {
+        match event {
+            jvmtiEvent::JVMTI_EVENT_VM_INIT => self.vm_init = None,
+            jvmtiEvent::JVMTI_EVENT_VM_DEATH => self.vm_death = None,
+            jvmtiEvent::JVMTI_EVENT_THREAD_START => self.thread_start = None,
+            jvmtiEvent::JVMTI_EVENT_THREAD_END => self.thread_end = None,
+            jvmtiEvent::JVMTI_EVENT_CLASS_LOAD => self.class_load = None,
+            jvmtiEvent::JVMTI_EVENT_CLASS_PREPARE => self.class_prepare = None,
+            jvmtiEvent::JVMTI_EVENT_EXCEPTION => self.exception = None,
+            jvmtiEvent::JVMTI_EVENT_METHOD_ENTRY => self.method_entry = None,
+            jvmtiEvent::JVMTI_EVENT_METHOD_EXIT => self.method_exit = None,
+            jvmtiEvent::JVMTI_EVENT_BREAKPOINT => self.breakpoint = None,
+            jvmtiEvent::JVMTI_EVENT_FIELD_ACCESS => self.field_access = None,
+            jvmtiEvent::JVMTI_EVENT_MONITOR_CONTENDED_ENTER => self.monitor_contended_enter = None,
+            jvmtiEvent::JVMTI_EVENT_MONITOR_CONTENDED_ENTERED => self.monitor_contended_entered = None,
+            jvmtiEvent::JVMTI_EVENT_GARBAGE_COLLECTION_START => self.garbage_collection_start = None,
+            jvmtiEvent::JVMTI_EVENT_GARBAGE_COLLECTION_FINISH => self.garbage_collection_finish = None,
+            jvmtiEvent::JVMTI_EVENT_OBJECT_FREE => self.object_free = None,
+            jvmtiEvent::JVMTI_EVENT_VM_OBJECT_ALLOC => self.vm_object_alloc = None,
+            jvmtiEvent::JVMTI_EVENT_SAMPLED_OBJECT_ALLOC => self.sampled_object_alloc = None,
+            jvmtiEvent::JVMTI_EVENT_RESOURCE_EXHAUSTED => self.resource_exhausted = None,
+            jvmtiEvent::JVMTI_EVENT_CLASS_FILE_LOAD_HOOK => self.class_file_load_hook = None,
+            _ => (),
+        }
+    }
+}
+
+/**
+ * Registers Rust closures as JVMTI event handlers. Registering enables the event,
+ * `clear` disables it again. A handler that panics is logged, the panic never reaches the VM.
+ * Most events need a capability first, without it enabling fails with MustPossessCapability.
+ */
+pub struct EventRegistry<'a> {
+    jvmti: &'a JvmtiEnv,
+}
+
+impl<'a> EventRegistry<'a> {
+    pub fn new(jvmti: &'a JvmtiEnv) -> EventRegistry<'a> {
+        EventRegistry { jvmti }
+    }
+
+    pub fn on_vm_init<F: Fn(&mut JNIEnv, &JObject) + Send + Sync + 'static>(&self, handler: F) -> JvmtiResult<()> {
+        self.register(jvmtiEvent::JVMTI_EVENT_VM_INIT, |h| h.vm_init = Some(Arc::new(handler)))
+    }
+
+    pub fn on_vm_death<F: Fn(&mut JNIEnv) + Send + Sync + 'static>(&self, handler: F) -> JvmtiResult<()> {
+        self.register(jvmtiEvent::JVMTI_EVENT_VM_DEATH, |h| h.vm_death = Some(Arc::new(handler)))
+    }
+
+    pub fn on_thread_start<F: Fn(&mut JNIEnv, &JObject) + Send + Sync + 'static>(&self, handler: F) -> JvmtiResult<()> {
+        self.register(jvmtiEvent::JVMTI_EVENT_THREAD_START, |h| h.thread_start = Some(Arc::new(handler)))
+    }
+
+    pub fn on_thread_end<F: Fn(&mut JNIEnv, &JObject) + Send + Sync + 'static>(&self, handler: F) -> JvmtiResult<()> {
+        self.register(jvmtiEvent::JVMTI_EVENT_THREAD_END, |h| h.thread_end = Some(Arc::new(handler)))
+    }
+
+    pub fn on_class_load<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &JObject, &JClass) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_CLASS_LOAD, |h| h.class_load = Some(Arc::new(handler)))
+    }
+
+    pub fn on_class_prepare<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &JObject, &JClass) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_CLASS_PREPARE, |h| h.class_prepare = Some(Arc::new(handler)))
+    }
+
+    /**
+     * Needs can_generate_exception_events.
+     */
+    pub fn on_exception<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &ExceptionEvent<'_>) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_EXCEPTION, |h| h.exception = Some(Arc::new(handler)))
+    }
+
+    /**
+     * Needs can_generate_method_entry_events, and slows down every call in the VM.
+     */
+    pub fn on_method_entry<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &JObject, jmethodID) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_METHOD_ENTRY, |h| h.method_entry = Some(Arc::new(handler)))
+    }
+
+    /**
+     * Needs can_generate_method_exit_events.
+     */
+    pub fn on_method_exit<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &MethodExitEvent<'_>) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_METHOD_EXIT, |h| h.method_exit = Some(Arc::new(handler)))
+    }
+
+    /**
+     * Needs can_generate_breakpoint_events, breakpoints are set with `set_break_point`.
+     */
+    pub fn on_breakpoint<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &JObject, jmethodID, jlocation) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_BREAKPOINT, |h| h.breakpoint = Some(Arc::new(handler)))
+    }
+
+    /**
+     * Needs can_generate_field_access_events, watches are set with `set_field_access_watch`.
+     */
+    pub fn on_field_access<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &FieldAccessEvent<'_>) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_FIELD_ACCESS, |h| h.field_access = Some(Arc::new(handler)))
+    }
+
+    /**
+     * Needs can_generate_monitor_events. The handler gets the thread and the monitor object.
+     */
+    pub fn on_monitor_contended_enter<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &JObject, &JObject) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_MONITOR_CONTENDED_ENTER, |h| {
+            h.monitor_contended_enter = Some(Arc::new(handler))
+        })
+    }
+
+    /**
+     * Needs can_generate_monitor_events.
+     */
+    pub fn on_monitor_contended_entered<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &JObject, &JObject) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_MONITOR_CONTENDED_ENTERED, |h| {
+            h.monitor_contended_entered = Some(Arc::new(handler))
+        })
+    }
+
+    /**
+     * Needs can_generate_garbage_collection_events. Runs while the world is stopped,
+     * the handler must not call JNI or anything but the raw monitor JVMTI functions.
+     */
+    pub fn on_garbage_collection_start<F: Fn() + Send + Sync + 'static>(&self, handler: F) -> JvmtiResult<()> {
+        self.register(jvmtiEvent::JVMTI_EVENT_GARBAGE_COLLECTION_START, |h| {
+            h.garbage_collection_start = Some(Arc::new(handler))
+        })
+    }
+
+    /**
+     * Same restrictions as `on_garbage_collection_start`.
+     */
+    pub fn on_garbage_collection_finish<F: Fn() + Send + Sync + 'static>(&self, handler: F) -> JvmtiResult<()> {
+        self.register(jvmtiEvent::JVMTI_EVENT_GARBAGE_COLLECTION_FINISH, |h| {
+            h.garbage_collection_finish = Some(Arc::new(handler))
+        })
+    }
+
+    /**
+     * Needs can_generate_object_free_events. Called with the tag of a freed tagged object,
+     * the same JNI restrictions as for garbage collection apply.
+     */
+    pub fn on_object_free<F: Fn(jlong) + Send + Sync + 'static>(&self, handler: F) -> JvmtiResult<()> {
+        self.register(jvmtiEvent::JVMTI_EVENT_OBJECT_FREE, |h| h.object_free = Some(Arc::new(handler)))
+    }
+
+    /**
+     * Needs can_generate_vm_object_alloc_events. Only reports allocations the VM makes
+     * on its own behalf, not those done by bytecode.
+     */
+    pub fn on_vm_object_alloc<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &ObjectAllocEvent<'_>) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_VM_OBJECT_ALLOC, |h| h.vm_object_alloc = Some(Arc::new(handler)))
+    }
+
+    /**
+     * Needs can_generate_sampled_object_alloc_events (JVMTI 11), the interval is set
+     * with SetHeapSamplingInterval.
+     */
+    pub fn on_sampled_object_alloc<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &ObjectAllocEvent<'_>) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_SAMPLED_OBJECT_ALLOC, |h| {
+            h.sampled_object_alloc = Some(Arc::new(handler))
+        })
+    }
+
+    /**
+     * Needs can_generate_resource_exhaustion_heap_events or ..._threads_events, depending on
+     * which resource is of interest. Called on the thread that ran out, before the error is thrown.
+     */
+    pub fn on_resource_exhausted<F>(&self, handler: F) -> JvmtiResult<()>
+    where
+        F: Fn(&mut JNIEnv, &ResourceExhaustedEvent) + Send + Sync + 'static,
+    {
+        self.register(jvmtiEvent::JVMTI_EVENT_RESOURCE_EXHAUSTED, |h| {
+            h.resource_exhausted = Some(Arc::new(handler))
+        })
+    }
+
+    /**
+     * The ClassFileLoadHook hands out buffers to replace, so it takes a raw callback.
+     */
+    pub fn set_class_file_load_hook(&self, hook: jvmtiEventClassFileLoadHook) -> JvmtiResult<()> {
+        self.register(jvmtiEvent::JVMTI_EVENT_CLASS_FILE_LOAD_HOOK, |h| h.class_file_load_hook = hook)
+    }
+
+    /**
+     * Disables the event and drops its handler.
+     */
+    pub fn clear(&self, event: jvmtiEvent) -> JvmtiResult<()> {
+        self.jvmti
+            .set_event_notification_mode(jvmtiEventMode::JVMTI_DISABLE, event, ptr::null_mut())?;
+        let callbacks = {
+            let mut handlers = HANDLERS.write().unwrap();
+            handlers.remove(event);
+            handlers.callbacks()
+        };
+        self.jvmti.set_event_callbacks(callbacks)
+    }
+
+    fn register(&self, event: jvmtiEvent, set: impl FnOnce(&mut Handlers)) -> JvmtiResult<()> {
+        let callbacks = {
+            let mut handlers = HANDLERS.write().unwrap();
+            set(&mut handlers);
+            handlers.callbacks()
+        };
+        self.jvmti.set_event_callbacks(callbacks)?;
+
+        let enabled = self
+            .jvmti
+            .set_event_notification_mode(jvmtiEventMode::JVMTI_ENABLE, event, ptr::null_mut());
+        if enabled.is_err() {
+            HANDLERS.write().unwrap().remove(event);
+        }
+        enabled
+    }
+}
+
+/**
+ * Looks the handler up, releases the lock so a handler can register others, and runs it
+ * with panics caught: unwinding into the VM is undefined behaviour.
+ */
+fn dispatch<H: ?Sized>(event: &str, select: impl FnOnce(&Handlers) -> Option<Arc<H>>, call: impl FnOnce(&H)) {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let handler = HANDLERS.read().ok().and_then(|handlers| select(&handlers));
+        if let Some(handler) = handler {
+            call(&handler);
+        }
+    }));
+    if let Err(panic) = result {
+        error!("{} handler panicked: {}", event, panic_message(&panic));
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+unsafe extern "C" fn vm_init(_jvmti_env: *mut jvmtiEnv, jni_env: *mut sys::JNIEnv, thread: jthread) {
+    dispatch("VMInit", |h| h.vm_init.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &JObject::from_raw(thread))
+        }
+    });
+}
+
+unsafe extern "C" fn vm_death(_jvmti_env: *mut jvmtiEnv, jni_env: *mut sys::JNIEnv) {
+    dispatch("VMDeath", |h| h.vm_death.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env)
+        }
+    });
+}
+
+unsafe extern "C" fn thread_start(_jvmti_env: *mut jvmtiEnv, jni_env: *mut sys::JNIEnv, thread: jthread) {
+    dispatch("ThreadStart", |h| h.thread_start.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &JObject::from_raw(thread))
+        }
+    });
+}
+
+unsafe extern "C" fn thread_end(_jvmti_env: *mut jvmtiEnv, jni_env: *mut sys::JNIEnv, thread: jthread) {
+    dispatch("ThreadEnd", |h| h.thread_end.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &JObject::from_raw(thread))
+        }
+    });
+}
+
+unsafe extern "C" fn class_load(_jvmti_env: *mut jvmtiEnv, jni_env: *mut sys::JNIEnv, thread: jthread, class: jclass) {
+    dispatch("ClassLoad", |h| h.class_load.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &JObject::from_raw(thread), &JClass::from_raw(class))
+        }
+    });
+}
+
+unsafe extern "C" fn class_prepare(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    thread: jthread,
+    class: jclass,
+) {
+    dispatch("ClassPrepare", |h| h.class_prepare.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &JObject::from_raw(thread), &JClass::from_raw(class))
+        }
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn exception(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    thread: jthread,
+    method: jmethodID,
+    location: jlocation,
+    exception: jobject,
+    catch_method: jmethodID,
+    catch_location: jlocation,
+) {
+    dispatch("Exception", |h| h.exception.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            let event = ExceptionEvent {
+                thread: JObject::from_raw(thread),
+                method,
+                location,
+                exception: JObject::from_raw(exception),
+                catch_method,
+                catch_location,
+            };
+            handler(&mut env, &event)
+        }
+    });
+}
+
+unsafe extern "C" fn method_entry(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    thread: jthread,
+    method: jmethodID,
+) {
+    dispatch("MethodEntry", |h| h.method_entry.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &JObject::from_raw(thread), method)
+        }
+    });
+}
+
+unsafe extern "C" fn method_exit(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    thread: jthread,
+    method: jmethodID,
+    was_popped_by_exception: jboolean,
+    return_value: jvalue,
+) {
+    dispatch("MethodExit", |h| h.method_exit.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            let event = MethodExitEvent {
+                thread: JObject::from_raw(thread),
+                method,
+                popped_by_exception: was_popped_by_exception != 0,
+                return_value,
+            };
+            handler(&mut env, &event)
+        }
+    });
+}
+
+unsafe extern "C" fn breakpoint(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    thread: jthread,
+    method: jmethodID,
+    location: jlocation,
+) {
+    dispatch("Breakpoint", |h| h.breakpoint.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &JObject::from_raw(thread), method, location)
+        }
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn field_access(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    thread: jthread,
+    method: jmethodID,
+    location: jlocation,
+    field_klass: jclass,
+    object: jobject,
+    field: jfieldID,
+) {
+    dispatch("FieldAccess", |h| h.field_access.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            let event = FieldAccessEvent {
+                thread: JObject::from_raw(thread),
+                method,
+                location,
+                field_class: JClass::from_raw(field_klass),
+                object: JObject::from_raw(object),
+                field,
+            };
+            handler(&mut env, &event)
+        }
+    });
+}
+
+unsafe extern "C" fn monitor_contended_enter(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    thread: jthread,
+    object: jobject,
+) {
+    dispatch("MonitorContendedEnter", |h| h.monitor_contended_enter.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &JObject::from_raw(thread), &JObject::from_raw(object))
+        }
+    });
+}
+
+unsafe extern "C" fn monitor_contended_entered(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    thread: jthread,
+    object: jobject,
+) {
+    dispatch("MonitorContendedEntered", |h| h.monitor_contended_entered.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &JObject::from_raw(thread), &JObject::from_raw(object))
+        }
+    });
+}
+
+unsafe extern "C" fn garbage_collection_start(_jvmti_env: *mut jvmtiEnv) {
+    dispatch("GarbageCollectionStart", |h| h.garbage_collection_start.clone(), |handler| handler());
+}
+
+unsafe extern "C" fn garbage_collection_finish(_jvmti_env: *mut jvmtiEnv) {
+    dispatch("GarbageCollectionFinish", |h| h.garbage_collection_finish.clone(), |handler| handler());
+}
+
+unsafe extern "C" fn object_free(_jvmti_env: *mut jvmtiEnv, tag: jlong) {
+    dispatch("ObjectFree", |h| h.object_free.clone(), |handler| handler(tag));
+}
+
+unsafe extern "C" fn vm_object_alloc(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    thread: jthread,
+    object: jobject,
+    object_klass: jclass,
+    size: jlong,
+) {
+    dispatch("VMObjectAlloc", |h| h.vm_object_alloc.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &alloc_event(thread, object, object_klass, size))
+        }
+    });
+}
+
+unsafe extern "C" fn sampled_object_alloc(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    thread: jthread,
+    object: jobject,
+    object_klass: jclass,
+    size: jlong,
+) {
+    dispatch("SampledObjectAlloc", |h| h.sampled_object_alloc.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            handler(&mut env, &alloc_event(thread, object, object_klass, size))
+        }
+    });
+}
+
+unsafe extern "C" fn resource_exhausted(
+    _jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut sys::JNIEnv,
+    flags: jint,
+    _reserved: *const c_void,
+    description: *const c_char,
+) {
+    dispatch("ResourceExhausted", |h| h.resource_exhausted.clone(), |handler| {
+        if let Ok(mut env) = JNIEnv::from_raw(jni_env) {
+            let description = if description.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(description).to_string_lossy().into_owned()
+            };
+            handler(&mut env, &ResourceExhaustedEvent { flags, description })
+        }
+    });
+}
+
+unsafe fn alloc_event<'a>(thread: jthread, object: jobject, class: jclass, size: jlong) -> ObjectAllocEvent<'a> {
+    ObjectAllocEvent {
+        thread: JObject::from_raw(thread),
+        object: JObject::from_raw(object),
+        class: JClass::from_raw(class),
+        size,
+    }
+}
@@ -0,0 +1,567 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::os::raw::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use bitflags::bitflags;
+use jni::sys::{
+    jboolean, jbyte, jchar, jclass, jdouble, jfloat, jint, jlong, jmethodID, jobject, jshort, jvalue,
+};
+
+use crate::jvmti::errors::JvmtiResult;
+use crate::jvmti::jvmti_sys::*;
+use crate::jvmti::jvmti_wrapper::JvmtiEnv;
+
+bitflags! {
+    /**
+     * Objects to leave out of a heap walk, by their own tag or by the tag of their class.
+     */
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct HeapFilter: jint {
+        const TAGGED = JVMTI_HEAP_FILTER_TAGGED as jint;
+        const UNTAGGED = JVMTI_HEAP_FILTER_UNTAGGED as jint;
+        const CLASS_TAGGED = JVMTI_HEAP_FILTER_CLASS_TAGGED as jint;
+        const CLASS_UNTAGGED = JVMTI_HEAP_FILTER_CLASS_UNTAGGED as jint;
+    }
+}
+
+/**
+ * What a heap walk does after a closure returns.
+ * `Skip` only differs from `Continue` when following references: the references
+ * of the object just reported are not followed.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visit {
+    Continue,
+    Skip,
+    Abort,
+}
+
+impl Visit {
+    fn bits(self) -> jint {
+        match self {
+            Visit::Continue => JVMTI_VISIT_OBJECTS as jint,
+            Visit::Skip => 0,
+            Visit::Abort => JVMTI_VISIT_ABORT as jint,
+        }
+    }
+}
+
+/**
+ * An object reported by a heap walk. The tag can be changed in place, which is how
+ * an analysis marks objects for a later pass or for GetObjectsWithTags.
+ */
+pub struct HeapObject<'a> {
+    pub class_tag: jlong,
+    pub size: jlong,
+    /**
+     * The number of elements for an array, None for any other object.
+     */
+    pub length: Option<jint>,
+    tag: &'a Cell<jlong>,
+}
+
+impl HeapObject<'_> {
+    pub fn tag(&self) -> jlong {
+        self.tag.get()
+    }
+
+    pub fn set_tag(&self, tag: jlong) {
+        self.tag.set(tag)
+    }
+}
+
+/**
+ * The object holding a reference. Its tag can be changed as well, and may be the same
+ * cell as the referee's when an object refers to itself.
+ */
+pub struct Referrer<'a> {
+    pub class_tag: jlong,
+    tag: &'a Cell<jlong>,
+}
+
+impl Referrer<'_> {
+    pub fn tag(&self) -> jlong {
+        self.tag.get()
+    }
+
+    pub fn set_tag(&self, tag: jlong) {
+        self.tag.set(tag)
+    }
+}
+
+/**
+ * How the referee is reached. Indexes are those of JVMTI: fields are numbered across
+ * the class and its superclasses, constant pool entries as in the class file.
+ */
+#[derive(Clone, Copy, Debug)]
+pub enum ReferenceKind {
+    Class,
+    Field { index: jint },
+    ArrayElement { index: jint },
+    ClassLoader,
+    Signers,
+    ProtectionDomain,
+    Interface,
+    StaticField { index: jint },
+    ConstantPool { index: jint },
+    Superclass,
+    JniGlobal,
+    SystemClass,
+    Monitor,
+    StackLocal {
+        thread_tag: jlong,
+        thread_id: jlong,
+        depth: jint,
+        method: jmethodID,
+        location: jlocation,
+        slot: jint,
+    },
+    JniLocal {
+        thread_tag: jlong,
+        thread_id: jlong,
+        depth: jint,
+        method: jmethodID,
+    },
+    Thread,
+    Other,
+}
+
+impl ReferenceKind {
+    /**
+     * Roots have no referrer.
+     */
+    pub fn is_root(&self) -> bool {
+        matches!(
+            self,
+            ReferenceKind::JniGlobal
+                | ReferenceKind::SystemClass
+                | ReferenceKind::Monitor
+                | ReferenceKind::StackLocal { .. }
+                | ReferenceKind::JniLocal { .. }
+                | ReferenceKind::Thread
+                | ReferenceKind::Other
+        )
+    }
+
+    unsafe fn from_raw(kind: jvmtiHeapReferenceKind, info: *const jvmtiHeapReferenceInfo) -> ReferenceKind {
+        use jvmtiHeapReferenceKind::*;
+
+        let index = || {
+            if info.is_null() {
+                -1
+            } else {
+                (*(info as *const jvmtiHeapReferenceInfoField)).index
+            }
+        };
+        match kind {
+            JVMTI_HEAP_REFERENCE_CLASS => ReferenceKind::Class,
+            JVMTI_HEAP_REFERENCE_FIELD => ReferenceKind::Field { index: index() },
+            JVMTI_HEAP_REFERENCE_ARRAY_ELEMENT => ReferenceKind::ArrayElement { index: index() },
+            JVMTI_HEAP_REFERENCE_CLASS_LOADER => ReferenceKind::ClassLoader,
+            JVMTI_HEAP_REFERENCE_SIGNERS => ReferenceKind::Signers,
+            JVMTI_HEAP_REFERENCE_PROTECTION_DOMAIN => ReferenceKind::ProtectionDomain,
+            JVMTI_HEAP_REFERENCE_INTERFACE => ReferenceKind::Interface,
+            JVMTI_HEAP_REFERENCE_STATIC_FIELD => ReferenceKind::StaticField { index: index() },
+            JVMTI_HEAP_REFERENCE_CONSTANT_POOL => ReferenceKind::ConstantPool { index: index() },
+            JVMTI_HEAP_REFERENCE_SUPERCLASS => ReferenceKind::Superclass,
+            JVMTI_HEAP_REFERENCE_JNI_GLOBAL => ReferenceKind::JniGlobal,
+            JVMTI_HEAP_REFERENCE_SYSTEM_CLASS => ReferenceKind::SystemClass,
+            JVMTI_HEAP_REFERENCE_MONITOR => ReferenceKind::Monitor,
+            JVMTI_HEAP_REFERENCE_STACK_LOCAL if !info.is_null() => {
+                let local = *(info as *const jvmtiHeapReferenceInfoStackLocal);
+                ReferenceKind::StackLocal {
+                    thread_tag: local.thread_tag,
+                    thread_id: local.thread_id,
+                    depth: local.depth,
+                    method: local.method,
+                    location: local.location,
+                    slot: local.slot,
+                }
+            }
+            JVMTI_HEAP_REFERENCE_JNI_LOCAL if !info.is_null() => {
+                let local = *(info as *const jvmtiHeapReferenceInfoJniLocal);
+                ReferenceKind::JniLocal {
+                    thread_tag: local.thread_tag,
+                    thread_id: local.thread_id,
+                    depth: local.depth,
+                    method: local.method,
+                }
+            }
+            JVMTI_HEAP_REFERENCE_THREAD => ReferenceKind::Thread,
+            _ => ReferenceKind::Other,
+        }
+    }
+}
+
+pub struct HeapReference<'a> {
+    pub kind: ReferenceKind,
+    /**
+     * None for a root.
+     */
+    pub referrer: Option<Referrer<'a>>,
+    pub referee: HeapObject<'a>,
+}
+
+/**
+ * The value of a primitive field, typed by the field's signature.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrimitiveValue {
+    Boolean(bool),
+    Byte(jbyte),
+    Char(jchar),
+    Short(jshort),
+    Int(jint),
+    Long(jlong),
+    Float(jfloat),
+    Double(jdouble),
+}
+
+impl PrimitiveValue {
+    unsafe fn from_raw(value: jvalue, value_type: jvmtiPrimitiveType) -> PrimitiveValue {
+        use jvmtiPrimitiveType::*;
+
+        match value_type {
+            JVMTI_PRIMITIVE_TYPE_BOOLEAN => PrimitiveValue::Boolean(value.z != 0),
+            JVMTI_PRIMITIVE_TYPE_BYTE => PrimitiveValue::Byte(value.b),
+            JVMTI_PRIMITIVE_TYPE_CHAR => PrimitiveValue::Char(value.c),
+            JVMTI_PRIMITIVE_TYPE_SHORT => PrimitiveValue::Short(value.s),
+            JVMTI_PRIMITIVE_TYPE_INT => PrimitiveValue::Int(value.i),
+            JVMTI_PRIMITIVE_TYPE_LONG => PrimitiveValue::Long(value.j),
+            JVMTI_PRIMITIVE_TYPE_FLOAT => PrimitiveValue::Float(value.f),
+            JVMTI_PRIMITIVE_TYPE_DOUBLE => PrimitiveValue::Double(value.d),
+        }
+    }
+}
+
+/**
+ * A primitive field of an object, or a static one of a class. `kind` is `Field` or
+ * `StaticField` and carries the field index.
+ */
+pub struct PrimitiveField<'a> {
+    pub kind: ReferenceKind,
+    pub class_tag: jlong,
+    pub value: PrimitiveValue,
+    tag: &'a Cell<jlong>,
+}
+
+impl PrimitiveField<'_> {
+    /**
+     * The tag of the object holding the field.
+     */
+    pub fn tag(&self) -> jlong {
+        self.tag.get()
+    }
+
+    pub fn set_tag(&self, tag: jlong) {
+        self.tag.set(tag)
+    }
+}
+
+/**
+ * The elements of a primitive array, borrowed from the VM for the duration of the callback.
+ */
+#[derive(Clone, Copy, Debug)]
+pub enum PrimitiveArray<'a> {
+    Boolean(&'a [jboolean]),
+    Byte(&'a [jbyte]),
+    Char(&'a [jchar]),
+    Short(&'a [jshort]),
+    Int(&'a [jint]),
+    Long(&'a [jlong]),
+    Float(&'a [jfloat]),
+    Double(&'a [jdouble]),
+}
+
+impl PrimitiveArray<'_> {
+    unsafe fn from_raw<'a>(
+        element_type: jvmtiPrimitiveType,
+        elements: *const c_void,
+        count: jint,
+    ) -> PrimitiveArray<'a> {
+        use jvmtiPrimitiveType::*;
+        use std::slice::from_raw_parts;
+
+        let count = count.max(0) as usize;
+        match element_type {
+            JVMTI_PRIMITIVE_TYPE_BOOLEAN => PrimitiveArray::Boolean(from_raw_parts(elements as *const jboolean, count)),
+            JVMTI_PRIMITIVE_TYPE_BYTE => PrimitiveArray::Byte(from_raw_parts(elements as *const jbyte, count)),
+            JVMTI_PRIMITIVE_TYPE_CHAR => PrimitiveArray::Char(from_raw_parts(elements as *const jchar, count)),
+            JVMTI_PRIMITIVE_TYPE_SHORT => PrimitiveArray::Short(from_raw_parts(elements as *const jshort, count)),
+            JVMTI_PRIMITIVE_TYPE_INT => PrimitiveArray::Int(from_raw_parts(elements as *const jint, count)),
+            JVMTI_PRIMITIVE_TYPE_LONG => PrimitiveArray::Long(from_raw_parts(elements as *const jlong, count)),
+            JVMTI_PRIMITIVE_TYPE_FLOAT => PrimitiveArray::Float(from_raw_parts(elements as *const jfloat, count)),
+            JVMTI_PRIMITIVE_TYPE_DOUBLE => PrimitiveArray::Double(from_raw_parts(elements as *const jdouble, count)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            PrimitiveArray::Boolean(elements) => elements.len(),
+            PrimitiveArray::Byte(elements) => elements.len(),
+            PrimitiveArray::Char(elements) => elements.len(),
+            PrimitiveArray::Short(elements) => elements.len(),
+            PrimitiveArray::Int(elements) => elements.len(),
+            PrimitiveArray::Long(elements) => elements.len(),
+            PrimitiveArray::Float(elements) => elements.len(),
+            PrimitiveArray::Double(elements) => elements.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/**
+ * What `for_each_value` reports. Every object comes as `Object` first, followed by
+ * its primitive fields or, for a primitive array, its elements.
+ */
+pub enum HeapValue<'a> {
+    Object(HeapObject<'a>),
+    Field(PrimitiveField<'a>),
+    Array(HeapObject<'a>, PrimitiveArray<'a>),
+}
+
+/**
+ * The closure and a panic it raised, which is carried past the VM and resumed in the caller.
+ */
+struct Walk<F> {
+    visit: F,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<F> Walk<F> {
+    fn new(visit: F) -> Walk<F> {
+        Walk { visit, panic: None }
+    }
+
+    fn call(&mut self, call: impl FnOnce(&mut F) -> Visit) -> jint {
+        if self.panic.is_some() {
+            return Visit::Abort.bits();
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| call(&mut self.visit))) {
+            Ok(visit) => visit.bits(),
+            Err(panic) => {
+                self.panic = Some(panic);
+                Visit::Abort.bits()
+            }
+        }
+    }
+
+    fn finish(self, result: JvmtiResult<()>) -> JvmtiResult<()> {
+        if let Some(panic) = self.panic {
+            panic::resume_unwind(panic);
+        }
+        result
+    }
+}
+
+/**
+ * JVMTI tags are plain jlongs, Cell<jlong> has the same layout and allows the
+ * referrer and referee to share one.
+ */
+unsafe fn tag_cell<'a>(tag_ptr: *mut jlong) -> &'a Cell<jlong> {
+    &*(tag_ptr as *const Cell<jlong>)
+}
+
+unsafe extern "C" fn heap_iteration<F>(
+    class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    length: jint,
+    user_data: *mut c_void,
+) -> jint
+where
+    F: FnMut(HeapObject) -> Visit,
+{
+    let walk = &mut *(user_data as *mut Walk<F>);
+    let object = HeapObject {
+        class_tag,
+        size,
+        length: (length >= 0).then_some(length),
+        tag: tag_cell(tag_ptr),
+    };
+    walk.call(|visit| visit(object))
+}
+
+unsafe extern "C" fn value_iteration<F>(
+    class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    length: jint,
+    user_data: *mut c_void,
+) -> jint
+where
+    F: FnMut(HeapValue) -> Visit,
+{
+    let walk = &mut *(user_data as *mut Walk<F>);
+    let object = HeapObject {
+        class_tag,
+        size,
+        length: (length >= 0).then_some(length),
+        tag: tag_cell(tag_ptr),
+    };
+    walk.call(|visit| visit(HeapValue::Object(object)))
+}
+
+unsafe extern "C" fn primitive_field<F>(
+    kind: jvmtiHeapReferenceKind,
+    info: *const jvmtiHeapReferenceInfo,
+    object_class_tag: jlong,
+    object_tag_ptr: *mut jlong,
+    value: jvalue,
+    value_type: jvmtiPrimitiveType,
+    user_data: *mut c_void,
+) -> jint
+where
+    F: FnMut(HeapValue) -> Visit,
+{
+    let walk = &mut *(user_data as *mut Walk<F>);
+    let field = PrimitiveField {
+        kind: ReferenceKind::from_raw(kind, info),
+        class_tag: object_class_tag,
+        value: PrimitiveValue::from_raw(value, value_type),
+        tag: tag_cell(object_tag_ptr),
+    };
+    walk.call(|visit| visit(HeapValue::Field(field)))
+}
+
+unsafe extern "C" fn array_primitive_value<F>(
+    class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    element_count: jint,
+    element_type: jvmtiPrimitiveType,
+    elements: *const c_void,
+    user_data: *mut c_void,
+) -> jint
+where
+    F: FnMut(HeapValue) -> Visit,
+{
+    let walk = &mut *(user_data as *mut Walk<F>);
+    let object = HeapObject {
+        class_tag,
+        size,
+        length: Some(element_count),
+        tag: tag_cell(tag_ptr),
+    };
+    let array = PrimitiveArray::from_raw(element_type, elements, element_count);
+    walk.call(|visit| visit(HeapValue::Array(object, array)))
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn heap_reference<F>(
+    reference_kind: jvmtiHeapReferenceKind,
+    reference_info: *const jvmtiHeapReferenceInfo,
+    class_tag: jlong,
+    referrer_class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    referrer_tag_ptr: *mut jlong,
+    length: jint,
+    user_data: *mut c_void,
+) -> jint
+where
+    F: FnMut(HeapReference) -> Visit,
+{
+    let walk = &mut *(user_data as *mut Walk<F>);
+    let reference = HeapReference {
+        kind: ReferenceKind::from_raw(reference_kind, reference_info),
+        referrer: (!referrer_tag_ptr.is_null()).then(|| Referrer {
+            class_tag: referrer_class_tag,
+            tag: tag_cell(referrer_tag_ptr),
+        }),
+        referee: HeapObject {
+            class_tag,
+            size,
+            length: (length >= 0).then_some(length),
+            tag: tag_cell(tag_ptr),
+        },
+    };
+    walk.call(|visit| visit(reference))
+}
+
+/**
+ * Closure based heap walks. The closures run while the VM holds the heap still:
+ * they must not call JNI or any JVMTI function but the raw monitor ones.
+ * A panic aborts the walk and is resumed once the VM has returned.
+ */
+impl JvmtiEnv {
+    /**
+     * Every object in the heap, or only the instances of `class` (and its subclasses)
+     * when it is not null. Needs can_tag_objects.
+     */
+    pub fn for_each_object<F>(&self, filter: HeapFilter, class: jclass, visit: F) -> JvmtiResult<()>
+    where
+        F: FnMut(HeapObject) -> Visit,
+    {
+        let callbacks = jvmtiHeapCallbacks {
+            heap_iteration_callback: Some(heap_iteration::<F>),
+            ..Default::default()
+        };
+        let mut walk = Walk::new(visit);
+        let user_data = ptr::addr_of_mut!(walk) as *const c_void;
+
+        let result = self.iterate_through_heap(filter.bits(), class, &callbacks, user_data);
+        walk.finish(result)
+    }
+
+    /**
+     * `for_each_object` with the primitive fields of every object, the static ones of every
+     * class and the elements of every primitive array. Needs can_tag_objects.
+     */
+    pub fn for_each_value<F>(&self, filter: HeapFilter, class: jclass, visit: F) -> JvmtiResult<()>
+    where
+        F: FnMut(HeapValue) -> Visit,
+    {
+        let callbacks = jvmtiHeapCallbacks {
+            heap_iteration_callback: Some(value_iteration::<F>),
+            primitive_field_callback: Some(primitive_field::<F>),
+            array_primitive_value_callback: Some(array_primitive_value::<F>),
+            ..Default::default()
+        };
+        let mut walk = Walk::new(visit);
+        let user_data = ptr::addr_of_mut!(walk) as *const c_void;
+
+        let result = self.iterate_through_heap(filter.bits(), class, &callbacks, user_data);
+        walk.finish(result)
+    }
+
+    /**
+     * Every reference reachable from the roots, or from `initial_object` when it is not null.
+     * Return `Visit::Skip` to not follow the references of a referee. Needs can_tag_objects.
+     */
+    pub fn for_each_reference<F>(
+        &self,
+        filter: HeapFilter,
+        class: jclass,
+        initial_object: jobject,
+        visit: F,
+    ) -> JvmtiResult<()>
+    where
+        F: FnMut(HeapReference) -> Visit,
+    {
+        let callbacks = jvmtiHeapCallbacks {
+            heap_reference_callback: Some(heap_reference::<F>),
+            ..Default::default()
+        };
+        let mut walk = Walk::new(visit);
+        let user_data = ptr::addr_of_mut!(walk) as *const c_void;
+
+        let result = self.follow_references(filter.bits(), class, initial_object, &callbacks, user_data);
+        walk.finish(result)
+    }
+
+    /**
+     * Resets every tag to 0, e.g. after an analysis so its tags do not leak into the next one.
+     */
+    pub fn clear_tags(&self) -> JvmtiResult<()> {
+        self.for_each_object(HeapFilter::UNTAGGED, ptr::null_mut(), |object| {
+            object.set_tag(0);
+            Visit::Continue
+        })
+    }
+}
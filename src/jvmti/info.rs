@@ -0,0 +1,110 @@
+use bitflags::bitflags;
+use jni::sys::{jint, jobject};
+
+use crate::jvmti::jvmti_sys::*;
+
+/**
+ * GetThreadInfo with the name copied out. `thread_group` and `context_class_loader`
+ * are JNI local references owned by the caller.
+ */
+#[derive(Clone, Debug)]
+pub struct ThreadInfo {
+    pub name: String,
+    pub priority: jint,
+    pub is_daemon: bool,
+    pub thread_group: jthreadGroup,
+    pub context_class_loader: jobject,
+}
+
+#[derive(Clone, Debug)]
+pub struct ThreadGroupInfo {
+    pub parent: jthreadGroup,
+    pub name: String,
+    pub max_priority: jint,
+    pub is_daemon: bool,
+}
+
+/**
+ * Who owns a monitor and who is waiting for it. `waiters` are the threads waiting to own it,
+ * `notify_waiters` those waiting in Object.wait() to be notified.
+ */
+#[derive(Clone, Debug)]
+pub struct MonitorUsage {
+    pub owner: jthread,
+    pub entry_count: jint,
+    pub waiters: Vec<jthread>,
+    pub notify_waiters: Vec<jthread>,
+}
+
+/**
+ * One entry of a method's LocalVariableTable. The variable is live for
+ * `length` bytecodes from `start_location`.
+ */
+#[derive(Clone, Debug)]
+pub struct LocalVariable {
+    pub start_location: jlocation,
+    pub length: jint,
+    pub name: String,
+    pub signature: String,
+    pub generic_signature: Option<String>,
+    pub slot: jint,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineNumberEntry {
+    pub start_location: jlocation,
+    pub line_number: jint,
+}
+
+impl From<&jvmtiLineNumberEntry> for LineNumberEntry {
+    fn from(entry: &jvmtiLineNumberEntry) -> Self {
+        LineNumberEntry {
+            start_location: entry.start_location,
+            line_number: entry.line_number,
+        }
+    }
+}
+
+bitflags! {
+    /**
+     * The bits of GetThreadState. Unknown bits are kept, JVMTI reserves some for vendors.
+     */
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct ThreadState: jint {
+        const ALIVE = JVMTI_THREAD_STATE_ALIVE as jint;
+        const TERMINATED = JVMTI_THREAD_STATE_TERMINATED as jint;
+        const RUNNABLE = JVMTI_THREAD_STATE_RUNNABLE as jint;
+        const BLOCKED_ON_MONITOR_ENTER = JVMTI_THREAD_STATE_BLOCKED_ON_MONITOR_ENTER as jint;
+        const WAITING = JVMTI_THREAD_STATE_WAITING as jint;
+        const WAITING_INDEFINITELY = JVMTI_THREAD_STATE_WAITING_INDEFINITELY as jint;
+        const WAITING_WITH_TIMEOUT = JVMTI_THREAD_STATE_WAITING_WITH_TIMEOUT as jint;
+        const SLEEPING = JVMTI_THREAD_STATE_SLEEPING as jint;
+        const IN_OBJECT_WAIT = JVMTI_THREAD_STATE_IN_OBJECT_WAIT as jint;
+        const PARKED = JVMTI_THREAD_STATE_PARKED as jint;
+        const SUSPENDED = JVMTI_THREAD_STATE_SUSPENDED as jint;
+        const INTERRUPTED = JVMTI_THREAD_STATE_INTERRUPTED as jint;
+        const IN_NATIVE = JVMTI_THREAD_STATE_IN_NATIVE as jint;
+        const VENDOR_1 = JVMTI_THREAD_STATE_VENDOR_1 as jint;
+        const VENDOR_2 = JVMTI_THREAD_STATE_VENDOR_2 as jint;
+        const VENDOR_3 = JVMTI_THREAD_STATE_VENDOR_3 as jint;
+
+        const _ = !0;
+    }
+}
+
+impl ThreadState {
+    /**
+     * The java.lang.Thread.State name, as a thread dump prints it.
+     */
+    pub fn java_lang_state(&self) -> &'static str {
+        match (self.bits() & JVMTI_JAVA_LANG_THREAD_STATE_MASK as jint) as u32 {
+            JVMTI_JAVA_LANG_THREAD_STATE_NEW => "NEW",
+            JVMTI_JAVA_LANG_THREAD_STATE_TERMINATED => "TERMINATED",
+            JVMTI_JAVA_LANG_THREAD_STATE_RUNNABLE => "RUNNABLE",
+            JVMTI_JAVA_LANG_THREAD_STATE_BLOCKED => "BLOCKED",
+            JVMTI_JAVA_LANG_THREAD_STATE_WAITING => "WAITING",
+            JVMTI_JAVA_LANG_THREAD_STATE_TIMED_WAITING => "TIMED_WAITING",
+            _ => "UNKNOWN",
+        }
+    }
+}
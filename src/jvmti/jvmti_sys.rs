@@ -18,7 +18,9 @@ pub const JVMTI_VERSION_1_1: c_uint = 805372160;
 pub const JVMTI_VERSION_1_2: c_uint = 805372416;
 pub const JVMTI_VERSION_9: c_uint = 805896192;
 pub const JVMTI_VERSION_11: c_uint = 806027264;
-pub const JVMTI_VERSION: c_int = 806420480;
+pub const JVMTI_VERSION_17: c_uint = 806420480;
+pub const JVMTI_VERSION_21: c_uint = 806682624;
+pub const JVMTI_VERSION: c_int = JVMTI_VERSION_21 as c_int;
 
 pub type jvmtiEnv = *const jvmtiInterface_1;
 pub type jthread = jobject;
@@ -692,6 +694,8 @@ impl Default for jvmtiHeapReferenceInfoReserved {
 pub struct jvmtiHeapReferenceInfo {
     pub _bindgen_data_: [u64; 8usize],
 }
+// Union accessors, the caller must know which member the reference kind selects
+#[allow(clippy::missing_safety_doc)]
 impl jvmtiHeapReferenceInfo {
     pub unsafe fn field(&mut self) -> *mut jvmtiHeapReferenceInfoField {
         let raw: *mut u8 = &self._bindgen_data_ as *const [u64; 8] as *mut u8;
@@ -1188,6 +1192,16 @@ pub type jvmtiEventVMObjectAlloc = Option<
 >;
 pub type jvmtiEventVMStart =
     Option<unsafe extern "C" fn(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) -> ()>;
+pub type jvmtiEventSampledObjectAlloc = Option<
+    unsafe extern "C" fn(
+        jvmti_env: *mut jvmtiEnv,
+        jni_env: *mut JNIEnv,
+        thread: jthread,
+        object: jobject,
+        object_klass: jclass,
+        size: jlong,
+    ) -> (),
+>;
 
 #[repr(C)]
 #[derive(Copy)]
@@ -1227,6 +1241,8 @@ pub struct jvmtiEventCallbacks {
     pub GarbageCollectionFinish: jvmtiEventGarbageCollectionFinish,
     pub ObjectFree: jvmtiEventObjectFree,
     pub VMObjectAlloc: jvmtiEventVMObjectAlloc,
+    pub reserved85: jvmtiEventReserved,
+    pub SampledObjectAlloc: jvmtiEventSampledObjectAlloc,
 }
 impl Clone for jvmtiEventCallbacks {
     fn clone(&self) -> Self {
@@ -2092,10 +2108,22 @@ pub struct jvmtiInterface_1 {
     >,
     /*   117 :  RESERVED */
     pub reserved117: *mut c_void,
-    /*   118 :  RESERVED */
-    pub reserved118: *mut c_void,
-    /*   119 :  RESERVED */
-    pub reserved119: *mut c_void,
+    /*   118 : Suspend All Virtual Threads */
+    pub SuspendAllVirtualThreads: Option<
+        unsafe extern "C" fn(
+            env: *mut jvmtiEnv,
+            except_count: jint,
+            except_list: *const jthread,
+        ) -> jvmtiError,
+    >,
+    /*   119 : Resume All Virtual Threads */
+    pub ResumeAllVirtualThreads: Option<
+        unsafe extern "C" fn(
+            env: *mut jvmtiEnv,
+            except_count: jint,
+            except_list: *const jthread,
+        ) -> jvmtiError,
+    >,
     /*   120 : Set JNI Function Table */
     pub SetJNIFunctionTable: Option<
         unsafe extern "C" fn(
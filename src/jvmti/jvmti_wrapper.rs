@@ -1,40 +1,29 @@
-#![allow(non_snake_case, non_camel_case_types, dead_code)]
+#![allow(non_snake_case, non_camel_case_types, dead_code, clippy::not_unsafe_ptr_arg_deref)]
 
+use crate::jvmti::info::{LineNumberEntry, LocalVariable, MonitorUsage, ThreadGroupInfo, ThreadInfo, ThreadState};
+use crate::jvmti::memory::{JvmtiBuf, JvmtiString};
+use crate::jvmti::stack::{StackFrame, ThreadStack};
 use crate::jvmti::{errors::*, jvmti_sys::*};
 use jni::sys::*;
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::mem::size_of;
-use std::os::raw::{c_char, c_uchar, c_void};
+use std::os::raw::{c_char, c_uchar, c_uint, c_void};
 use std::{mem, ptr};
 
+/// A slot the VM leaves empty, e.g. a function added after its JVMTI version,
+/// reports UNSUPPORTED_VERSION instead of being called
 macro_rules! jvmti_unchecked {
     ($jvmti:expr, $name:tt $(, $args:expr)*) => {
         unsafe {
-            (**$jvmti.internal).$name.unwrap()($jvmti.internal, $($args),*)
+            match (**$jvmti.internal).$name {
+                Some(function) => function($jvmti.internal, $($args),*),
+                None => jvmtiError::JVMTI_ERROR_UNSUPPORTED_VERSION,
+            }
         }
     };
 }
 
-fn to_string(ptr: *mut c_char) -> String {
-    unsafe {
-        if ptr.is_null() {
-            String::new()
-        } else {
-            let c_str = CString::from_raw(ptr);
-            String::from(c_str.to_str().unwrap())
-        }
-    }
-}
-
-fn as_vec<T>(count: i32, ptr: *mut T) -> Vec<T> {
-    unsafe {
-        let size = count as usize;
-
-        Vec::from_raw_parts(ptr, size, size)
-    }
-}
-
 #[inline(always)]
 fn as_c_string(name: &str) -> CString {
     CString::new(name).unwrap()
@@ -65,7 +54,18 @@ impl JvmtiEnv {
         .value(|| ())
     }
 
-    pub fn get_all_threads(&self) -> JvmtiResult<Vec<jthread>> {
+    pub fn get_all_modules(&self) -> JvmtiResult<JvmtiBuf<'_, jobject>> {
+        let mut count: jint = none();
+        let count_ptr: *mut jint = &mut count;
+        let mut modules: *mut jobject = none();
+        let modules_ptr: *mut *mut jobject = &mut modules;
+
+        let error = jvmti_unchecked!(self, GetAllModules, count_ptr, modules_ptr);
+
+        error.value(|| unsafe { JvmtiBuf::from_raw(self, modules, count) })
+    }
+
+    pub fn get_all_threads(&self) -> JvmtiResult<JvmtiBuf<'_, jthread>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
         let mut threads: *mut jthread = none();
@@ -73,7 +73,7 @@ impl JvmtiEnv {
 
         let error = jvmti_unchecked!(self, GetAllThreads, count_ptr, threads_ptr);
 
-        error.value(|| as_vec(count, threads))
+        error.value(|| unsafe { JvmtiBuf::from_raw(self, threads, count) })
     }
 
     pub fn suspend_thread(&self, thread: jthread) -> JvmtiResult<()> {
@@ -92,14 +92,20 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, InterruptThread, thread).value(|| ())
     }
 
-    pub fn get_thread_info(&self, thread: jthread) -> JvmtiResult<jvmtiThreadInfo> {
+    pub fn get_thread_info(&self, thread: jthread) -> JvmtiResult<ThreadInfo> {
         let mut info: jvmtiThreadInfo = none();
         let info_ptr: *mut jvmtiThreadInfo = &mut info;
 
-        jvmti_unchecked!(self, GetThreadInfo, thread, info_ptr).value(|| info)
+        jvmti_unchecked!(self, GetThreadInfo, thread, info_ptr).value(|| ThreadInfo {
+            name: self.take_string(info.name),
+            priority: info.priority,
+            is_daemon: info.is_daemon == 1,
+            thread_group: info.thread_group,
+            context_class_loader: info.context_class_loader,
+        })
     }
 
-    pub fn get_owned_monitor_info(&self, thread: jthread) -> JvmtiResult<Vec<jobject>> {
+    pub fn get_owned_monitor_info(&self, thread: jthread) -> JvmtiResult<JvmtiBuf<'_, jobject>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
         let mut monitors: *mut jobject = none();
@@ -107,7 +113,7 @@ impl JvmtiEnv {
 
         let error = jvmti_unchecked!(self, GetOwnedMonitorInfo, thread, count_ptr, monitors_ptr);
 
-        error.value(|| as_vec(count, monitors))
+        error.value(|| unsafe { JvmtiBuf::from_raw(self, monitors, count) })
     }
 
     pub fn get_current_contended_monitor(&self, thread: jthread) -> JvmtiResult<jobject> {
@@ -127,7 +133,7 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, RunAgentThread, thread, _proc, arg, priority).value(|| ())
     }
 
-    pub fn get_top_thread_groups(&self) -> JvmtiResult<Vec<jthreadGroup>> {
+    pub fn get_top_thread_groups(&self) -> JvmtiResult<JvmtiBuf<'_, jthreadGroup>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
         let mut groups: *mut jthreadGroup = none();
@@ -135,20 +141,25 @@ impl JvmtiEnv {
 
         let error = jvmti_unchecked!(self, GetTopThreadGroups, count_ptr, groups_ptr);
 
-        error.value(|| as_vec(count, groups))
+        error.value(|| unsafe { JvmtiBuf::from_raw(self, groups, count) })
     }
 
-    pub fn get_thread_group_info(&self, group: jthreadGroup) -> JvmtiResult<jvmtiThreadGroupInfo> {
+    pub fn get_thread_group_info(&self, group: jthreadGroup) -> JvmtiResult<ThreadGroupInfo> {
         let mut info: jvmtiThreadGroupInfo = none();
         let info_ptr: *mut jvmtiThreadGroupInfo = &mut info;
 
-        jvmti_unchecked!(self, GetThreadGroupInfo, group, info_ptr).value(|| info)
+        jvmti_unchecked!(self, GetThreadGroupInfo, group, info_ptr).value(|| ThreadGroupInfo {
+            parent: info.parent,
+            name: self.take_string(info.name),
+            max_priority: info.max_priority,
+            is_daemon: info.is_daemon == 1,
+        })
     }
 
     pub fn get_thread_group_children(
         &self,
         group: jthreadGroup,
-    ) -> JvmtiResult<(Vec<jthread>, Vec<jthreadGroup>)> {
+    ) -> JvmtiResult<(JvmtiBuf<'_, jthread>, JvmtiBuf<'_, jthreadGroup>)> {
         let mut thread_count: i32 = none();
         let thread_count_ptr: *mut i32 = &mut thread_count;
         let mut threads: *mut jthread = none();
@@ -169,7 +180,12 @@ impl JvmtiEnv {
             groups_ptr
         );
 
-        error.value(|| (as_vec(thread_count, threads), as_vec(group_count, groups)))
+        error.value(|| unsafe {
+            (
+                JvmtiBuf::from_raw(self, threads, thread_count),
+                JvmtiBuf::from_raw(self, groups, group_count),
+            )
+        })
     }
 
     pub fn get_frame_count(&self, thread: jthread) -> JvmtiResult<jint> {
@@ -179,11 +195,11 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, GetFrameCount, thread, count_ptr).value(|| count)
     }
 
-    pub fn get_thread_state(&self, thread: jthread) -> JvmtiResult<jint> {
+    pub fn get_thread_state(&self, thread: jthread) -> JvmtiResult<ThreadState> {
         let mut state: i32 = none();
         let state_ptr: *mut i32 = &mut state;
 
-        jvmti_unchecked!(self, GetThreadState, thread, state_ptr).value(|| state)
+        jvmti_unchecked!(self, GetThreadState, thread, state_ptr).value(|| ThreadState::from_bits_retain(state))
     }
 
     pub fn get_current_thread(&self) -> JvmtiResult<jthread> {
@@ -263,13 +279,33 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, GetLocalDouble, thread, depth, slot, double_ptr).value(|| double)
     }
 
+    pub fn set_local_object(&self, thread: jthread, depth: jint, slot: jint, value: jobject) -> JvmtiResult<()> {
+        jvmti_unchecked!(self, SetLocalObject, thread, depth, slot, value).value(|| ())
+    }
+
+    pub fn set_local_int(&self, thread: jthread, depth: jint, slot: jint, value: jint) -> JvmtiResult<()> {
+        jvmti_unchecked!(self, SetLocalInt, thread, depth, slot, value).value(|| ())
+    }
+
+    pub fn set_local_long(&self, thread: jthread, depth: jint, slot: jint, value: jlong) -> JvmtiResult<()> {
+        jvmti_unchecked!(self, SetLocalLong, thread, depth, slot, value).value(|| ())
+    }
+
+    pub fn set_local_float(&self, thread: jthread, depth: jint, slot: jint, value: jfloat) -> JvmtiResult<()> {
+        jvmti_unchecked!(self, SetLocalFloat, thread, depth, slot, value).value(|| ())
+    }
+
+    pub fn set_local_double(&self, thread: jthread, depth: jint, slot: jint, value: jdouble) -> JvmtiResult<()> {
+        jvmti_unchecked!(self, SetLocalDouble, thread, depth, slot, value).value(|| ())
+    }
+
     pub fn create_raw_monitor(&self, name: &str) -> JvmtiResult<jrawMonitorID> {
         let mut monitor: jrawMonitorID = none();
         let monitor_ptr: *mut jrawMonitorID = &mut monitor;
 
-        let name_ptr = as_c_string(name).as_ptr();
+        let name = as_c_string(name);
 
-        jvmti_unchecked!(self, CreateRawMonitor, name_ptr, monitor_ptr).value(|| monitor)
+        jvmti_unchecked!(self, CreateRawMonitor, name.as_ptr(), monitor_ptr).value(|| monitor)
     }
 
     pub fn destroy_raw_monitor(&self, monitor: jrawMonitorID) -> JvmtiResult<()> {
@@ -304,6 +340,18 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, ClearBreakpoint, method, location).value(|| ())
     }
 
+    /// The module a class loader defines a package in, null if the package is not in a named module.
+    /// The package name uses the internal form, e.g. `java/lang`
+    pub fn get_named_module(&self, class_loader: jobject, package_name: &str) -> JvmtiResult<jobject> {
+        let mut module: jobject = none();
+        let module_ptr: *mut jobject = &mut module;
+
+        let package_name = as_c_string(package_name);
+
+        jvmti_unchecked!(self, GetNamedModule, class_loader, package_name.as_ptr(), module_ptr)
+            .value(|| module)
+    }
+
     pub fn set_field_access_watch(&self, class: jclass, field: jfieldID) -> JvmtiResult<()> {
         jvmti_unchecked!(self, SetFieldAccessWatch, class, field).value(|| ())
     }
@@ -342,6 +390,11 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, Deallocate, mem).value(|| ())
     }
 
+    /// Copies a string JVMTI allocated for us and deallocates it
+    fn take_string(&self, ptr: *mut c_char) -> String {
+        unsafe { JvmtiString::from_raw(self, ptr) }.to_string()
+    }
+
     /// Returns name, generic
     pub fn get_class_signature(&self, class: jclass) -> JvmtiResult<(String, String)> {
         let mut signature: *mut c_char = none();
@@ -351,7 +404,7 @@ impl JvmtiEnv {
 
         let error = jvmti_unchecked!(self, GetClassSignature, class, signature_ptr, generic_ptr);
 
-        error.value(|| (to_string(signature), to_string(generic)))
+        error.value(|| (self.take_string(signature), self.take_string(generic)))
     }
 
     pub fn get_class_status(&self, class: jclass) -> JvmtiResult<jint> {
@@ -365,7 +418,7 @@ impl JvmtiEnv {
         let mut name: *mut c_char = none();
         let name_ptr: *mut *mut c_char = &mut name;
 
-        jvmti_unchecked!(self, GetSourceFileName, class, name_ptr).value(|| to_string(name))
+        jvmti_unchecked!(self, GetSourceFileName, class, name_ptr).value(|| self.take_string(name))
     }
 
     pub fn get_class_modifiers(&self, class: jclass) -> JvmtiResult<jint> {
@@ -375,7 +428,7 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, GetClassModifiers, class, modifiers_ptr).value(|| modifiers)
     }
 
-    pub fn get_class_methods(&self, class: jclass) -> JvmtiResult<Vec<jmethodID>> {
+    pub fn get_class_methods(&self, class: jclass) -> JvmtiResult<JvmtiBuf<'_, jmethodID>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -383,10 +436,10 @@ impl JvmtiEnv {
         let methods_ptr: *mut *mut jmethodID = &mut methods;
 
         jvmti_unchecked!(self, GetClassMethods, class, count_ptr, methods_ptr)
-            .value(|| as_vec(count, methods))
+            .value(|| unsafe { JvmtiBuf::from_raw(self, methods, count) })
     }
 
-    pub fn get_class_fields(&self, class: jclass) -> JvmtiResult<Vec<jfieldID>> {
+    pub fn get_class_fields(&self, class: jclass) -> JvmtiResult<JvmtiBuf<'_, jfieldID>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -394,10 +447,10 @@ impl JvmtiEnv {
         let fields_ptr: *mut *mut jfieldID = &mut fields;
 
         jvmti_unchecked!(self, GetClassFields, class, count_ptr, fields_ptr)
-            .value(|| as_vec(count, fields))
+            .value(|| unsafe { JvmtiBuf::from_raw(self, fields, count) })
     }
 
-    pub fn get_implemented_interfaces(&self, class: jclass) -> JvmtiResult<Vec<jclass>> {
+    pub fn get_implemented_interfaces(&self, class: jclass) -> JvmtiResult<JvmtiBuf<'_, jclass>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -411,7 +464,7 @@ impl JvmtiEnv {
             count_ptr,
             interfaces_ptr
         )
-        .value(|| as_vec(count, interfaces))
+        .value(|| unsafe { JvmtiBuf::from_raw(self, interfaces, count) })
     }
 
     pub fn is_interface(&self, class: jclass) -> JvmtiResult<bool> {
@@ -442,11 +495,22 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, GetObjectHashCode, object, hashcode_ptr).value(|| hashcode)
     }
 
-    pub fn get_object_monitor_usage(&self, object: jobject) -> JvmtiResult<jvmtiMonitorUsage> {
+    pub fn get_object_monitor_usage(&self, object: jobject) -> JvmtiResult<MonitorUsage> {
         let mut usage: jvmtiMonitorUsage = none();
         let usage_ptr: *mut jvmtiMonitorUsage = &mut usage;
 
-        jvmti_unchecked!(self, GetObjectMonitorUsage, object, usage_ptr).value(|| usage)
+        jvmti_unchecked!(self, GetObjectMonitorUsage, object, usage_ptr).value(|| {
+            let waiters = unsafe { JvmtiBuf::from_raw(self, usage.waiters, usage.waiter_count) };
+            let notify_waiters =
+                unsafe { JvmtiBuf::from_raw(self, usage.notify_waiters, usage.notify_waiter_count) };
+
+            MonitorUsage {
+                owner: usage.owner,
+                entry_count: usage.entry_count,
+                waiters: waiters.to_vec(),
+                notify_waiters: notify_waiters.to_vec(),
+            }
+        })
     }
 
     /// Returns name, signature, generic
@@ -474,7 +538,7 @@ impl JvmtiEnv {
             generic_ptr
         );
 
-        error.value(|| (to_string(name), to_string(signature), to_string(generic)))
+        error.value(|| (self.take_string(name), self.take_string(signature), self.take_string(generic)))
     }
 
     pub fn get_field_declaring_class(&self, class: jclass, field: jfieldID) -> JvmtiResult<jclass> {
@@ -520,7 +584,7 @@ impl JvmtiEnv {
             generic_ptr
         );
 
-        error.value(|| (to_string(name), to_string(signature), to_string(generic)))
+        error.value(|| (self.take_string(name), self.take_string(signature), self.take_string(generic)))
     }
 
     pub fn get_method_declaring_class(&self, method: jmethodID) -> JvmtiResult<jclass> {
@@ -551,18 +615,17 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, GetArgumentsSize, method, size_ptr).value(|| size)
     }
 
-    pub fn get_line_number_table(
-        &self,
-        method: jmethodID,
-    ) -> JvmtiResult<Vec<jvmtiLineNumberEntry>> {
+    pub fn get_line_number_table(&self, method: jmethodID) -> JvmtiResult<Vec<LineNumberEntry>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
         let mut table: *mut jvmtiLineNumberEntry = ptr::null_mut();
         let table_ptr: *mut *mut jvmtiLineNumberEntry = &mut table;
 
-        jvmti_unchecked!(self, GetLineNumberTable, method, count_ptr, table_ptr)
-            .value(|| as_vec(count, table))
+        jvmti_unchecked!(self, GetLineNumberTable, method, count_ptr, table_ptr).value(|| {
+            let table = unsafe { JvmtiBuf::from_raw(self, table, count) };
+            table.iter().map(LineNumberEntry::from).collect()
+        })
     }
 
     /// Returns start location, end location
@@ -576,36 +639,48 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, GetMethodLocation, method, start_ptr, end_ptr).value(|| (start, end))
     }
 
-    pub fn get_local_variable_table(
-        &self,
-        method: jmethodID,
-    ) -> JvmtiResult<Vec<jvmtiLocalVariableEntry>> {
+    /// The strings inside each entry are separate allocations, they are released with the table
+    pub fn get_local_variable_table(&self, method: jmethodID) -> JvmtiResult<Vec<LocalVariable>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
         let mut table: *mut jvmtiLocalVariableEntry = none();
         let table_ptr: *mut *mut jvmtiLocalVariableEntry = &mut table;
 
-        jvmti_unchecked!(self, GetLocalVariableTable, method, count_ptr, table_ptr)
-            .value(|| as_vec(count, table))
+        jvmti_unchecked!(self, GetLocalVariableTable, method, count_ptr, table_ptr).value(|| {
+            let table = unsafe { JvmtiBuf::from_raw(self, table, count) };
+            table
+                .iter()
+                .map(|entry| {
+                    let generic = unsafe { JvmtiString::from_raw(self, entry.generic_signature) };
+                    LocalVariable {
+                        start_location: entry.start_location,
+                        length: entry.length,
+                        name: self.take_string(entry.name),
+                        signature: self.take_string(entry.signature),
+                        generic_signature: (!generic.is_null()).then(|| generic.to_string()),
+                        slot: entry.slot,
+                    }
+                })
+                .collect()
+        })
     }
 
     pub fn set_native_method_prefix(&self, prefix: &str) -> JvmtiResult<()> {
-        let prefix_ptr = as_c_string(prefix).as_ptr();
-        jvmti_unchecked!(self, SetNativeMethodPrefix, prefix_ptr).value(|| ())
+        let prefix = as_c_string(prefix);
+        jvmti_unchecked!(self, SetNativeMethodPrefix, prefix.as_ptr()).value(|| ())
     }
 
     pub fn set_native_method_prefixes(&self, prefixes: &[&str]) -> JvmtiResult<()> {
         let count = prefixes.len() as i32;
-        let mut vec: Vec<*mut c_char> = Vec::new();
+        // the CStrings have to outlive the call, not just the pointers to them
+        let prefixes: Vec<CString> = prefixes.iter().map(|&x| as_c_string(x)).collect();
+        let mut vec: Vec<*mut c_char> = prefixes.iter().map(|x| x.as_ptr() as *mut c_char).collect();
 
-        for &x in prefixes {
-            vec.push(as_c_string(x).as_ptr() as *mut c_char)
-        }
         jvmti_unchecked!(self, SetNativeMethodPrefixes, count, vec.as_mut_ptr()).value(|| ())
     }
 
-    pub fn get_bytecodes(&self, method: jmethodID) -> JvmtiResult<Vec<c_uchar>> {
+    pub fn get_bytecodes(&self, method: jmethodID) -> JvmtiResult<JvmtiBuf<'_, c_uchar>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -613,7 +688,7 @@ impl JvmtiEnv {
         let bytecodes_ptr: *mut *mut c_uchar = &mut bytecodes;
 
         jvmti_unchecked!(self, GetBytecodes, method, count_ptr, bytecodes_ptr)
-            .value(|| as_vec(count, bytecodes))
+            .value(|| unsafe { JvmtiBuf::from_raw(self, bytecodes, count) })
     }
 
     pub fn is_method_native(&self, method: jmethodID) -> JvmtiResult<bool> {
@@ -631,7 +706,7 @@ impl JvmtiEnv {
             .value(|| is_synthetic == 0)
     }
 
-    pub fn get_loaded_classes(&self) -> JvmtiResult<Vec<jclass>> {
+    pub fn get_loaded_classes(&self) -> JvmtiResult<JvmtiBuf<'_, jclass>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -639,10 +714,10 @@ impl JvmtiEnv {
         let classes_ptr: *mut *mut jclass = &mut classes;
 
         jvmti_unchecked!(self, GetLoadedClasses, count_ptr, classes_ptr)
-            .value(|| as_vec(count, classes))
+            .value(|| unsafe { JvmtiBuf::from_raw(self, classes, count) })
     }
 
-    pub fn get_class_loader_classes(&self, initiating_loader: jobject) -> JvmtiResult<Vec<jclass>> {
+    pub fn get_class_loader_classes(&self, initiating_loader: jobject) -> JvmtiResult<JvmtiBuf<'_, jclass>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -656,7 +731,7 @@ impl JvmtiEnv {
             count_ptr,
             classes_ptr
         )
-        .value(|| as_vec(count, classes))
+        .value(|| unsafe { JvmtiBuf::from_raw(self, classes, count) })
     }
 
     pub fn pop_frame(&self, thread: jthread) -> JvmtiResult<()> {
@@ -704,6 +779,23 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, GetVersionNumber, version_ptr).value(|| version)
     }
 
+    /// The major part of `get_version_number`, e.g. 17 for 0x30110000 and 1 for any 1.x
+    pub fn get_major_version(&self) -> JvmtiResult<jint> {
+        let version = self.get_version_number()? as c_uint;
+
+        Ok(((version & JVMTI_VERSION_MASK_MAJOR) >> JVMTI_VERSION_SHIFT_MAJOR) as jint)
+    }
+
+    /// Slots past the end of an older VM's function table are not empty but out of bounds,
+    /// so functions living there are gated on the version instead
+    fn require_version(&self, major: jint) -> JvmtiResult<()> {
+        if self.get_major_version()? >= major {
+            Ok(())
+        } else {
+            Err(JvmtiError::UnsupportedVersion)
+        }
+    }
+
     pub fn get_capabilities(&self) -> JvmtiResult<jvmtiCapabilities> {
         let mut capabilities: jvmtiCapabilities = none();
         let capabilities_ptr = ptr::addr_of_mut!(capabilities);
@@ -716,7 +808,7 @@ impl JvmtiEnv {
         let extension_ptr: *mut *mut c_char = &mut extension;
 
         jvmti_unchecked!(self, GetSourceDebugExtension, class, extension_ptr)
-            .value(|| to_string(extension))
+            .value(|| self.take_string(extension))
     }
 
     pub fn is_method_obsolete(&self, method: jmethodID) -> JvmtiResult<bool> {
@@ -746,7 +838,40 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, ResumeThreadList, count, ptr, results_ptr).value(|| results)
     }
 
-    pub fn get_all_stack_traces(&self, max_frame_count: i32) -> JvmtiResult<Vec<jvmtiStackInfo>> {
+    pub fn add_module_reads(&self, module: jobject, to_module: jobject) -> JvmtiResult<()> {
+        jvmti_unchecked!(self, AddModuleReads, module, to_module).value(|| ())
+    }
+
+    /// Unlike GetNamedModule the package name uses the dotted form, e.g. `com.example.internal`
+    pub fn add_module_exports(&self, module: jobject, package_name: &str, to_module: jobject) -> JvmtiResult<()> {
+        let package_name = as_c_string(package_name);
+
+        jvmti_unchecked!(self, AddModuleExports, module, package_name.as_ptr(), to_module).value(|| ())
+    }
+
+    /// Unlike GetNamedModule the package name uses the dotted form, e.g. `com.example.internal`
+    pub fn add_module_opens(&self, module: jobject, package_name: &str, to_module: jobject) -> JvmtiResult<()> {
+        let package_name = as_c_string(package_name);
+
+        jvmti_unchecked!(self, AddModuleOpens, module, package_name.as_ptr(), to_module).value(|| ())
+    }
+
+    pub fn add_module_uses(&self, module: jobject, service: jclass) -> JvmtiResult<()> {
+        jvmti_unchecked!(self, AddModuleUses, module, service).value(|| ())
+    }
+
+    pub fn add_module_provides(&self, module: jobject, service: jclass, impl_class: jclass) -> JvmtiResult<()> {
+        jvmti_unchecked!(self, AddModuleProvides, module, service, impl_class).value(|| ())
+    }
+
+    pub fn is_modifiable_module(&self, module: jobject) -> JvmtiResult<bool> {
+        let mut is_modifiable: jboolean = none();
+        let is_modifiable_ptr: *mut jboolean = &mut is_modifiable;
+
+        jvmti_unchecked!(self, IsModifiableModule, module, is_modifiable_ptr).value(|| is_modifiable == 1)
+    }
+
+    pub fn get_all_stack_traces(&self, max_frame_count: i32) -> JvmtiResult<Vec<ThreadStack>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -760,14 +885,14 @@ impl JvmtiEnv {
             stack_info_ptr,
             count_ptr
         )
-        .value(|| as_vec(count, stack_info))
+        .value(|| stack_traces(unsafe { JvmtiBuf::from_raw(self, stack_info, count) }))
     }
 
     pub fn get_thread_list_stack_traces(
         &self,
         thread_list: Vec<jthread>,
         max_frame_count: i32,
-    ) -> JvmtiResult<*mut jvmtiStackInfo> {
+    ) -> JvmtiResult<Vec<ThreadStack>> {
         let count = thread_list.len() as i32;
         let ptr = thread_list.as_ptr();
 
@@ -782,7 +907,7 @@ impl JvmtiEnv {
             max_frame_count,
             stack_info_ptr
         )
-        .value(|| stack_info)
+        .value(|| stack_traces(unsafe { JvmtiBuf::from_raw(self, stack_info, count) }))
     }
 
     pub fn get_thread_local_storage(&self, thread: jthread) -> JvmtiResult<*mut c_void> {
@@ -800,8 +925,28 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, SetThreadLocalStorage, thread, data).value(|| ())
     }
 
-    pub fn get_stack_trace(&self) {
-        panic!("Not implemented yet")
+    /// Up to `max_frame_count` frames starting at `start_depth`, 0 being the current frame.
+    /// A negative `start_depth` counts from the bottom of the stack instead
+    pub fn get_stack_trace(
+        &self,
+        thread: jthread,
+        start_depth: jint,
+        max_frame_count: jint,
+    ) -> JvmtiResult<Vec<StackFrame>> {
+        let mut frames: Vec<jvmtiFrameInfo> = vec![jvmtiFrameInfo::default(); max_frame_count.max(0) as usize];
+        let mut count: jint = none();
+        let count_ptr: *mut jint = &mut count;
+
+        jvmti_unchecked!(
+            self,
+            GetStackTrace,
+            thread,
+            start_depth,
+            max_frame_count,
+            frames.as_mut_ptr(),
+            count_ptr
+        )
+        .value(|| frames[..count as usize].iter().map(StackFrame::from).collect())
     }
 
     pub fn get_tag(&self, object: jobject) -> JvmtiResult<jlong> {
@@ -910,19 +1055,12 @@ impl JvmtiEnv {
             tag_result_ptr
         );
 
-        let object_vec = as_vec(result_count, object_result);
-        let tag_vec = as_vec(result_count, tag_result);
-        let mut result: Vec<(jobject, jlong)> = Vec::new();
-
-        for i in 0..result_count - 1 {
-            let index = i as usize;
-            let object = object_vec[index];
-            let tag = tag_vec[index];
+        error.value(|| {
+            let objects = unsafe { JvmtiBuf::from_raw(self, object_result, result_count) };
+            let tags = unsafe { JvmtiBuf::from_raw(self, tag_result, result_count) };
 
-            result.push((object, tag));
-        }
-
-        error.value(|| result)
+            objects.iter().copied().zip(tags.iter().copied()).collect()
+        })
     }
 
     pub fn follow_references(
@@ -963,6 +1101,20 @@ impl JvmtiEnv {
         .value(|| ())
     }
 
+    /// JVMTI 21, needs can_support_virtual_threads. Platform threads are not affected
+    pub fn suspend_all_virtual_threads(&self, except_list: &[jthread]) -> JvmtiResult<()> {
+        let count = except_list.len() as jint;
+
+        jvmti_unchecked!(self, SuspendAllVirtualThreads, count, except_list.as_ptr()).value(|| ())
+    }
+
+    /// JVMTI 21, needs can_support_virtual_threads
+    pub fn resume_all_virtual_threads(&self, except_list: &[jthread]) -> JvmtiResult<()> {
+        let count = except_list.len() as jint;
+
+        jvmti_unchecked!(self, ResumeAllVirtualThreads, count, except_list.as_ptr()).value(|| ())
+    }
+
     pub fn set_jni_function_table(
         &self,
         function_table: *const JNINativeInterface_,
@@ -988,7 +1140,9 @@ impl JvmtiEnv {
         jvmti_unchecked!(self, GenerateEvents, event_type).value(|| ())
     }
 
-    pub fn get_extension_functions(&self) -> JvmtiResult<Vec<jvmtiExtensionFunctionInfo>> {
+    /// Strings and parameter arrays inside each entry are separate allocations that are not
+    /// released with the array
+    pub fn get_extension_functions(&self) -> JvmtiResult<JvmtiBuf<'_, jvmtiExtensionFunctionInfo>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -996,10 +1150,12 @@ impl JvmtiEnv {
         let extensions_ptr: *mut *mut jvmtiExtensionFunctionInfo = &mut extensions;
 
         jvmti_unchecked!(self, GetExtensionFunctions, count_ptr, extensions_ptr)
-            .value(|| as_vec(count, extensions))
+            .value(|| unsafe { JvmtiBuf::from_raw(self, extensions, count) })
     }
 
-    pub fn get_extension_events(&self) -> JvmtiResult<Vec<jvmtiExtensionEventInfo>> {
+    /// Strings and parameter arrays inside each entry are separate allocations that are not
+    /// released with the array
+    pub fn get_extension_events(&self) -> JvmtiResult<JvmtiBuf<'_, jvmtiExtensionEventInfo>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -1007,7 +1163,7 @@ impl JvmtiEnv {
         let extensions_ptr: *mut *mut jvmtiExtensionEventInfo = &mut extensions;
 
         jvmti_unchecked!(self, GetExtensionEvents, count_ptr, extensions_ptr)
-            .value(|| as_vec(count, extensions))
+            .value(|| unsafe { JvmtiBuf::from_raw(self, extensions, count) })
     }
 
     pub fn set_extension_event_callback(
@@ -1032,7 +1188,7 @@ impl JvmtiEnv {
         let mut name: *mut c_char = none();
         let name_ptr: *mut *mut c_char = &mut name;
 
-        jvmti_unchecked!(self, GetErrorName, error, name_ptr).value(|| to_string(name))
+        jvmti_unchecked!(self, GetErrorName, error, name_ptr).value(|| self.take_string(name))
     }
 
     pub fn get_jlocation_format(&self) -> JvmtiResult<jvmtiJlocationFormat> {
@@ -1043,29 +1199,25 @@ impl JvmtiEnv {
     }
 
     pub fn get_system_properties(&self) -> JvmtiResult<HashMap<String, String>> {
-        let mut result: HashMap<String, String> = HashMap::new();
+        let mut count: jint = none();
+        let count_ptr: *mut jint = &mut count;
+        let mut properties: *mut *mut c_char = none();
+        let properties_ptr: *mut *mut *mut c_char = &mut properties;
 
-        let count: *mut jint = &mut 0i32;
-        let mut value: *mut *mut c_char = none();
-        let property_ptr: *mut *mut *mut c_char = &mut value;
+        jvmti_unchecked!(self, GetSystemProperties, count_ptr, properties_ptr).value(|| ())?;
 
-        if let Err(e) =
-            jvmti_unchecked!(self, GetSystemProperties, count, property_ptr).value(|| property_ptr)
-        {
-            return JvmtiResult::Err(e);
-        }
+        // the array and every name in it are separate allocations
+        let properties = unsafe { JvmtiBuf::from_raw(self, properties, count) };
+        let names: Vec<JvmtiString> = properties
+            .iter()
+            .map(|&name| unsafe { JvmtiString::from_raw(self, name) })
+            .collect();
 
-        unsafe {
-            let mut value: *mut c_char = none();
-            let value_ptr: *mut *mut c_char = &mut value;
-            for i in 0..*count {
-                let ptr = (*property_ptr).add(i as usize);
-
-                if let Ok(v) = jvmti_unchecked!(self, GetSystemProperty, *ptr, value_ptr)
-                    .value(|| to_string(value))
-                {
-                    result.insert(to_string(*ptr), v.to_string());
-                }
+        let mut result: HashMap<String, String> = HashMap::new();
+        for name in names {
+            let name = name.to_string();
+            if let Ok(value) = self.get_system_property(&name) {
+                result.insert(name, value);
             }
         }
         JvmtiResult::Ok(result)
@@ -1081,7 +1233,7 @@ impl JvmtiEnv {
             as_c_string(property).as_ptr(),
             value_ptr
         )
-        .value(|| to_string(value))
+        .value(|| self.take_string(value))
     }
 
     pub fn set_system_property(&self, property: &str, value: &str) -> JvmtiResult<()> {
@@ -1181,7 +1333,7 @@ impl JvmtiEnv {
             .value(|| (major, minor))
     }
 
-    pub fn get_constant_pool(&self, class: jclass) -> JvmtiResult<(jint, Vec<c_uchar>)> {
+    pub fn get_constant_pool(&self, class: jclass) -> JvmtiResult<(jint, JvmtiBuf<'_, c_uchar>)> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -1200,7 +1352,7 @@ impl JvmtiEnv {
             bytes_ptr
         );
 
-        error.value(|| (count, as_vec(byte_count, bytes)))
+        error.value(|| (count, unsafe { JvmtiBuf::from_raw(self, bytes, byte_count) }))
     }
 
     pub fn get_environment_local_storage(&self) -> JvmtiResult<*mut c_void> {
@@ -1246,7 +1398,7 @@ impl JvmtiEnv {
     pub fn get_owned_monitor_stack_depth_info(
         &self,
         thread: jthread,
-    ) -> JvmtiResult<Vec<jvmtiMonitorStackDepthInfo>> {
+    ) -> JvmtiResult<JvmtiBuf<'_, jvmtiMonitorStackDepthInfo>> {
         let mut count: i32 = none();
         let count_ptr: *mut i32 = &mut count;
 
@@ -1261,7 +1413,7 @@ impl JvmtiEnv {
             monitor_info_ptr
         );
 
-        error.value(|| as_vec(count, monitor_info))
+        error.value(|| unsafe { JvmtiBuf::from_raw(self, monitor_info, count) })
     }
 
     pub fn get_object_size(&self, object: jobject) -> JvmtiResult<jlong> {
@@ -1277,6 +1429,19 @@ impl JvmtiEnv {
 
         jvmti_unchecked!(self, GetLocalInstance, thread, depth, value_ptr).value(|| value)
     }
+
+    /// Average number of bytes between SampledObjectAlloc events, 0 samples every allocation
+    pub fn set_heap_sampling_interval(&self, sampling_interval: jint) -> JvmtiResult<()> {
+        self.require_version(11)?;
+
+        jvmti_unchecked!(self, SetHeapSamplingInterval, sampling_interval).value(|| ())
+    }
+}
+
+/// The frame buffers live inside the same allocation as the stack infos,
+/// so the frames are copied out before it is released
+fn stack_traces(stack_info: JvmtiBuf<jvmtiStackInfo>) -> Vec<ThreadStack> {
+    stack_info.iter().map(ThreadStack::from).collect()
 }
 
 impl From<*mut jvmtiEnv> for JvmtiEnv {
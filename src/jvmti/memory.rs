@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::fmt::{Display, Formatter, Result};
+use std::ops::Deref;
+use std::os::raw::{c_char, c_uchar};
+use std::slice;
+
+use jni::sys::jint;
+
+use crate::jvmti::jvmti_wrapper::JvmtiEnv;
+
+/**
+ * An array the VM allocated for us, e.g. the result of GetLoadedClasses.
+ * It belongs to the JVMTI allocator, so it is released with Deallocate when dropped
+ * and never by Rust's allocator.
+ */
+pub struct JvmtiBuf<'a, T> {
+    jvmti: &'a JvmtiEnv,
+    ptr: *mut T,
+    len: usize,
+}
+
+impl<'a, T> JvmtiBuf<'a, T> {
+    /**
+     * # Safety
+     * `ptr` is null or was allocated by `jvmti` and holds `len` initialized values.
+     */
+    pub unsafe fn from_raw(jvmti: &'a JvmtiEnv, ptr: *mut T, len: jint) -> JvmtiBuf<'a, T> {
+        JvmtiBuf {
+            jvmti,
+            ptr,
+            len: len.max(0) as usize,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr
+    }
+}
+
+impl<T> Deref for JvmtiBuf<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        if self.ptr.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.ptr, self.len) }
+        }
+    }
+}
+
+impl<T> Drop for JvmtiBuf<'_, T> {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            let _ = self.jvmti.deallocate(self.ptr as *mut c_uchar);
+        }
+    }
+}
+
+/**
+ * A modified UTF-8 string the VM allocated for us, released with Deallocate when dropped.
+ * Null, which JVMTI returns e.g. for a missing generic signature, reads as "".
+ */
+pub struct JvmtiString<'a> {
+    jvmti: &'a JvmtiEnv,
+    ptr: *mut c_char,
+}
+
+impl<'a> JvmtiString<'a> {
+    /**
+     * # Safety
+     * `ptr` is null or a NUL terminated string allocated by `jvmti`.
+     */
+    pub unsafe fn from_raw(jvmti: &'a JvmtiEnv, ptr: *mut c_char) -> JvmtiString<'a> {
+        JvmtiString { jvmti, ptr }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.ptr.is_null()
+    }
+
+    pub fn as_c_str(&self) -> Option<&CStr> {
+        if self.ptr.is_null() {
+            None
+        } else {
+            Some(unsafe { CStr::from_ptr(self.ptr) })
+        }
+    }
+
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        self.as_c_str().map_or(Cow::Borrowed(""), CStr::to_string_lossy)
+    }
+}
+
+impl Display for JvmtiString<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.write_str(&self.to_string_lossy())
+    }
+}
+
+impl Drop for JvmtiString<'_> {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            let _ = self.jvmti.deallocate(self.ptr as *mut c_uchar);
+        }
+    }
+}
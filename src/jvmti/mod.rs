@@ -1,5 +1,10 @@
 pub mod capabilities;
 pub mod errors;
+pub mod events;
+pub mod heap;
+pub mod info;
 pub mod jvmti_sys;
 pub mod jvmti_wrapper;
+pub mod memory;
+pub mod stack;
 pub mod sync;
@@ -0,0 +1,163 @@
+use std::fmt::{Display, Formatter, Result};
+
+use jni::sys::{jint, jmethodID};
+
+use crate::jvmti::errors::{JvmtiError, JvmtiResult};
+use crate::jvmti::info::{LineNumberEntry, ThreadState};
+use crate::jvmti::jvmti_sys::{jlocation, jthread, jvmtiFrameInfo, jvmtiStackInfo};
+use crate::jvmti::jvmti_wrapper::JvmtiEnv;
+
+/**
+ * One frame as JVMTI reports it. The location is a bytecode index, -1 for a native method.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct StackFrame {
+    pub method: jmethodID,
+    pub location: jlocation,
+}
+
+/**
+ * The stack of one thread from GetAllStackTraces or GetThreadListStackTraces.
+ * `state` is the thread state at the time the stack was taken.
+ */
+pub struct ThreadStack {
+    pub thread: jthread,
+    pub state: ThreadState,
+    pub frames: Vec<StackFrame>,
+}
+
+/**
+ * A frame with everything a StackTraceElement holds. `source_file` and `line_number`
+ * are None when the class was compiled without debug information.
+ */
+#[derive(Clone, Debug)]
+pub struct ResolvedFrame {
+    pub class_name: String,
+    pub method_name: String,
+    pub signature: String,
+    pub source_file: Option<String>,
+    pub line_number: Option<jint>,
+    pub native: bool,
+}
+
+impl From<&jvmtiFrameInfo> for StackFrame {
+    fn from(info: &jvmtiFrameInfo) -> Self {
+        StackFrame {
+            method: info.method,
+            location: info.location,
+        }
+    }
+}
+
+impl From<&jvmtiStackInfo> for ThreadStack {
+    fn from(info: &jvmtiStackInfo) -> Self {
+        let frames = if info.frame_buffer.is_null() {
+            Vec::new()
+        } else {
+            let buffer = unsafe { std::slice::from_raw_parts(info.frame_buffer, info.frame_count.max(0) as usize) };
+            buffer.iter().map(StackFrame::from).collect()
+        };
+        ThreadStack {
+            thread: info.thread,
+            state: ThreadState::from_bits_retain(info.state),
+            frames,
+        }
+    }
+}
+
+impl StackFrame {
+    pub fn resolve(&self, jvmti: &JvmtiEnv) -> JvmtiResult<ResolvedFrame> {
+        let (method_name, signature, _) = jvmti.get_method_name(self.method)?;
+        let class = jvmti.get_method_declaring_class(self.method)?;
+        let (class_signature, _) = jvmti.get_class_signature(class)?;
+        let native = jvmti.is_method_native(self.method)?;
+
+        let source_file = absent_as_none(jvmti.get_source_file_name(class))?;
+        let line_number = if native || self.location < 0 {
+            None
+        } else {
+            absent_as_none(jvmti.get_line_number_table(self.method))?
+                .and_then(|table| line_number(&table, self.location))
+        };
+
+        Ok(ResolvedFrame {
+            class_name: class_name(&class_signature),
+            method_name,
+            signature,
+            source_file,
+            line_number,
+            native,
+        })
+    }
+}
+
+/**
+ * Formatted like StackTraceElement.toString() without the module prefix,
+ * e.g. `com.example.Main.run(Main.java:42)`.
+ */
+impl Display for ResolvedFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}.{}(", self.class_name, self.method_name)?;
+        match (&self.source_file, self.line_number) {
+            _ if self.native => write!(f, "Native Method")?,
+            (Some(file), Some(line)) if line >= 0 => write!(f, "{}:{}", file, line)?,
+            (Some(file), _) => write!(f, "{}", file)?,
+            (None, _) => write!(f, "Unknown Source")?,
+        }
+        write!(f, ")")
+    }
+}
+
+/**
+ * The layout of Throwable.printStackTrace: the header line, e.g. the exception or
+ * the thread, followed by one tab-indented `at` line per frame.
+ */
+pub fn format_stack_trace(header: &str, frames: &[ResolvedFrame]) -> String {
+    let mut out = format!("{}\n", header);
+    for frame in frames {
+        out.push_str(&format!("\tat {}\n", frame));
+    }
+    out
+}
+
+/**
+ * Missing debug information is not an error for a stack trace, the frame just prints without it.
+ * The same goes for an environment without can_get_source_file_name or can_get_line_numbers.
+ */
+fn absent_as_none<T>(result: JvmtiResult<T>) -> JvmtiResult<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(JvmtiError::AbsentInformation)
+        | Err(JvmtiError::NativeMethod)
+        | Err(JvmtiError::MustPossessCapability) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/**
+ * The line of the last table entry starting at or before `location`, the table is not sorted.
+ */
+fn line_number(table: &[LineNumberEntry], location: jlocation) -> Option<jint> {
+    table
+        .iter()
+        .filter(|entry| entry.start_location <= location)
+        .max_by_key(|entry| entry.start_location)
+        .map(|entry| entry.line_number)
+}
+
+/**
+ * `Lcom/example/Main;` to `com.example.Main`. Hidden classes keep the slash before their
+ * suffix, like Class.getName() does: `com.example.Main$$Lambda$14/0x0000000800c03000`.
+ */
+pub fn class_name(signature: &str) -> String {
+    let name = signature
+        .strip_prefix('L')
+        .and_then(|name| name.strip_suffix(';'))
+        .unwrap_or(signature);
+    match name.rsplit_once('/') {
+        Some((binary_name, suffix)) if suffix.starts_with("0x") => {
+            format!("{}/{}", binary_name.replace('/', "."), suffix)
+        }
+        _ => name.replace('/', "."),
+    }
+}
@@ -1,24 +1,57 @@
 #![allow(non_snake_case, non_camel_case_types, dead_code)]
 
-use crate::jvmti::{jvmti_sys::jvmtiEnv, jvmti_wrapper::JvmtiEnv};
+use crate::jvmti::errors::{JvmtiError, JvmtiResult};
+use crate::jvmti::jvmti_sys::{
+    jvmtiEnv, JVMTI_VERSION_11, JVMTI_VERSION_17, JVMTI_VERSION_1_0, JVMTI_VERSION_1_1, JVMTI_VERSION_1_2,
+    JVMTI_VERSION_21, JVMTI_VERSION_9,
+};
+use crate::jvmti::jvmti_wrapper::JvmtiEnv;
+use jni::sys::{JNI_EDETACHED, JNI_EVERSION, JNI_OK};
 use jni::JavaVM;
-use std::os::raw::{c_int, c_void};
+use std::os::raw::{c_int, c_uint, c_void};
 use std::ptr;
 
+/**
+ * Newest first. HotSpot accepts 1.x, 9, 11 and anything from 13 up to its own major version,
+ * so a JDK 17 refuses 21 and the first version that fits wins.
+ */
+const JVMTI_VERSIONS: [c_uint; 7] = [
+    JVMTI_VERSION_21,
+    JVMTI_VERSION_17,
+    JVMTI_VERSION_11,
+    JVMTI_VERSION_9,
+    JVMTI_VERSION_1_2,
+    JVMTI_VERSION_1_1,
+    JVMTI_VERSION_1_0,
+];
+
 pub trait JvmtiSupplier {
-    fn get_jvmti_env(&self, jvmti_version: c_int) -> JvmtiEnv;
+    /**
+     * Asks for `jvmti_version`, then for every older known version the VM might support.
+     * The version the VM implements is available from `get_version_number` afterwards.
+     */
+    fn get_jvmti_env(&self, jvmti_version: c_int) -> JvmtiResult<JvmtiEnv>;
 }
 
 impl JvmtiSupplier for JavaVM {
-    fn get_jvmti_env(&self, jvmti_version: c_int) -> JvmtiEnv {
+    fn get_jvmti_env(&self, jvmti_version: c_int) -> JvmtiResult<JvmtiEnv> {
         let vm = self.get_java_vm_pointer();
 
-        let mut jvmti: *mut c_void = ptr::null_mut();
+        let older = JVMTI_VERSIONS
+            .iter()
+            .map(|version| *version as c_int)
+            .filter(|version| *version < jvmti_version);
 
-        unsafe {
-            (**vm).GetEnv.unwrap()(vm, &mut jvmti, jvmti_version);
+        for version in std::iter::once(jvmti_version).chain(older) {
+            let mut jvmti: *mut c_void = ptr::null_mut();
 
-            JvmtiEnv::from(jvmti as *mut jvmtiEnv)
+            match unsafe { (**vm).GetEnv.unwrap()(vm, &mut jvmti, version) } {
+                JNI_OK if !jvmti.is_null() => return Ok(JvmtiEnv::from(jvmti as *mut jvmtiEnv)),
+                JNI_EVERSION => continue,
+                JNI_EDETACHED => return Err(JvmtiError::UnattachedThread),
+                _ => return Err(JvmtiError::Internal),
+            }
         }
+        Err(JvmtiError::UnsupportedVersion)
     }
 }
@@ -0,0 +1,526 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, fs};
+
+use jni::objects::{JClass, JObject, JObjectArray, JValue};
+use jni::sys::jsize;
+use jni::{InitArgsBuilder, JNIEnv, JavaVM};
+use log::{debug, error, info};
+
+use crate::config::{self, Config, Overrides};
+use crate::errors::GeneralError;
+use crate::heapdump::HeapDumper;
+use crate::histogram::HeapReporter;
+use crate::jdk::{Jdk, JdkResolver};
+use crate::jvm::jvm_internal::JvmInternal;
+use crate::jvmti::capabilities::{self, Capabilities, CapabilityError};
+use crate::jvmti::events::EventRegistry;
+use crate::jvmti::jvmti_sys::JVMTI_VERSION;
+use crate::jvmti::jvmti_wrapper::JvmtiEnv;
+use crate::jvmti::sync::JvmtiSupplier;
+use crate::policy::{OptionPolicy, Verdict};
+use crate::signal::Request;
+use crate::watchdog::{Reaction, Violation, Watchdog};
+use crate::{antidebug, check, hook, integrity, logging, signal, utility};
+
+/**
+ * Launch modes of sun.launcher.LauncherHelper.checkAndLoadMain
+ */
+const LM_CLASS: i32 = 1;
+const LM_JAR: i32 = 2;
+
+#[cfg(debug_assertions)]
+const DEBUG: bool = true;
+#[cfg(not(debug_assertions))]
+const DEBUG: bool = false;
+
+/**
+ * Everything the command line can change about a launch.
+ */
+#[derive(Default)]
+pub struct LaunchOptions {
+    /**
+     * Config file to use instead of searching the usual locations.
+     */
+    pub config: Option<String>,
+    pub profile: Option<String>,
+    /**
+     * `SECTION.key=value` overrides.
+     */
+    pub set: Vec<String>,
+    pub jar: Option<String>,
+    pub main_class: Option<String>,
+    pub print_config: bool,
+    pub dry_run: bool,
+    pub check: bool,
+    /**
+     * Write a heap histogram once main has returned.
+     */
+    pub heap_histogram: bool,
+    /**
+     * Write a heap dump once main has returned.
+     */
+    pub heap_dump: bool,
+    /**
+     * Passed to the JVM after the configured options.
+     */
+    pub jvm_options: Vec<String>,
+    /**
+     * Passed to main before [JAR] main_args.
+     */
+    pub program_args: Vec<String>,
+}
+
+/**
+ * Loads the config, runs the startup checks and calls the application's main method.
+ * Returns once main has returned and the VM is destroyed, the caller decides
+ * how to report an error and what to exit with.
+ */
+pub fn run(options: &LaunchOptions) -> Result<(), GeneralError> {
+    let config_path = Config::locate(options.config.as_deref()).ok_or_else(|| GeneralError::ConfigError {
+        location: String::from(config::CONFIG_FILE_NAME),
+        message: format!("not found, use --config or {}", config::CONFIG_ENV),
+    })?;
+    let mut overrides = Overrides {
+        profile: options.profile.clone(),
+        values: options.set.clone(),
+    };
+    if let Some(jar) = &options.jar {
+        let jar = fs::canonicalize(jar).unwrap_or(PathBuf::from(jar));
+        overrides.values.push(format!("JAR.jar_path={}", jar.display()));
+    }
+    let config = Config::load(&config_path, &overrides)?;
+    logging::configure(&config.logging)?;
+    debug!("Loaded config {}", config.path.display());
+
+    if options.print_config {
+        print!("{}", config.describe());
+        return Ok(());
+    }
+
+    config.validate()?;
+
+    let jdk = JdkResolver::new(&config)?.resolve()?;
+    info!("Using JDK {} ({}) at {}", jdk.version, jdk.vm, jdk.home.display());
+
+    if jdk.bundled && config.verify_runtime {
+        jdk.verify()?;
+        debug!("Bundled runtime verified");
+    }
+
+    // before create_javavm loads anything from the runtime
+    if let Err(e) = check::runtime_check(&jdk.home, config.security.runtime_manifest.as_deref()) {
+        if !DEBUG || options.check {
+            return Err(e);
+        }
+        error!("Environmental anomaly, ignored in a debug build: {}", e);
+    }
+
+    if let Some(manifest) = &config.security.jar_manifest {
+        integrity::load_jar_manifest(manifest)?;
+        integrity::verify_jar_file(&config.jar_path)?;
+        debug!("{} matches {}", config.jar_path.display(), manifest.display());
+    }
+
+    check::java_env_check(config.security.env_options)?;
+
+    let policy = OptionPolicy::new(&config.policy);
+    let vm_options = jvm_options(&config, &policy, &options.jvm_options)?;
+    if options.dry_run {
+        print_dry_run(options, &config, &jdk, &vm_options);
+        return Ok(());
+    }
+
+    // before any thread is started, the anti-debugging one included
+    if config.diagnostics.histogram_signal || config.diagnostics.heap_dump_signal {
+        signal::block();
+    }
+
+    if config.security.anti_debug {
+        antidebug::protect()?;
+    }
+
+    env::set_var("JAVA_HOME", &jdk.home);
+    let jvm = create_javavm(&vm_options, &jdk)?;
+
+    // The VM is destroyed on every path from here on, so shutdown hooks run and output is flushed
+    let result = run_in_vm(&jvm, options, &config, &jdk, &vm_options, &policy);
+    unsafe {
+        jvm.detach_current_thread();
+        let _ = jvm.destroy();
+    };
+    result
+}
+
+fn run_in_vm(
+    jvm: &JavaVM,
+    options: &LaunchOptions,
+    config: &Config,
+    jdk: &Jdk,
+    vm_options: &[String],
+    policy: &OptionPolicy,
+) -> Result<(), GeneralError> {
+    let mut env = jvm
+        .attach_current_thread()
+        .map_err(|e| GeneralError::jni("cannot attach the main thread", e))?;
+    let jvmti = jvm
+        .get_jvmti_env(JVMTI_VERSION)
+        .map_err(|e| GeneralError::jvmti("cannot get a JVMTI environment", e))?;
+    if let Ok(version) = jvmti.get_major_version() {
+        debug!("JVMTI version {}", version);
+    }
+
+    check::vm_param_check(&jvmti, &mut env, vm_options, policy, config.security.env_options)?;
+
+    if options.check {
+        info!("Environment checks passed");
+        return Ok(());
+    }
+
+    if config.security.watchdog {
+        start_watchdog(jvm, config)?;
+    }
+
+    let reporter = HeapReporter::new(&config.diagnostics);
+    let dumper = HeapDumper::new(&config.diagnostics);
+    start_diagnostics(jvm, config, &reporter, &dumper)?;
+    if config.diagnostics.heap_dump_on_oom {
+        dumper.dump_on_oom(&jvmti)?;
+    }
+
+    set_capabilities(&jvmti)?;
+    hook::configure(&config.transformers, &config.security);
+    if config.transformers.decrypt_classes
+        || !config.security.protected_packages.is_empty()
+        || config.security.jar_manifest.is_some()
+    {
+        set_event_callback(&jvmti)?;
+    }
+
+    let internal = JvmInternal::new(&jdk.libjvm.display().to_string())
+        .map_err(|e| GeneralError::JdkError(format!("cannot load {} ({})", jdk.libjvm.display(), e)))?;
+    let main_class = match &options.main_class {
+        Some(name) => load_main_class(&mut env, &internal, LM_CLASS, name)?,
+        None => load_main_class(&mut env, &internal, LM_JAR, &config.jar_path.display().to_string())?,
+    };
+
+    let main_args = load_main_args(&mut env, &internal, config, &options.program_args)?;
+
+    // register native functions with the JVM for Java to call
+    register_native_methods(&mut env);
+
+    // call main method
+    let result = env.call_static_method(
+        &main_class,
+        "main",
+        "([Ljava/lang/String;)V",
+        &[JValue::Object(&main_args)],
+    );
+    let result = result
+        .map(|_| ())
+        .map_err(|e| java_error(&mut env, "main method terminated with an exception", e));
+
+    // also after an exception, what was left behind is the interesting part then
+    if options.heap_histogram {
+        if let Err(e) = reporter.report(jvm, &mut env) {
+            error!("{}", e);
+        }
+    }
+    if options.heap_dump {
+        if let Err(e) = dumper.dump(jvm, &mut env) {
+            error!("{}", e);
+        }
+    }
+    result
+}
+
+/**
+ * Hands SIGUSR1 to the heap histogram and SIGUSR2 to the heap dump, whichever [DIAGNOSTICS] enables.
+ */
+fn start_diagnostics(
+    jvm: &JavaVM,
+    config: &Config,
+    reporter: &Arc<HeapReporter>,
+    dumper: &Arc<HeapDumper>,
+) -> Result<(), GeneralError> {
+    let histogram = config.diagnostics.histogram_signal.then(|| reporter.clone());
+    let dump = config.diagnostics.heap_dump_signal.then(|| dumper.clone());
+    if histogram.is_none() && dump.is_none() {
+        return Ok(());
+    }
+
+    let vm = unsafe { JavaVM::from_raw(jvm.get_java_vm_pointer()) }
+        .map_err(|e| GeneralError::jni("cannot hand the VM to the diagnostics thread", e))?;
+    signal::start(vm, move |vm, env, request| {
+        let result = match request {
+            Request::Histogram => histogram.as_ref().map(|reporter| reporter.report(vm, env)),
+            Request::HeapDump => dump.as_ref().map(|dumper| dumper.dump(vm, env)),
+        };
+        match result {
+            Some(Err(e)) => error!("{}", e),
+            Some(Ok(())) => (),
+            None => debug!("{:?} requested by a signal but not enabled in [DIAGNOSTICS]", request),
+        }
+    })?;
+    Ok(())
+}
+
+/**
+ * A pending Java exception is printed like java does and reported as an application error,
+ * anything else is a failure of the JNI call itself.
+ */
+fn java_error(env: &mut JNIEnv, context: &str, e: jni::errors::Error) -> GeneralError {
+    match e {
+        jni::errors::Error::JavaException => {
+            let _ = env.exception_describe();
+            let _ = env.exception_clear();
+            GeneralError::ApplicationError(context.to_string())
+        }
+        e => GeneralError::jni(context, e),
+    }
+}
+
+/**
+ * Every option the JVM is started with, in order: launcher defaults, [JVM] jvm_args,
+ * the typed heap settings and finally -J options from the command line.
+ * Only the launcher defaults bypass the option policy.
+ */
+fn jvm_options(config: &Config, policy: &OptionPolicy, cli_options: &[String]) -> Result<Vec<String>, GeneralError> {
+    let mut options = vec![
+        format!("-Djava.class.path={}", config.jar_path.display()),
+        // Native libraries live next to the launcher, wherever it is started from
+        format!("-Djava.library.path={}", utility::app_dir().display()),
+        String::from("-Dsun.java.launcher=VM_STARTUP"),
+        String::from("-XX:+DisableAttachMechanism"),
+    ];
+    // "-agentlib:jdwp=transport=dt_socket,server=y,suspend=n,address=*:5005"
+
+    // The typed heap settings take precedence over anything in jvm_args
+    let min_heap = config.min_heap.map(|size| format!("-Xms{}", size));
+    let max_heap = config.max_heap.map(|size| format!("-Xmx{}", size));
+
+    let configured = config.jvm_args.iter().chain(min_heap.iter()).chain(max_heap.iter());
+    for option in configured.chain(cli_options) {
+        if option.is_empty() {
+            continue;
+        }
+
+        // I don't want others to use the agent to make modifications to my program
+        match policy.check(option) {
+            Verdict::Allowed => options.push(option.clone()),
+            Verdict::Denied(pattern) => {
+                return Err(GeneralError::JvmArgsError(format!(
+                    "JVM option {} is denied by the option policy ({})",
+                    option, pattern
+                )))
+            }
+            Verdict::NotAllowed => {
+                return Err(GeneralError::JvmArgsError(format!(
+                    "JVM option {} is not in the option policy's allow-list",
+                    option
+                )))
+            }
+        }
+    }
+
+    let missing = policy.missing(&options);
+    if !missing.is_empty() {
+        return Err(GeneralError::JvmArgsError(format!(
+            "required JVM options missing: {}",
+            missing.join(" ")
+        )));
+    }
+    Ok(options)
+}
+
+fn create_javavm(options: &[String], jdk: &Jdk) -> Result<JavaVM, GeneralError> {
+    let mut jvm_args_builder = InitArgsBuilder::new();
+    for option in options {
+        jvm_args_builder = jvm_args_builder.option(option);
+    }
+
+    let init_args = jvm_args_builder
+        .version(jni::JNIVersion::V2)
+        .ignore_unrecognized(false)
+        .build()
+        .map_err(|e| GeneralError::JvmArgsError(format!("invalid JVM options ({})", e)))?;
+
+    Ok(JavaVM::with_libjvm(init_args, || Ok(jdk.libjvm.as_path()))?)
+}
+
+fn print_dry_run(options: &LaunchOptions, config: &Config, jdk: &Jdk, vm_options: &[String]) {
+    println!("# JDK {} ({}) {}", jdk.version, jdk.vm, jdk.libjvm.display());
+    for option in vm_options {
+        println!("{}", option);
+    }
+    match &options.main_class {
+        Some(name) => println!("# main class {}", name),
+        None => println!("# main class from {}", config.jar_path.display()),
+    }
+    let args: Vec<&str> = options
+        .program_args
+        .iter()
+        .chain(config.main_args.iter())
+        .map(String::as_str)
+        .collect();
+    println!("# arguments {}", args.join(" "));
+}
+
+fn load_main_args<'local>(
+    env: &mut JNIEnv<'local>,
+    internal: &JvmInternal,
+    config: &Config,
+    cmd_args: &[String],
+) -> Result<JObjectArray<'local>, GeneralError> {
+    let error = |e| GeneralError::jni("cannot create the main arguments", e);
+    let items: Vec<&str> = cmd_args
+        .iter()
+        .chain(config.main_args.iter())
+        .map(String::as_str)
+        .collect();
+
+    let string_class = internal
+        .find_class_from_bootloader(env.get_raw(), "java/lang/String")
+        .ok_or_else(|| GeneralError::JdkError(String::from("java.lang.String not found in the boot class path")))?;
+
+    let array = env
+        .new_object_array(items.len() as jsize, string_class, JObject::null())
+        .map_err(error)?;
+    for (i, item) in items.iter().enumerate() {
+        let item = env.new_string(item).map_err(error)?;
+        env.set_object_array_element(&array, i as jsize, &item).map_err(error)?;
+    }
+    Ok(array)
+}
+
+fn load_main_class<'local>(
+    env: &mut JNIEnv<'local>,
+    internal: &JvmInternal,
+    mode: i32,
+    name: &str,
+) -> Result<JClass<'local>, GeneralError> {
+    let launcher_helper = internal
+        .find_class_from_bootloader(env.get_raw(), "sun/launcher/LauncherHelper")
+        .ok_or_else(|| GeneralError::JdkError(String::from("sun.launcher.LauncherHelper not found in the boot class path")))?;
+
+    let context = format!("cannot load the main class from {}", name);
+    let jname = env.new_string(name).map_err(|e| GeneralError::jni(&context, e))?;
+    let result = env
+        .call_static_method(
+            launcher_helper,
+            "checkAndLoadMain",
+            "(ZILjava/lang/String;)Ljava/lang/Class;",
+            &[JValue::from(true), JValue::from(mode), JValue::Object(&jname)],
+        )
+        .and_then(|value| value.l());
+
+    match result {
+        Ok(class) if !class.is_null() => Ok(JClass::from(class)),
+        Ok(_) => Err(GeneralError::ApplicationError(context)),
+        Err(e) => Err(java_error(env, &context, e)),
+    }
+}
+
+fn start_watchdog(jvm: &JavaVM, config: &Config) -> Result<(), GeneralError> {
+    let security = &config.security;
+    let reaction = match security.watchdog_action.as_str() {
+        "log" => Reaction::Log,
+        "callback" => {
+            let (class, method) = security.watchdog_callback.rsplit_once('.').unwrap_or_default();
+            let class = class.replace('.', "/");
+            let method = method.to_string();
+            Reaction::Callback(Box::new(move |env: &mut JNIEnv, violation: &Violation| {
+                let result = env.new_string(violation.to_string()).and_then(|message| {
+                    env.call_static_method(
+                        class.as_str(),
+                        method.as_str(),
+                        "(Ljava/lang/String;)V",
+                        &[JValue::Object(&message)],
+                    )
+                });
+                if let Err(e) = result {
+                    let _ = env.exception_clear();
+                    error!("Watchdog callback {}.{} failed. Cause: ({})", class, method, e);
+                }
+            }))
+        }
+        _ => Reaction::Terminate,
+    };
+
+    let vm = unsafe { JavaVM::from_raw(jvm.get_java_vm_pointer()) }
+        .map_err(|e| GeneralError::jni("cannot hand the VM to the watchdog", e))?;
+    Watchdog::new(Duration::from_secs(security.watchdog_interval), reaction).start(vm)?;
+    Ok(())
+}
+
+/**
+ * Class file hooks are what the launcher is built on, monitor events are nice to have.
+ */
+fn set_capabilities(jvmti: &JvmtiEnv) -> Result<(), GeneralError> {
+    let required = Capabilities::new().can_generate_all_class_hook_events();
+    let optional = Capabilities::new().can_generate_monitor_events();
+
+    let negotiated = capabilities::negotiate(jvmti, required, optional).map_err(|e| match e {
+        CapabilityError::Missing(missing) => {
+            GeneralError::JdkError(format!("the VM does not support {}", missing))
+        }
+        CapabilityError::Jvmti(e) => GeneralError::jvmti("failed to add capabilities", e),
+    })?;
+
+    debug!("JVMTI capabilities: {}", negotiated.granted);
+    if !negotiated.unavailable.is_empty() {
+        debug!("JVMTI capabilities not available: {}", negotiated.unavailable);
+    }
+    Ok(())
+}
+
+fn set_event_callback(jvmti: &JvmtiEnv) -> Result<(), GeneralError> {
+    EventRegistry::new(jvmti)
+        .set_class_file_load_hook(Some(hook::class_hook_event))
+        .map_err(|e| GeneralError::jvmti("failed to enable the ClassFileLoadHook", e))
+}
+
+
+
+ /**
+  * a jni example
+  */
+fn register_native_methods(env: &mut JNIEnv) {
+    use jni::NativeMethod;
+    use jni::strings::JNIString;
+    use jni::objects::JString;
+
+    #[no_mangle]
+    #[allow(unused_mut)]
+    pub extern "system" fn Main_test(mut env: JNIEnv, _class: JObject, text: JString) {
+        let text: String = match env.get_string(&text) {
+            Ok(text) => text.into(),
+            Err(_) => return,
+        };
+        println!("hello, I am jni : {text}");
+    }
+
+    let fn_ptr = Main_test as *mut std::ffi::c_void;
+    let methods = NativeMethod {
+        name: JNIString::from("test"),
+        sig: JNIString::from("(Ljava/lang/String;)V"),
+        fn_ptr,
+    };
+
+    // Only the example application has this class
+    let clz = match env.find_class("Main") {
+        Ok(clz) => clz,
+        Err(_) => {
+            let _ = env.exception_clear();
+            debug!("No class Main, JNI function not registered");
+            return;
+        }
+    };
+    let result = env.register_native_methods(clz, &[methods]);
+
+    match result {
+        Ok(_) => info!("JNI function registered successfully"),
+        Err(e) => error!("JNI function registered failed, reason: {}", e),
+    }
+}
@@ -1,8 +1,29 @@
-#![allow(non_snake_case, non_camel_case_types, unused)]
+#![allow(non_snake_case, non_camel_case_types)]
 extern crate jni;
 use jni::sys::{jint, JNI_OK};
 
+mod antidebug;
+mod check;
+pub mod config;
+mod crypto;
+pub mod errors;
+mod heapdump;
+mod histogram;
+mod hook;
+mod hprof;
+pub mod integrity;
+pub mod jdk;
+pub mod jvm;
+pub mod jvmti;
+pub mod launcher;
+pub mod logging;
+mod policy;
+mod signal;
+mod utility;
+mod watchdog;
+
 #[no_mangle]
+#[allow(unused)]
 pub extern "system" fn Agent_OnLoad(
     vm: jni::JavaVM,
     options: *mut std::os::raw::c_void,
@@ -15,6 +36,7 @@ pub extern "system" fn Agent_OnLoad(
 }
 
 #[no_mangle]
+#[allow(unused)]
 pub extern "system" fn Agent_OnAttach(vm: jni::JavaVM, options: *mut std::os::raw::c_void, reserved: *mut std::os::raw::c_void) -> jint {
     let env = vm.get_env().expect("Failed to get JNIEnv");
     let jvmti = vm.get_env();
@@ -23,6 +45,7 @@ pub extern "system" fn Agent_OnAttach(vm: jni::JavaVM, options: *mut std::os::ra
 }
 
 #[no_mangle]
+#[allow(unused)]
 pub extern "system" fn Agent_OnUnload(vm: jni::JavaVM) {
     println!("Agent_OnUnload called!");
 }
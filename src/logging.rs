@@ -0,0 +1,128 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use chrono::Local;
+use env_logger::{Builder, Target};
+use log::LevelFilter;
+use once_cell::sync::Lazy;
+
+use crate::config::LoggingConfig;
+use crate::errors::GeneralError;
+
+static LOG_FILE: Lazy<Mutex<Option<File>>> = Lazy::new(|| Mutex::new(None));
+
+/**
+ * Set when -d/-v picked the level, the config file must not override it then.
+ */
+static LEVEL_FROM_ARGS: AtomicBool = AtomicBool::new(false);
+
+/**
+ * Writes to stderr until a log file is configured, then to that file
+ * with the color codes stripped.
+ */
+struct LogSink;
+
+impl Write for LogSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = LOG_FILE.lock().unwrap();
+        match file.as_mut() {
+            Some(file) => {
+                file.write_all(&strip_ansi(buf))?;
+                Ok(buf.len())
+            }
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match LOG_FILE.lock().unwrap().as_mut() {
+            Some(file) => file.flush(),
+            None => io::stderr().flush(),
+        }
+    }
+}
+
+fn strip_ansi(buf: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(buf.len());
+    let mut i = 0;
+    while i < buf.len() {
+        if buf[i] == 0x1b && buf.get(i + 1) == Some(&b'[') {
+            i += 2;
+            while i < buf.len() && !buf[i].is_ascii_alphabetic() {
+                i += 1;
+            }
+            i += 1;
+            continue;
+        }
+        result.push(buf[i]);
+        i += 1;
+    }
+    result
+}
+
+/**
+ * The logger filters nothing itself, the level is moved with `log::set_max_level`
+ * so the config file can still change it after startup.
+ */
+pub fn init(debug: bool, verbose: bool) {
+    let level = if debug && verbose {
+        Some(LevelFilter::Trace)
+    } else if debug {
+        Some(LevelFilter::Debug)
+    } else {
+        None
+    };
+    LEVEL_FROM_ARGS.store(level.is_some(), Ordering::Relaxed);
+
+    Builder::new()
+        .format(|buf, record| {
+            let level_style = buf.default_level_style(record.level());
+            let level_name = record.level().as_str();
+            let timestamp = Local::now().format("%Y-%m-%dT%H:%M:%S%.3f");
+            writeln!(
+                buf,
+                "[{timestamp}] {level_style}{level_name}{level_style:#} {}",
+                record.args()
+            )
+        })
+        .filter_level(LevelFilter::Trace)
+        .target(Target::Pipe(Box::new(LogSink)))
+        .write_style(env_logger::WriteStyle::Always)
+        .init();
+
+    log::set_max_level(level.unwrap_or_else(default_level));
+}
+
+fn default_level() -> LevelFilter {
+    if cfg!(debug_assertions) {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    }
+}
+
+/**
+ * Applies the [LOGGING] section, command line flags take precedence over `log_level`.
+ */
+pub fn configure(config: &LoggingConfig) -> Result<(), GeneralError> {
+    if let Some(level) = config.log_level {
+        if !LEVEL_FROM_ARGS.load(Ordering::Relaxed) {
+            log::set_max_level(level);
+        }
+    }
+
+    if let Some(path) = &config.log_file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| GeneralError::ConfigError {
+                location: String::from("[LOGGING] log_file"),
+                message: format!("cannot open {}: {}", path.display(), e),
+            })?;
+        *LOG_FILE.lock().unwrap() = Some(file);
+    }
+    Ok(())
+}
@@ -0,0 +1,291 @@
+use crate::config::PolicyConfig;
+
+/**
+ * Never allowed, whatever the config says. Each entry closes a way to attach to, instrument
+ * or reconfigure the VM behind our back.
+ */
+const DENIED: &[&str] = &[
+    // argument files, we would never see what they contain
+    "@*",
+    "-agentlib:*",
+    "-agentpath:*",
+    "-javaagent:*",
+    "-Xrun*",
+    "-Xdebug",
+    "-Xbootclasspath*",
+    "--patch-module*",
+    "-XX:+EnableDynamicAgentLoading",
+    "-XX:+StartAttachListener",
+    "-XX:-DisableAttachMechanism",
+    "-XX:+UnlockDiagnosticVMOptions",
+    "-XX:+UnlockExperimentalVMOptions",
+    "-XX:OnError=*",
+    "-XX:OnOutOfMemoryError=*",
+    "-XX:Flags=*",
+    "-XX:VMOptionsFile=*",
+    "-Djdk.attach.allowAttachSelf*",
+    "-Djava.system.class.loader=*",
+    // the launcher sets these itself, a later duplicate would win
+    "-Djava.class.path=*",
+    "-Djava.library.path=*",
+    "-Dsun.java.launcher=*",
+];
+
+/**
+ * Used when [POLICY] allow is empty.
+ */
+const DEFAULT_ALLOWED: &[&str] = &[
+    "-D*",
+    "-Xms*",
+    "-Xmx*",
+    "-Xss*",
+    "-Xmn*",
+    "-Xlog:*",
+    "-Xshare:*",
+    "-verbose:*",
+    "-ea*",
+    "-da*",
+    "-XX:+Use*GC",
+    "-XX:MaxGCPauseMillis=*",
+    "-XX:MetaspaceSize=*",
+    "-XX:MaxMetaspaceSize=*",
+    "-XX:InitialRAMPercentage=*",
+    "-XX:MinRAMPercentage=*",
+    "-XX:MaxRAMPercentage=*",
+    "-XX:+HeapDumpOnOutOfMemoryError",
+    "-XX:HeapDumpPath=*",
+    "-XX:+ExitOnOutOfMemoryError",
+    "-XX:+DisableAttachMechanism",
+    "--add-opens=*",
+    "--add-exports=*",
+    "--add-modules=*",
+    "--enable-native-access=*",
+];
+
+const REQUIRED: &[&str] = &["-XX:+DisableAttachMechanism"];
+
+pub enum Verdict<'a> {
+    Allowed,
+    Denied(&'a str),
+    NotAllowed,
+}
+
+/**
+ * Decides which JVM options may be used. Deny patterns win over allow patterns,
+ * `*` matches any run of characters and everything else must match exactly.
+ */
+pub struct OptionPolicy {
+    allow: Vec<String>,
+    deny: Vec<String>,
+    require: Vec<String>,
+}
+
+impl OptionPolicy {
+    /**
+     * The config replaces the allow-list but only adds to the deny and require lists.
+     */
+    pub fn new(config: &PolicyConfig) -> OptionPolicy {
+        let builtin = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let allow = if config.allow.is_empty() {
+            builtin(DEFAULT_ALLOWED)
+        } else {
+            config.allow.clone()
+        };
+        let mut deny = builtin(DENIED);
+        deny.extend(config.deny.iter().cloned());
+        let mut require = builtin(REQUIRED);
+        require.extend(config.require.iter().cloned());
+
+        OptionPolicy { allow, deny, require }
+    }
+
+    pub fn check(&self, option: &str) -> Verdict<'_> {
+        let option = normalize(option);
+        if let Some(pattern) = self.deny.iter().find(|p| matches(p, &option)) {
+            return Verdict::Denied(pattern);
+        }
+        if self.allow.iter().any(|p| matches(p, &option)) {
+            Verdict::Allowed
+        } else {
+            Verdict::NotAllowed
+        }
+    }
+
+    pub fn is_denied(&self, option: &str) -> bool {
+        matches!(self.check(option), Verdict::Denied(_))
+    }
+
+    /**
+     * Required options that none of `options` provides.
+     */
+    pub fn missing<'a, S: AsRef<str>>(&'a self, options: &[S]) -> Vec<&'a str> {
+        self.require
+            .iter()
+            .filter(|required| {
+                !options
+                    .iter()
+                    .any(|option| matches(required, &normalize(option.as_ref())))
+            })
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/**
+ * `-XX:Name=true` is the same flag as `-XX:+Name`, match them the same way.
+ */
+fn normalize(option: &str) -> String {
+    let option = option.trim();
+    if let Some((name, value)) = option.strip_prefix("-XX:").and_then(|flag| flag.split_once('=')) {
+        match value {
+            "true" => return format!("-XX:+{}", name),
+            "false" => return format!("-XX:-{}", name),
+            _ => (),
+        }
+    }
+    option.to_string()
+}
+
+fn matches(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let text = match text.strip_prefix(prefix) {
+                Some(text) => text,
+                None => return false,
+            };
+            (0..=text.len())
+                .filter(|&i| text.is_char_boundary(i))
+                .any(|i| matches(rest, &text[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str], require: &[&str]) -> OptionPolicy {
+        let list = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        OptionPolicy::new(&PolicyConfig {
+            allow: list(allow),
+            deny: list(deny),
+            require: list(require),
+        })
+    }
+
+    /**
+     * Allowing everything must not let any of these through.
+     */
+    fn assert_denied(option: &str) {
+        assert!(policy(&[], &[], &[]).is_denied(option), "{} passed the default policy", option);
+        assert!(policy(&["*"], &[], &[]).is_denied(option), "{} passed allow=*", option);
+    }
+
+    #[test]
+    fn denies_dynamic_agent_loading() {
+        assert_denied("-XX:+EnableDynamicAgentLoading");
+        assert_denied("-XX:EnableDynamicAgentLoading=true");
+    }
+
+    #[test]
+    fn denies_jdwp() {
+        assert_denied("-Xrunjdwp:transport=dt_socket,server=y,address=5005");
+        assert_denied("-agentlib:jdwp=transport=dt_socket,server=y");
+        assert_denied("-Xdebug");
+    }
+
+    #[test]
+    fn denies_agents() {
+        assert_denied("-javaagent:/tmp/agent.jar");
+        assert_denied("-agentpath:/tmp/libagent.so");
+    }
+
+    #[test]
+    fn denies_attach_listener() {
+        assert_denied("-XX:+StartAttachListener");
+        assert_denied("-XX:StartAttachListener=true");
+        assert_denied("-XX:-DisableAttachMechanism");
+        assert_denied("-XX:DisableAttachMechanism=false");
+    }
+
+    #[test]
+    fn denies_attach_self() {
+        assert_denied("-Djdk.attach.allowAttachSelf");
+        assert_denied("-Djdk.attach.allowAttachSelf=true");
+    }
+
+    #[test]
+    fn denies_on_error_commands() {
+        assert_denied("-XX:OnError=gdb - %p");
+        assert_denied("-XX:OnOutOfMemoryError=kill -3 %p");
+    }
+
+    #[test]
+    fn denies_diagnostic_options() {
+        assert_denied("-XX:+UnlockDiagnosticVMOptions");
+        assert_denied("-XX:UnlockDiagnosticVMOptions=true");
+        assert_denied("-XX:+UnlockExperimentalVMOptions");
+    }
+
+    #[test]
+    fn denies_argument_files() {
+        assert_denied("@/tmp/options.txt");
+        assert_denied("-XX:VMOptionsFile=/tmp/options.txt");
+        assert_denied("-XX:Flags=/tmp/.hotspotrc");
+    }
+
+    #[test]
+    fn normalizes_boolean_flags() {
+        assert_eq!(normalize("-XX:DisableAttachMechanism=true"), "-XX:+DisableAttachMechanism");
+        assert_eq!(normalize(" -XX:UseG1GC=false "), "-XX:-UseG1GC");
+        assert_eq!(normalize("-XX:MaxRAMPercentage=75"), "-XX:MaxRAMPercentage=75");
+        assert_eq!(normalize("-Dflag=true"), "-Dflag=true");
+    }
+
+    #[test]
+    fn allows_only_the_allow_list() {
+        let default = policy(&[], &[], &[]);
+        assert!(matches!(default.check("-Xmx512M"), Verdict::Allowed));
+        assert!(matches!(default.check("-XX:+UseG1GC"), Verdict::Allowed));
+        assert!(matches!(default.check("-XX:UseG1GC=true"), Verdict::Allowed));
+        assert!(matches!(default.check("-XX:+PrintCompilation"), Verdict::NotAllowed));
+
+        let configured = policy(&["-Xmx*"], &[], &[]);
+        assert!(matches!(configured.check("-Xmx512M"), Verdict::Allowed));
+        assert!(matches!(configured.check("-Dfile.encoding=UTF-8"), Verdict::NotAllowed));
+    }
+
+    #[test]
+    fn configured_deny_adds_to_the_builtin_one() {
+        let policy = policy(&[], &["-Dapp.debug*"], &[]);
+        assert!(policy.is_denied("-Dapp.debug=true"));
+        assert!(policy.is_denied("-javaagent:/tmp/agent.jar"));
+        assert!(!policy.is_denied("-Dapp.name=x"));
+    }
+
+    #[test]
+    fn requires_disable_attach_mechanism() {
+        let policy = policy(&[], &[], &[]);
+        assert_eq!(policy.missing::<&str>(&[]), vec!["-XX:+DisableAttachMechanism"]);
+        assert_eq!(policy.missing(&["-Xmx128M"]), vec!["-XX:+DisableAttachMechanism"]);
+        assert!(policy.missing(&["-XX:+DisableAttachMechanism"]).is_empty());
+        assert!(policy.missing(&["-XX:DisableAttachMechanism=true"]).is_empty());
+    }
+
+    #[test]
+    fn configured_require_adds_to_the_builtin_one() {
+        let policy = policy(&[], &[], &["-Xss*"]);
+        assert_eq!(policy.missing(&["-XX:+DisableAttachMechanism"]), vec!["-Xss*"]);
+        assert!(policy.missing(&["-XX:+DisableAttachMechanism", "-Xss1M"]).is_empty());
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches("-D*", "-Dx=1"));
+        assert!(matches("-XX:+Use*GC", "-XX:+UseZGC"));
+        assert!(!matches("-XX:+Use*GC", "-XX:+UseZGCx"));
+        assert!(matches("*", ""));
+        assert!(!matches("-Xdebug", "-Xdebugx"));
+    }
+}
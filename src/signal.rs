@@ -0,0 +1,111 @@
+use std::thread::JoinHandle;
+#[cfg(unix)]
+use std::{env, io, ptr, thread};
+
+use jni::{JNIEnv, JavaVM};
+use log::debug;
+
+use crate::errors::GeneralError;
+#[cfg(unix)]
+use log::error;
+
+/**
+ * What a signal asks for: kill -USR1 a heap histogram, kill -USR2 a heap dump.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    Histogram,
+    HeapDump,
+}
+
+/**
+ * Read by HotSpot when the VM is created, the signal it suspends and resumes threads with.
+ */
+#[cfg(unix)]
+const SR_SIGNUM: &str = "_JAVA_SR_SIGNUM";
+
+#[cfg(unix)]
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        libc::sigaddset(&mut set, libc::SIGUSR2);
+        set
+    }
+}
+
+/**
+ * Blocks SIGUSR1 and SIGUSR2 in the calling thread and every thread started from it later,
+ * the VM's included, so only the thread from `start` ever takes them. Must run before any other
+ * thread is started: SIGUSR1 is fatal to a thread that does not block it.
+ * HotSpot suspends threads with SIGUSR2 and unblocks it in all of its own, so the VM is moved
+ * to a realtime signal for that unless _JAVA_SR_SIGNUM already names one.
+ */
+#[cfg(unix)]
+pub fn block() {
+    let chosen = env::var(SR_SIGNUM).ok().and_then(|value| value.trim().parse::<libc::c_int>().ok());
+    if !chosen.is_some_and(|signal| signal != libc::SIGUSR1 && signal != libc::SIGUSR2) {
+        let signal = libc::SIGRTMAX() - 1;
+        debug!("Moving the VM's thread suspension from SIGUSR2 to signal {}", signal);
+        env::set_var(SR_SIGNUM, signal.to_string());
+    }
+
+    let set = signal_set();
+    let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    if result != 0 {
+        error!("Cannot block SIGUSR1 and SIGUSR2. Cause: ({})", io::Error::from_raw_os_error(result));
+    }
+}
+
+#[cfg(not(unix))]
+pub fn block() {}
+
+/**
+ * Runs `on_signal` for every SIGUSR1 and SIGUSR2, on a daemon thread attached to the VM.
+ * `block` must have run before the VM was created.
+ */
+#[cfg(unix)]
+pub fn start<F>(vm: JavaVM, on_signal: F) -> Result<Option<JoinHandle<()>>, GeneralError>
+where
+    F: Fn(&JavaVM, &mut JNIEnv, Request) + Send + 'static,
+{
+    let handle = thread::Builder::new()
+        .name(String::from("diagnostics"))
+        .spawn(move || {
+            let mut env = match vm.attach_current_thread_as_daemon() {
+                Ok(env) => env,
+                Err(e) => {
+                    error!("Diagnostics thread cannot attach to the VM. Cause: ({})", e);
+                    return;
+                }
+            };
+            let set = signal_set();
+            debug!("Waiting for kill -USR1 or -USR2 {}", std::process::id());
+            loop {
+                let mut signal = 0;
+                let result = unsafe { libc::sigwait(&set, &mut signal) };
+                if result != 0 {
+                    error!("Waiting for SIGUSR1 and SIGUSR2 failed. Cause: ({})", io::Error::from_raw_os_error(result));
+                    return;
+                }
+                let request = if signal == libc::SIGUSR2 {
+                    Request::HeapDump
+                } else {
+                    Request::Histogram
+                };
+                on_signal(&vm, &mut env, request);
+            }
+        })
+        .map_err(|e| GeneralError::diagnostics(String::from("cannot start the diagnostics thread"), e))?;
+    Ok(Some(handle))
+}
+
+#[cfg(not(unix))]
+pub fn start<F>(_vm: JavaVM, _on_signal: F) -> Result<Option<JoinHandle<()>>, GeneralError>
+where
+    F: Fn(&JavaVM, &mut JNIEnv, Request) + Send + 'static,
+{
+    debug!("Diagnostics on a signal are only implemented on Unix");
+    Ok(None)
+}
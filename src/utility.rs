@@ -6,6 +6,8 @@ use jni::{
 };
 use log::debug;
 use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
 
 pub trait JNIEnvUtility {
     fn get_class_name(&mut self, class: &JObject) -> String;
@@ -15,11 +17,11 @@ pub trait JNIEnvUtility {
 
 impl JNIEnvUtility for JNIEnv<'_> {
     fn get_class_name(&mut self, class: &JObject) -> String {
-        let name = self.call_method(&class, "getName", "()Ljava/lang/String;", &[]);
+        let name = self.call_method(class, "getName", "()Ljava/lang/String;", &[]);
         let jname = name.unwrap().l().unwrap();
         let name = JString::from(jname);
         let java_name = self.get_string(&name).unwrap();
-        return java_name.to_str().unwrap().to_string();
+        java_name.to_str().unwrap().to_string()
     }
 
     fn get_code_location(&mut self, protection_domain: &JObject) -> String {
@@ -33,7 +35,7 @@ impl JNIEnvUtility for JNIEnv<'_> {
         let code_source = match result {
             Ok(v) => v,
             Err(e) => {
-                debug!("Call getCodeSource Error. Cause({})", e.to_string());
+                debug!("Call getCodeSource Error. Cause({})", e);
                 return String::new();
             }
         }
@@ -44,7 +46,7 @@ impl JNIEnvUtility for JNIEnv<'_> {
         let url = match url {
             Ok(v) => v,
             Err(e) => {
-                debug!("Call getLocation Error. Cause({})", e.to_string());
+                debug!("Call getLocation Error. Cause({})", e);
                 return String::new();
             }
         }
@@ -55,7 +57,7 @@ impl JNIEnvUtility for JNIEnv<'_> {
         let path = match path {
             Ok(v) => v,
             Err(e) => {
-                debug!("Call getPath Error. Cause({})", e.to_string());
+                debug!("Call getPath Error. Cause({})", e);
                 return String::new();
             }
         }
@@ -84,4 +86,16 @@ where
 {
     let value = data.as_ptr() as *const T;
     unsafe { value.read_unaligned() }
-}
\ No newline at end of file
+}
+
+/**
+ * The directory containing the launcher executable, symlinks resolved.
+ * Falls back to the current directory if it cannot be determined.
+ */
+pub fn app_dir() -> PathBuf {
+    env::current_exe()
+        .and_then(fs::canonicalize)
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter, Result};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{fs, process};
+
+use jni::objects::{JObject, JString, JValue};
+use jni::{JNIEnv, JavaVM};
+use log::{debug, error, warn};
+
+use crate::errors::{self, GeneralError};
+
+/**
+ * Libraries the JVM only loads for a debugger or a java.lang.instrument agent.
+ */
+const DEBUGGER_LIBRARIES: &[&str] = &["libjdwp", "libdt_socket", "libdt_shmem"];
+const INSTRUMENT_LIBRARIES: &[&str] = &["libinstrument"];
+
+pub enum Violation {
+    Debugger(String),
+    Instrument(String),
+    /**
+     * A native library exporting Agent_OnLoad/Agent_OnAttach, i.e. a JVMTI agent.
+     */
+    Agent(String),
+    AttachEnabled,
+    /**
+     * DisableAttachMechanism could not be read, with the reason. Counts as a violation,
+     * a watchdog that cannot see the flag must not vouch for it.
+     */
+    AttachUnknown(String),
+    AttachListener(PathBuf),
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Violation::Debugger(library) => write!(f, "debugger library loaded: {}", library),
+            Violation::Instrument(library) => write!(f, "java.lang.instrument agent loaded: {}", library),
+            Violation::Agent(library) => write!(f, "JVMTI agent loaded: {}", library),
+            Violation::AttachEnabled => write!(f, "DisableAttachMechanism is no longer set"),
+            Violation::AttachUnknown(cause) => write!(f, "DisableAttachMechanism cannot be read ({})", cause),
+            Violation::AttachListener(path) => write!(f, "attach listener is running: {}", path.display()),
+        }
+    }
+}
+
+/**
+ * Called on the watchdog thread, which is attached to the VM as a daemon.
+ */
+pub type ViolationHandler = dyn Fn(&mut JNIEnv, &Violation) + Send;
+
+pub enum Reaction {
+    Log,
+    Callback(Box<ViolationHandler>),
+    Terminate,
+}
+
+/**
+ * Re-runs the startup checks in the background: debugger and agent libraries in the process,
+ * the attach mechanism flag and the attach listener socket. Every violation is reported once.
+ */
+pub struct Watchdog {
+    interval: Duration,
+    reaction: Reaction,
+    reported: HashSet<String>,
+    probed: HashSet<String>,
+}
+
+impl Watchdog {
+    pub fn new(interval: Duration, reaction: Reaction) -> Watchdog {
+        Watchdog {
+            interval,
+            reaction,
+            reported: HashSet::new(),
+            probed: HashSet::new(),
+        }
+    }
+
+    pub fn start(mut self, vm: JavaVM) -> std::result::Result<JoinHandle<()>, GeneralError> {
+        thread::Builder::new()
+            .name(String::from("watchdog"))
+            .spawn(move || {
+                let mut env = match vm.attach_current_thread_as_daemon() {
+                    Ok(env) => env,
+                    Err(e) => {
+                        error!("Watchdog cannot attach to the VM. Cause: ({})", e);
+                        return;
+                    }
+                };
+                debug!("Watchdog started, checking every {:?}", self.interval);
+                loop {
+                    for violation in self.scan(&mut env) {
+                        if self.reported.insert(violation.to_string()) {
+                            self.react(&mut env, &violation);
+                        }
+                    }
+                    thread::sleep(self.interval);
+                }
+            })
+            .map_err(|e| GeneralError::SecurityError(format!("cannot start the watchdog ({})", e)))
+    }
+
+    fn react(&self, env: &mut JNIEnv, violation: &Violation) {
+        match &self.reaction {
+            Reaction::Log => warn!("Watchdog: {}", violation),
+            Reaction::Callback(callback) => {
+                warn!("Watchdog: {}", violation);
+                callback(env, violation);
+            }
+            Reaction::Terminate => {
+                errors::report(&GeneralError::SecurityError(format!("watchdog: {}", violation)))
+            }
+        }
+    }
+
+    fn scan(&mut self, env: &mut JNIEnv) -> Vec<Violation> {
+        let mut violations = Vec::new();
+
+        for library in loaded_libraries() {
+            let name = Path::new(&library)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if DEBUGGER_LIBRARIES.iter().any(|prefix| name.starts_with(prefix)) {
+                violations.push(Violation::Debugger(library));
+            } else if INSTRUMENT_LIBRARIES.iter().any(|prefix| name.starts_with(prefix)) {
+                violations.push(Violation::Instrument(library));
+            } else if self.probed.insert(library.clone()) && is_agent(&library) {
+                violations.push(Violation::Agent(library));
+            }
+        }
+
+        match attach_disabled(env) {
+            Ok(true) => (),
+            Ok(false) => violations.push(Violation::AttachEnabled),
+            Err(e) => violations.push(Violation::AttachUnknown(e.to_string())),
+        }
+
+        // HotSpot creates this socket once the attach listener is up
+        let socket = PathBuf::from(format!("/tmp/.java_pid{}", process::id()));
+        if socket.exists() {
+            violations.push(Violation::AttachListener(socket));
+        }
+        violations
+    }
+}
+
+/**
+ * Shared libraries mapped into this process, empty where /proc/self/maps does not exist.
+ */
+fn loaded_libraries() -> Vec<String> {
+    let maps = fs::read_to_string("/proc/self/maps").unwrap_or_default();
+    let mut libraries: Vec<String> = maps
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(5))
+        .filter(|path| path.starts_with('/') && path.contains(".so"))
+        .map(String::from)
+        .collect();
+    libraries.sort();
+    libraries.dedup();
+    libraries
+}
+
+#[cfg(unix)]
+fn is_agent(library: &str) -> bool {
+    use libloading::os::unix::{Library, RTLD_LAZY};
+
+    // RTLD_NOLOAD only hands out libraries that are already loaded, nothing gets initialized
+    let library = match unsafe { Library::open(Some(library), RTLD_LAZY | libc::RTLD_NOLOAD) } {
+        Ok(library) => library,
+        Err(_) => return false,
+    };
+    ["Agent_OnLoad", "Agent_OnAttach"]
+        .iter()
+        .any(|symbol| unsafe { library.get::<*const ()>(symbol.as_bytes()).is_ok() })
+}
+
+#[cfg(not(unix))]
+fn is_agent(_library: &str) -> bool {
+    false
+}
+
+/**
+ * Asks HotSpotDiagnosticMXBean for the live value of DisableAttachMechanism.
+ * Fails where the runtime has no jdk.management module.
+ */
+fn attach_disabled(env: &mut JNIEnv) -> jni::errors::Result<bool> {
+    let value = env.with_local_frame(8, |env| -> jni::errors::Result<String> {
+        let bean_class = env.find_class("com/sun/management/HotSpotDiagnosticMXBean")?;
+        let bean = env
+            .call_static_method(
+                "java/lang/management/ManagementFactory",
+                "getPlatformMXBean",
+                "(Ljava/lang/Class;)Ljava/lang/management/PlatformManagedObject;",
+                &[JValue::Object(&JObject::from(bean_class))],
+            )?
+            .l()?;
+        let name = env.new_string("DisableAttachMechanism")?;
+        let option = env
+            .call_method(
+                &bean,
+                "getVMOption",
+                "(Ljava/lang/String;)Lcom/sun/management/VMOption;",
+                &[JValue::Object(&name)],
+            )?
+            .l()?;
+        let value = env.call_method(&option, "getValue", "()Ljava/lang/String;", &[])?.l()?;
+        let value = JString::from(value);
+        let value = env.get_string(&value)?;
+        Ok(value.into())
+    });
+
+    if value.is_err() {
+        let _ = env.exception_clear();
+    }
+    value.map(|value| value == "true")
+}
@@ -0,0 +1,23 @@
+/**
+ * run() holds one local of every type and hands its own frame to the native swap(),
+ * which reports what it reads through JVMTI and rewrites the locals.
+ */
+public class Locals {
+    public interface Service {
+    }
+
+    public static class Provider implements Service {
+    }
+
+    private native String swap();
+
+    public String run() {
+        int i = 1;
+        long l = 2L;
+        float f = 3.0f;
+        double d = 4.0;
+        Object o = "five";
+        String seen = swap();
+        return seen + " -> " + i + " " + l + " " + f + " " + d + " " + o;
+    }
+}
@@ -0,0 +1,254 @@
+/*!
+ * Runs the JVMTI 9-21 wrappers against a live VM. A process can only ever create one VM,
+ * so every test shares it and gets a JVMTI environment of its own for the capabilities it needs.
+ * The tests are skipped when no JDK can be found.
+ */
+
+use std::ffi::c_void;
+use std::path::PathBuf;
+use std::process::Command;
+
+use jni::objects::{JObject, JValue};
+use jni::sys::jstring;
+use jni::NativeMethod;
+use jni::{InitArgsBuilder, JNIEnv, JavaVM};
+use librust_jni_example::config::Config;
+use librust_jni_example::jdk::JdkResolver;
+use librust_jni_example::jvmti::capabilities::Capabilities;
+use librust_jni_example::jvmti::errors::JvmtiError;
+use librust_jni_example::jvmti::jvmti_sys::JVMTI_VERSION;
+use librust_jni_example::jvmti::jvmti_wrapper::JvmtiEnv;
+use librust_jni_example::jvmti::sync::JvmtiSupplier;
+use once_cell::sync::Lazy;
+
+static VM: Lazy<Option<JavaVM>> = Lazy::new(|| match start_vm() {
+    Ok(vm) => Some(vm),
+    Err(e) => {
+        eprintln!("No JVM for the JVMTI tests, skipping them ({})", e);
+        None
+    }
+});
+
+/**
+ * The JDK the launcher would pick without a config, with tests/java compiled onto the class path.
+ */
+fn start_vm() -> Result<JavaVM, String> {
+    let jdk = JdkResolver::new(&Config::default())
+        .and_then(|resolver| resolver.resolve())
+        .map_err(|e| e.to_string())?;
+
+    let classes = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("jvmti-classes");
+    let source = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/java/Locals.java");
+    let status = Command::new(jdk.home.join("bin").join("javac"))
+        .arg("-g")
+        .arg("-d")
+        .arg(&classes)
+        .arg(&source)
+        .status()
+        .map_err(|e| format!("cannot run javac ({})", e))?;
+    if !status.success() {
+        return Err(format!("javac exited with {}", status));
+    }
+
+    let args = InitArgsBuilder::new()
+        .option(format!("-Djava.class.path={}", classes.display()))
+        .option("-Xrs")
+        // HotSpot only hands out can_access_local_variables while agents load. Once an agent
+        // holds it, later environments can add it too, so jdwp is loaded for the locals test.
+        .option("-agentlib:jdwp=transport=dt_socket,server=y,suspend=n,address=127.0.0.1:0,quiet=y")
+        .build()
+        .map_err(|e| e.to_string())?;
+    JavaVM::with_libjvm(args, || Ok(jdk.libjvm.as_path())).map_err(|e| e.to_string())
+}
+
+/**
+ * Attaches the test thread and creates a fresh environment holding `capabilities`,
+ * None when the test has to be skipped. GetEnv must not be called from a detached thread.
+ */
+fn environment(capabilities: Capabilities) -> Option<(JNIEnv<'static>, JvmtiEnv)> {
+    let vm = VM.as_ref()?;
+    let env = vm.attach_current_thread_permanently().expect("cannot attach the test thread");
+    let jvmti = vm.get_jvmti_env(JVMTI_VERSION).expect("GetEnv failed");
+    if !capabilities.is_empty() {
+        jvmti.add_capabilities(capabilities.into()).expect("capabilities not available in the live phase");
+    }
+    Some((env, jvmti))
+}
+
+fn module_of<'local>(env: &mut JNIEnv<'local>, class: &str) -> JObject<'local> {
+    let class = env.find_class(class).unwrap();
+    env.call_method(class, "getModule", "()Ljava/lang/Module;", &[]).unwrap().l().unwrap()
+}
+
+fn unnamed_module<'local>(env: &mut JNIEnv<'local>) -> JObject<'local> {
+    let loader = env
+        .call_static_method("java/lang/ClassLoader", "getSystemClassLoader", "()Ljava/lang/ClassLoader;", &[])
+        .unwrap()
+        .l()
+        .unwrap();
+    env.call_method(loader, "getUnnamedModule", "()Ljava/lang/Module;", &[]).unwrap().l().unwrap()
+}
+
+fn module_query(env: &mut JNIEnv, module: &JObject, method: &str, package: &str, other: &JObject) -> bool {
+    let package = env.new_string(package).unwrap();
+    env.call_method(
+        module,
+        method,
+        "(Ljava/lang/String;Ljava/lang/Module;)Z",
+        &[JValue::Object(&package), JValue::Object(other)],
+    )
+    .unwrap()
+    .z()
+    .unwrap()
+}
+
+#[test]
+fn lists_and_finds_modules() {
+    let Some((mut env, jvmti)) = environment(Capabilities::new()) else { return };
+
+    let modules = jvmti.get_all_modules().unwrap();
+    let java_base = module_of(&mut env, "java/lang/Object");
+    let listed = modules
+        .iter()
+        .any(|module| env.is_same_object(&java_base, unsafe { JObject::from_raw(*module) }).unwrap());
+    assert!(listed);
+
+    let named = jvmti.get_named_module(std::ptr::null_mut(), "java/lang").unwrap();
+    assert!(env.is_same_object(&java_base, unsafe { JObject::from_raw(named) }).unwrap());
+    assert!(jvmti.get_named_module(std::ptr::null_mut(), "no/such/package").unwrap().is_null());
+
+    assert!(jvmti.is_modifiable_module(java_base.as_raw()).unwrap());
+    assert!(matches!(jvmti.is_modifiable_module(std::ptr::null_mut()), Err(JvmtiError::NullPointer)));
+}
+
+#[test]
+fn updates_module_graph() {
+    let Some((mut env, jvmti)) = environment(Capabilities::new()) else { return };
+
+    let java_base = module_of(&mut env, "java/lang/Object");
+    let unnamed = unnamed_module(&mut env);
+
+    jvmti.add_module_reads(java_base.as_raw(), unnamed.as_raw()).unwrap();
+    let reads = env
+        .call_method(&java_base, "canRead", "(Ljava/lang/Module;)Z", &[JValue::Object(&unnamed)])
+        .unwrap()
+        .z()
+        .unwrap();
+    assert!(reads);
+
+    assert!(!module_query(&mut env, &java_base, "isExported", "jdk.internal.misc", &unnamed));
+    jvmti.add_module_exports(java_base.as_raw(), "jdk.internal.misc", unnamed.as_raw()).unwrap();
+    assert!(module_query(&mut env, &java_base, "isExported", "jdk.internal.misc", &unnamed));
+
+    assert!(!module_query(&mut env, &java_base, "isOpen", "jdk.internal.loader", &unnamed));
+    jvmti.add_module_opens(java_base.as_raw(), "jdk.internal.loader", unnamed.as_raw()).unwrap();
+    assert!(module_query(&mut env, &java_base, "isOpen", "jdk.internal.loader", &unnamed));
+
+    assert!(matches!(
+        jvmti.add_module_exports(java_base.as_raw(), "no.such.package", unnamed.as_raw()),
+        Err(JvmtiError::IllegalArgument)
+    ));
+
+    let service = env.find_class("Locals$Service").unwrap();
+    let provider = env.find_class("Locals$Provider").unwrap();
+    jvmti.add_module_uses(java_base.as_raw(), service.as_raw()).unwrap();
+    let uses = env
+        .call_method(&java_base, "canUse", "(Ljava/lang/Class;)Z", &[JValue::Object(&service)])
+        .unwrap()
+        .z()
+        .unwrap();
+    assert!(uses);
+    jvmti.add_module_provides(java_base.as_raw(), service.as_raw(), provider.as_raw()).unwrap();
+}
+
+/**
+ * Locals.swap(): reads the locals of its caller, Locals.run(), then rewrites them.
+ * Runs on the thread that owns the frame, so the thread does not have to be suspended.
+ */
+extern "system" fn swap<'local>(mut env: JNIEnv<'local>, this: JObject<'local>) -> jstring {
+    let seen = match swap_locals(&mut env, &this) {
+        Ok(seen) => seen,
+        Err(e) => format!("JVMTI error: {}", e),
+    };
+    env.new_string(seen).unwrap().into_raw()
+}
+
+fn swap_locals(env: &mut JNIEnv, this: &JObject) -> Result<String, JvmtiError> {
+    let vm = VM.as_ref().unwrap();
+    let jvmti = vm.get_jvmti_env(JVMTI_VERSION)?;
+    jvmti.add_capabilities(Capabilities::new().can_access_local_variables().into())?;
+
+    // null is the current thread, depth 0 is swap() itself
+    let thread = std::ptr::null_mut();
+    let depth = 1;
+    let frame = jvmti.get_stack_trace(thread, depth, 1)?[0];
+    let table = jvmti.get_local_variable_table(frame.method)?;
+    let slot = |name: &str| table.iter().find(|v| v.name == name).unwrap().slot;
+
+    let o = jvmti.get_local_object(thread, depth, slot("o"))?;
+    let o: String = env.get_string(&unsafe { JObject::from_raw(o) }.into()).unwrap().into();
+    let instance = jvmti.get_local_instance(thread, depth)?;
+    let seen = format!(
+        "{} {} {:?} {:?} {} {}",
+        jvmti.get_local_int(thread, depth, slot("i"))?,
+        jvmti.get_local_long(thread, depth, slot("l"))?,
+        jvmti.get_local_float(thread, depth, slot("f"))?,
+        jvmti.get_local_double(thread, depth, slot("d"))?,
+        o,
+        env.is_same_object(this, unsafe { JObject::from_raw(instance) }).unwrap()
+    );
+
+    if !matches!(jvmti.set_local_int(thread, depth, slot("l"), 0), Err(JvmtiError::TypeMismatch)) {
+        return Ok(String::from("set_local_int accepted a long slot"));
+    }
+    let changed = env.new_string("changed").unwrap();
+    jvmti.set_local_int(thread, depth, slot("i"), 10)?;
+    jvmti.set_local_long(thread, depth, slot("l"), 20)?;
+    jvmti.set_local_float(thread, depth, slot("f"), 30.0)?;
+    jvmti.set_local_double(thread, depth, slot("d"), 40.0)?;
+    jvmti.set_local_object(thread, depth, slot("o"), changed.as_raw())?;
+    Ok(seen)
+}
+
+#[test]
+fn reads_and_writes_locals() {
+    let Some((mut env, _)) = environment(Capabilities::new()) else { return };
+
+    let method = NativeMethod {
+        name: "swap".into(),
+        sig: "()Ljava/lang/String;".into(),
+        fn_ptr: swap as *mut c_void,
+    };
+    env.register_native_methods("Locals", &[method]).unwrap();
+
+    let locals = env.new_object("Locals", "()V", &[]).unwrap();
+    let result = env.call_method(&locals, "run", "()Ljava/lang/String;", &[]).unwrap().l().unwrap();
+    let result: String = env.get_string(&result.into()).unwrap().into();
+    assert_eq!(result, "1 2 3.0 4.0 five true -> 10 20 30.0 40.0 changed");
+}
+
+#[test]
+fn sets_heap_sampling_interval() {
+    let Some((_, jvmti)) = environment(Capabilities::new()) else { return };
+    assert!(matches!(jvmti.set_heap_sampling_interval(0), Err(JvmtiError::MustPossessCapability)));
+
+    let Some((_, jvmti)) = environment(Capabilities::new().can_generate_sampled_object_alloc_events()) else { return };
+    jvmti.set_heap_sampling_interval(0).unwrap();
+    jvmti.set_heap_sampling_interval(512 * 1024).unwrap();
+    assert!(matches!(jvmti.set_heap_sampling_interval(-1), Err(JvmtiError::IllegalArgument)));
+}
+
+#[test]
+fn suspends_and_resumes_virtual_threads() {
+    let Some((_, jvmti)) = environment(Capabilities::new()) else { return };
+    if jvmti.get_major_version().unwrap() < 21 {
+        assert!(matches!(jvmti.suspend_all_virtual_threads(&[]), Err(JvmtiError::UnsupportedVersion)));
+        assert!(matches!(jvmti.resume_all_virtual_threads(&[]), Err(JvmtiError::UnsupportedVersion)));
+        return;
+    }
+    assert!(matches!(jvmti.suspend_all_virtual_threads(&[]), Err(JvmtiError::MustPossessCapability)));
+
+    let Some((_, jvmti)) = environment(Capabilities::new().can_support_virtual_threads()) else { return };
+    jvmti.suspend_all_virtual_threads(&[]).unwrap();
+    jvmti.resume_all_virtual_threads(&[]).unwrap();
+}